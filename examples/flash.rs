@@ -12,7 +12,7 @@
 mod app {
     use libdaisy::{flash::FlashErase, gpio, logger, system};
     use log::info;
-    use stm32h7xx_hal::{nb, stm32, time::MilliSeconds, timer::Timer};
+    use stm32h7xx_hal::{stm32, time::MilliSeconds, timer::Timer};
 
     #[shared]
     struct Shared {}
@@ -36,8 +36,8 @@ mod app {
         let mut flash = system.flash;
 
         //takes some time
-        nb::block!(flash.erase(FlashErase::Sector4K(0))).unwrap();
-        nb::block!(flash.erase(FlashErase::Sector4K(4096))).unwrap();
+        flash.erase_blocking(FlashErase::Sector4K(0)).unwrap();
+        flash.erase_blocking(FlashErase::Sector4K(4096)).unwrap();
 
         //read, should be all 0xFF
         let mut r = [0x0; 64];
@@ -45,10 +45,10 @@ mod app {
         assert_eq!(r[0], 0xFF);
         assert_eq!(r[31], 0xFF);
 
-        nb::block!(flash.program(0, &[0x42])).unwrap();
+        flash.program_blocking(0, &[0x42]).unwrap();
         //can program over 1s
-        nb::block!(flash.program(32, &[0x1, 0xFF])).unwrap();
-        nb::block!(flash.program(33, &[0x2, 0x3])).unwrap();
+        flash.program_blocking(32, &[0x1, 0xFF]).unwrap();
+        flash.program_blocking(33, &[0x2, 0x3]).unwrap();
 
         //read the new values
         flash.read(0, &mut r).unwrap();
@@ -58,18 +58,18 @@ mod app {
         assert_eq!(r[34], 0x3);
 
         r[35] = 0x91;
-        nb::block!(flash.program(0, &r)).unwrap();
+        flash.program_blocking(0, &r).unwrap();
         flash.read(0, &mut r).unwrap();
         assert_eq!(r[35], 0x91);
 
         flash.read(4096, &mut r).unwrap();
         assert_eq!(r[0], 0xFF);
         r[0] = 0;
-        nb::block!(flash.program(4096, &r)).unwrap();
+        flash.program_blocking(4096, &r).unwrap();
         flash.read(4096, &mut r).unwrap();
         assert_eq!(r[0], 0x00);
 
-        nb::block!(flash.erase(FlashErase::Sector4K(4096))).unwrap();
+        flash.erase_blocking(FlashErase::Sector4K(4096)).unwrap();
         flash.read(4096, &mut r).unwrap();
         assert_eq!(r[0], 0xFF);
         flash.read(0, &mut r).unwrap();
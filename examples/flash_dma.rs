@@ -0,0 +1,96 @@
+//! examples/flash_dma.rs
+//!
+//! Copy the first 1M of the QSPI flash into SDRAM with the MDMA while audio passes through.
+#![no_main]
+#![no_std]
+#[rtic::app(
+    device = stm32h7xx_hal::stm32,
+    peripherals = true,
+)]
+mod app {
+    use log::info;
+
+    use libdaisy::audio;
+    use libdaisy::flash::{FlashDma, MemoryMappedFlash};
+    use libdaisy::logger;
+    use libdaisy::system;
+
+    const COPY_SIZE: usize = 1024 * 1024;
+
+    #[shared]
+    struct Shared {}
+
+    #[local]
+    struct Local {
+        audio: audio::Audio,
+        buffer: audio::AudioBuffer,
+        flash: MemoryMappedFlash,
+        flash_dma: FlashDma,
+    }
+
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        logger::init();
+        let system = system::System::init(ctx.core, ctx.device);
+        let buffer = [(0.0, 0.0); audio::BLOCK_SIZE_MAX];
+
+        let sdram: &'static mut [u8] = unsafe {
+            core::slice::from_raw_parts_mut(system.sdram.as_mut_ptr() as *mut u8, COPY_SIZE)
+        };
+
+        let flash = system.flash.into_memory_mapped().ok().unwrap();
+        let mut flash_dma = FlashDma::new(system.mdma, system.mdma_rec);
+        flash_dma.listen();
+        flash_dma.start(&flash, 0, sdram).ok().unwrap();
+
+        info!("Startup done!");
+
+        (
+            Shared {},
+            Local {
+                audio: system.audio,
+                buffer,
+                flash,
+                flash_dma,
+            },
+            init::Monotonics(),
+        )
+    }
+
+    // Non-default idle ensures chip doesn't go to sleep which causes issues for
+    // probe.rs currently
+    #[idle]
+    fn idle(_ctx: idle::Context) -> ! {
+        loop {
+            cortex_m::asm::nop();
+        }
+    }
+
+    // Interrupt handler for audio
+    #[task(binds = DMA1_STR1, local = [audio, buffer], priority = 8)]
+    fn audio_handler(ctx: audio_handler::Context) {
+        let audio = ctx.local.audio;
+        let buffer = ctx.local.buffer;
+
+        if audio.get_stereo(buffer) {
+            for (left, right) in buffer {
+                audio.push_stereo((*left, *right)).unwrap();
+            }
+        } else {
+            info!("Error reading data!");
+        }
+    }
+
+    // Runs after every 64K block, audio has a higher priority
+    #[task(binds = MDMA, local = [flash, flash_dma], priority = 1)]
+    fn flash_dma_handler(ctx: flash_dma_handler::Context) {
+        match ctx.local.flash_dma.poll() {
+            Ok(data) => {
+                let matches = data == &ctx.local.flash.as_slice()[..data.len()];
+                info!("Copied {} bytes, matches: {}", data.len(), matches);
+            }
+            Err(stm32h7xx_hal::nb::Error::WouldBlock) => {}
+            Err(stm32h7xx_hal::nb::Error::Other(e)) => info!("Flash DMA error: {:?}", e),
+        }
+    }
+}
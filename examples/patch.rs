@@ -0,0 +1,149 @@
+//! examples/patch.rs
+//!
+//! Daisy Patch: passes all four audio channels through, shows the four controls as bars on the
+//! OLED and copies gate input 1 to the gate output.
+#![no_main]
+#![no_std]
+#[rtic::app(
+    device = stm32h7xx_hal::stm32,
+    peripherals = true,
+)]
+mod app {
+    use log::info;
+    // Includes a panic handler and optional logging facilities
+    use libdaisy::logger;
+
+    use stm32h7xx_hal::stm32;
+    use stm32h7xx_hal::timer::Timer;
+
+    use libdaisy::audio;
+    use libdaisy::boards::patch::{Controls, GateOut, Oled, Patch, OLED_HEIGHT, OLED_WIDTH};
+    use libdaisy::system;
+
+    // Redraw the OLED every 50 control updates
+    const OLED_DIVIDER: u32 = 50;
+
+    #[shared]
+    struct Shared {}
+
+    #[local]
+    struct Local {
+        audio: audio::Audio,
+        audio2: audio::Sai2Audio,
+        buffer: audio::AudioBuffer,
+        buffer2: audio::AudioBuffer,
+        controls: Controls,
+        gate_out: GateOut,
+        oled: Oled,
+        timer2: Timer<stm32::TIM2>,
+    }
+
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        logger::init();
+        let Patch {
+            controls,
+            gate_out,
+            oled,
+            audio,
+            audio2,
+            timer2,
+            ..
+        } = Patch::new(system::System::init(ctx.core, ctx.device));
+
+        info!("Startup done!");
+
+        (
+            Shared {},
+            Local {
+                audio,
+                audio2,
+                buffer: [(0.0, 0.0); audio::BLOCK_SIZE_MAX],
+                buffer2: [(0.0, 0.0); audio::BLOCK_SIZE_MAX],
+                controls,
+                gate_out,
+                oled,
+                timer2,
+            },
+            init::Monotonics(),
+        )
+    }
+
+    // Non-default idle ensures chip doesn't go to sleep which causes issues for
+    // probe.rs currently
+    #[idle]
+    fn idle(_ctx: idle::Context) -> ! {
+        loop {
+            cortex_m::asm::nop();
+        }
+    }
+
+    // Interrupt handler for channels 1 and 2
+    #[task(binds = DMA1_STR1, local = [audio, buffer], priority = 8)]
+    fn audio_handler(ctx: audio_handler::Context) {
+        let audio = ctx.local.audio;
+        let buffer = ctx.local.buffer;
+
+        if audio.get_stereo(buffer) {
+            for (left, right) in buffer {
+                audio.push_stereo((*left, *right)).unwrap();
+            }
+        } else {
+            info!("Error reading data!");
+        }
+    }
+
+    // Interrupt handler for channels 3 and 4
+    #[task(binds = DMA2_STR1, local = [audio2, buffer2], priority = 8)]
+    fn audio2_handler(ctx: audio2_handler::Context) {
+        let audio2 = ctx.local.audio2;
+        let buffer = ctx.local.buffer2;
+
+        if audio2.get_stereo(buffer) {
+            for (left, right) in buffer {
+                audio2.push_stereo((*left, *right)).unwrap();
+            }
+        } else {
+            info!("Error reading data!");
+        }
+    }
+
+    #[task(binds = TIM2, local = [timer2, controls, gate_out, oled, count: u32 = 0])]
+    fn interface_handler(ctx: interface_handler::Context) {
+        ctx.local.timer2.clear_irq();
+        let controls = ctx.local.controls;
+        let oled = ctx.local.oled;
+
+        controls.process_controls();
+
+        if controls.gate_in1.state() {
+            ctx.local.gate_out.set_high();
+        } else {
+            ctx.local.gate_out.set_low();
+        }
+
+        *ctx.local.count += 1;
+        if *ctx.local.count < OLED_DIVIDER {
+            return;
+        }
+        *ctx.local.count = 0;
+
+        let values = [
+            controls.ctrl1.get_value(),
+            controls.ctrl2.get_value(),
+            controls.ctrl3.get_value(),
+            controls.ctrl4.get_value(),
+        ];
+        oled.clear();
+        for (i, value) in values.iter().enumerate() {
+            let width = (value.clamp(0.0, 1.0) * OLED_WIDTH as f32) as usize;
+            let top = i * OLED_HEIGHT / 4 + 4;
+            for y in top..top + 8 {
+                for x in 0..width {
+                    oled.set_pixel(x, y, true);
+                }
+            }
+        }
+        oled.flush().ok();
+    }
+}
@@ -0,0 +1,118 @@
+//! examples/patch_sm.rs
+//!
+//! Daisy Patch SM: audio passthrough, CV 1 plus 1V on CV out 1, CV 2 rectified on CV out 2 and
+//! the gate inputs copied to the gate outputs.
+#![no_main]
+#![no_std]
+#[rtic::app(
+    device = stm32h7xx_hal::stm32,
+    peripherals = true,
+)]
+mod app {
+    use log::info;
+    // Includes a panic handler and optional logging facilities
+    use libdaisy::logger;
+
+    use stm32h7xx_hal::stm32;
+    use stm32h7xx_hal::timer::Timer;
+
+    use libdaisy::audio;
+    use libdaisy::boards::patch_sm::{Controls, CvOut, CvOutChannel, GateOut1, GateOut2, PatchSm};
+
+    #[shared]
+    struct Shared {}
+
+    #[local]
+    struct Local {
+        audio: audio::Audio,
+        buffer: audio::AudioBuffer,
+        controls: Controls,
+        cv_out: CvOut,
+        gate_out1: GateOut1,
+        gate_out2: GateOut2,
+        timer2: Timer<stm32::TIM2>,
+    }
+
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        logger::init();
+        let PatchSm {
+            controls,
+            cv_out,
+            gate_out1,
+            gate_out2,
+            audio,
+            timer2,
+            ..
+        } = match PatchSm::try_new(ctx.core, ctx.device) {
+            Ok(patch_sm) => patch_sm,
+            Err(e) => {
+                info!("Patch SM init failed: {:?}", e);
+                panic!("error code {}", e.code());
+            }
+        };
+
+        info!("Startup done!");
+
+        (
+            Shared {},
+            Local {
+                audio,
+                buffer: [(0.0, 0.0); audio::BLOCK_SIZE_MAX],
+                controls,
+                cv_out,
+                gate_out1,
+                gate_out2,
+                timer2,
+            },
+            init::Monotonics(),
+        )
+    }
+
+    // Non-default idle ensures chip doesn't go to sleep which causes issues for
+    // probe.rs currently
+    #[idle]
+    fn idle(_ctx: idle::Context) -> ! {
+        loop {
+            cortex_m::asm::nop();
+        }
+    }
+
+    // Interrupt handler for audio
+    #[task(binds = DMA1_STR1, local = [audio, buffer], priority = 8)]
+    fn audio_handler(ctx: audio_handler::Context) {
+        let audio = ctx.local.audio;
+        let buffer = ctx.local.buffer;
+
+        if audio.get_stereo(buffer) {
+            for (left, right) in buffer {
+                audio.push_stereo((*left, *right)).unwrap();
+            }
+        } else {
+            info!("Error reading data!");
+        }
+    }
+
+    #[task(binds = TIM2, local = [timer2, controls, cv_out, gate_out1, gate_out2])]
+    fn interface_handler(ctx: interface_handler::Context) {
+        ctx.local.timer2.clear_irq();
+        let controls = ctx.local.controls;
+        let cv_out = ctx.local.cv_out;
+
+        controls.process_controls();
+
+        cv_out.set_voltage(CvOutChannel::One, controls.cv1.volts() + 1.0);
+        cv_out.set_voltage(CvOutChannel::Two, controls.cv2.volts().abs());
+
+        if controls.gate_in1.state() {
+            ctx.local.gate_out1.set_high();
+        } else {
+            ctx.local.gate_out1.set_low();
+        }
+        if controls.gate_in2.state() {
+            ctx.local.gate_out2.set_high();
+        } else {
+            ctx.local.gate_out2.set_low();
+        }
+    }
+}
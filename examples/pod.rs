@@ -0,0 +1,105 @@
+//! examples/pod.rs
+//!
+//! Daisy Pod: the knobs set the red and blue of LED 1, the encoder sets the green of LED 2 and
+//! button 1 mutes the audio passthrough.
+#![no_main]
+#![no_std]
+#[rtic::app(
+    device = stm32h7xx_hal::stm32,
+    peripherals = true,
+)]
+mod app {
+    use log::info;
+    // Includes a panic handler and optional logging facilities
+    use libdaisy::logger;
+
+    use stm32h7xx_hal::stm32;
+    use stm32h7xx_hal::timer::Timer;
+
+    use libdaisy::audio;
+    use libdaisy::boards::pod::{Controls, Pod};
+    use libdaisy::system;
+
+    #[shared]
+    struct Shared {
+        mute: bool,
+    }
+
+    #[local]
+    struct Local {
+        audio: audio::Audio,
+        buffer: audio::AudioBuffer,
+        controls: Controls,
+        timer2: Timer<stm32::TIM2>,
+    }
+
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        logger::init();
+        let Pod {
+            controls,
+            audio,
+            timer2,
+            ..
+        } = Pod::new(system::System::init(ctx.core, ctx.device));
+
+        info!("Startup done!");
+
+        (
+            Shared { mute: false },
+            Local {
+                audio,
+                buffer: [(0.0, 0.0); audio::BLOCK_SIZE_MAX],
+                controls,
+                timer2,
+            },
+            init::Monotonics(),
+        )
+    }
+
+    // Non-default idle ensures chip doesn't go to sleep which causes issues for
+    // probe.rs currently
+    #[idle]
+    fn idle(_ctx: idle::Context) -> ! {
+        loop {
+            cortex_m::asm::nop();
+        }
+    }
+
+    // Interrupt handler for audio
+    #[task(binds = DMA1_STR1, local = [audio, buffer], shared = [mute], priority = 8)]
+    fn audio_handler(mut ctx: audio_handler::Context) {
+        let audio = ctx.local.audio;
+        let buffer = ctx.local.buffer;
+        let mute = ctx.shared.mute.lock(|mute| *mute);
+
+        if audio.get_stereo(buffer) {
+            for (left, right) in buffer {
+                let (left, right) = if mute { (0.0, 0.0) } else { (*left, *right) };
+                audio.push_stereo((left, right)).unwrap();
+            }
+        } else {
+            info!("Error reading data!");
+        }
+    }
+
+    #[task(binds = TIM2, local = [timer2, controls, green: f32 = 0.0], shared = [mute])]
+    fn interface_handler(mut ctx: interface_handler::Context) {
+        ctx.local.timer2.clear_irq();
+        let controls = ctx.local.controls;
+        let green = ctx.local.green;
+
+        controls.process_controls();
+
+        if controls.switch1.is_falling() {
+            ctx.shared.mute.lock(|mute| *mute = !*mute);
+        }
+        *green = (*green + controls.encoder.increment() as f32 * 0.1).clamp(0.0, 1.0);
+
+        controls
+            .led1
+            .set(controls.knob1.get_value(), 0.0, controls.knob2.get_value());
+        controls.led2.set(0.0, *green, 0.0);
+        controls.update_leds();
+    }
+}
@@ -0,0 +1,114 @@
+//! examples/system_builder.rs
+//!
+//! Bring up only audio and TIM2, leaving SDRAM and the flash off and their peripherals free.
+//! Audio runs at 96kHz with blocks of 32 samples.
+#![no_main]
+#![no_std]
+#[rtic::app(
+    device = stm32h7xx_hal::stm32,
+    peripherals = true,
+)]
+mod app {
+    use log::info;
+
+    use libdaisy::audio::{self, AudioConfig, SampleRate};
+    use libdaisy::gpio;
+    use libdaisy::prelude::*;
+    use libdaisy::system::{System, SystemBuilder};
+    use stm32h7xx_hal::stm32;
+    use stm32h7xx_hal::timer::Timer;
+
+    #[shared]
+    struct Shared {}
+
+    #[local]
+    struct Local {
+        audio: audio::Audio,
+        buffer: audio::AudioBuffer,
+        seed_led: gpio::SeedLed,
+        timer2: Timer<stm32::TIM2>,
+    }
+
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        let dp = ctx.device;
+        let ccdr = System::init_clocks(dp.PWR, dp.RCC, &dp.SYSCFG);
+        let pins = gpio::Pins::new(
+            dp.GPIOA.split(ccdr.peripheral.GPIOA),
+            dp.GPIOB.split(ccdr.peripheral.GPIOB),
+            dp.GPIOC.split(ccdr.peripheral.GPIOC),
+            dp.GPIOD.split(ccdr.peripheral.GPIOD),
+            dp.GPIOE.split(ccdr.peripheral.GPIOE),
+            dp.GPIOF.split(ccdr.peripheral.GPIOF),
+            dp.GPIOG.split(ccdr.peripheral.GPIOG),
+            dp.GPIOH.split(ccdr.peripheral.GPIOH),
+            dp.GPIOI.split(ccdr.peripheral.GPIOI),
+        );
+
+        let system = SystemBuilder::new(ctx.core, ccdr.clocks)
+            .logging(true)
+            .audio_config(AudioConfig {
+                sample_rate: SampleRate::Rate96K,
+                block_size: 32,
+            })
+            .audio(
+                dp.DMA1,
+                ccdr.peripheral.DMA1,
+                dp.SAI1,
+                ccdr.peripheral.SAI1,
+                pins.audio,
+                pins.codec_reset,
+            )
+            .timer(dp.TIM2, ccdr.peripheral.TIM2, 2.Hz())
+            .build();
+
+        info!("Startup done!");
+
+        (
+            Shared {},
+            Local {
+                audio: system.audio.unwrap(),
+                buffer: [(0.0, 0.0); audio::BLOCK_SIZE_MAX],
+                seed_led: pins.led.into_push_pull_output(),
+                timer2: system.timer2.unwrap(),
+            },
+            init::Monotonics(),
+        )
+    }
+
+    // Non-default idle ensures chip doesn't go to sleep which causes issues for
+    // probe.rs currently
+    #[idle]
+    fn idle(_ctx: idle::Context) -> ! {
+        loop {
+            cortex_m::asm::nop();
+        }
+    }
+
+    // Interrupt handler for audio
+    #[task(binds = DMA1_STR1, local = [audio, buffer], priority = 8)]
+    fn audio_handler(ctx: audio_handler::Context) {
+        let audio = ctx.local.audio;
+        let buffer = ctx.local.buffer;
+
+        if audio.get_stereo(buffer) {
+            for (left, right) in &buffer[..audio.block_size()] {
+                audio.push_stereo((*left, *right)).unwrap();
+            }
+        } else {
+            info!("Error reading data!");
+        }
+    }
+
+    #[task(binds = TIM2, local = [timer2, seed_led, led_is_on: bool = true])]
+    fn blink(ctx: blink::Context) {
+        ctx.local.timer2.clear_irq();
+
+        if *ctx.local.led_is_on {
+            ctx.local.seed_led.set_high();
+        } else {
+            ctx.local.seed_led.set_low();
+        }
+        *ctx.local.led_is_on = !(*ctx.local.led_is_on);
+    }
+}
@@ -74,51 +74,27 @@ mod app {
         );
         timer2.listen(Event::TimeOut);
 
-        let gpioa = device.GPIOA.split(ccdr.peripheral.GPIOA);
-        let gpiob = device.GPIOB.split(ccdr.peripheral.GPIOB);
-        let gpioc = device.GPIOC.split(ccdr.peripheral.GPIOC);
-        let gpiod = device.GPIOD.split(ccdr.peripheral.GPIOD);
-        let gpiog = device.GPIOG.split(ccdr.peripheral.GPIOG);
-
-        let gpio = gpio::GPIO::init(
-            gpioc.pc7,
-            gpiob.pb11,
-            Some(gpiob.pb12),
-            Some(gpioc.pc11),
-            Some(gpioc.pc10),
-            Some(gpioc.pc9),
-            Some(gpioc.pc8),
-            Some(gpiod.pd2),
-            Some(gpioc.pc12),
-            Some(gpiog.pg10),
-            Some(gpiog.pg11),
-            Some(gpiob.pb4),
-            Some(gpiob.pb5),
-            Some(gpiob.pb8),
-            Some(gpiob.pb9),
-            Some(gpiob.pb6),
-            Some(gpiob.pb7),
-            Some(gpioc.pc0),
-            Some(gpioa.pa3),
-            Some(gpiob.pb1),
-            Some(gpioa.pa7),
-            Some(gpioa.pa6),
-            Some(gpioc.pc1),
-            Some(gpioc.pc4),
-            Some(gpioa.pa5),
-            Some(gpioa.pa4),
-            Some(gpioa.pa1),
-            Some(gpioa.pa0),
-            Some(gpiod.pd11),
-            Some(gpiog.pg9),
-            Some(gpioa.pa2),
-            Some(gpiob.pb14),
-            Some(gpiob.pb15),
+        let pins = gpio::Pins::new(
+            device.GPIOA.split(ccdr.peripheral.GPIOA),
+            device.GPIOB.split(ccdr.peripheral.GPIOB),
+            device.GPIOC.split(ccdr.peripheral.GPIOC),
+            device.GPIOD.split(ccdr.peripheral.GPIOD),
+            device.GPIOE.split(ccdr.peripheral.GPIOE),
+            device.GPIOF.split(ccdr.peripheral.GPIOF),
+            device.GPIOG.split(ccdr.peripheral.GPIOG),
+            device.GPIOH.split(ccdr.peripheral.GPIOH),
+            device.GPIOI.split(ccdr.peripheral.GPIOI),
         );
-
-        let (pin_dm, pin_dp) = { (gpioa.pa11.into_alternate(), gpioa.pa12.into_alternate()) };
+        let seed_led = pins.led.into_push_pull_output();
+
+        let (pin_dm, pin_dp) = {
+            (
+                pins.usb.pa11.into_alternate(),
+                pins.usb.pa12.into_alternate(),
+            )
+        };
         //float makes this a device
-        gpioa.pa10.into_floating_input();
+        pins.usb.pa10.into_floating_input();
         let usb = USB2::new(
             device.OTG2_HS_GLOBAL,
             device.OTG2_HS_DEVICE,
@@ -148,10 +124,7 @@ mod app {
             Shared {
                 usb: (usb_dev, midi),
             },
-            Local {
-                seed_led: gpio.led,
-                timer2,
-            },
+            Local { seed_led, timer2 },
             init::Monotonics(),
         )
     }
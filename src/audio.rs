@@ -4,13 +4,17 @@ use core::ops::{Deref, DerefMut};
 
 use log::info;
 
+use crate::gpio::AudioPins;
+use crate::mpu::MpuError;
+
 use stm32h7xx_hal::{
     dma,
-    gpio::{gpioe, Analog},
+    gpio::{gpioa, gpiod, gpioe, gpiog, Analog},
     pac::{self},
     rcc::{self, rec},
     sai::{self, *},
     stm32::{self, rcc::d2ccip1r::SAI1SEL_A},
+    time::Hertz,
     traits::i2s::FullDuplex,
 };
 
@@ -21,8 +25,69 @@ pub const DMA_BUFFER_SIZE: usize = BLOCK_SIZE_MAX * 2 * 2;
 
 pub type DmaBuffer = [u32; DMA_BUFFER_SIZE];
 
+/// Sample rates supported by [Audio], the same as libDaisy's
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleRate {
+    Rate8K,
+    Rate16K,
+    Rate32K,
+    Rate48K,
+    Rate96K,
+}
+
+impl SampleRate {
+    pub const fn hz(self) -> Hertz {
+        Hertz::from_raw(match self {
+            SampleRate::Rate8K => 8_000,
+            SampleRate::Rate16K => 16_000,
+            SampleRate::Rate32K => 32_000,
+            SampleRate::Rate48K => 48_000,
+            SampleRate::Rate96K => 96_000,
+        })
+    }
+}
+
+/// Sample rate and block size of [Audio]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioConfig {
+    pub sample_rate: SampleRate,
+    /// Samples per channel in each block, 1 to [BLOCK_SIZE_MAX]
+    pub block_size: usize,
+}
+
+impl Default for AudioConfig {
+    /// 48kHz with blocks of [BLOCK_SIZE_MAX], what [Audio::new] uses
+    fn default() -> Self {
+        Self {
+            sample_rate: SampleRate::Rate48K,
+            block_size: BLOCK_SIZE_MAX,
+        }
+    }
+}
+
+/// Audio start up errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioError {
+    /// The DMA buffer MPU region couldn't be set up
+    Mpu(MpuError),
+    /// The block size is 0 or more than [BLOCK_SIZE_MAX]
+    BlockSize,
+    /// The SAI didn't start moving data, e.g. there is no clock
+    Timeout,
+    /// The first sample couldn't be sent to the SAI
+    Sai,
+}
+
+impl From<MpuError> for AudioError {
+    fn from(e: MpuError) -> Self {
+        AudioError::Mpu(e)
+    }
+}
+
 const START_OF_DRAM2: u32 = 0x30000000;
 const DMA_MEM_SIZE: usize = 32 * 1024;
+// Status reads to wait for the SAI1 FIFO to fill at start up, well over a few samples
+const SAI_START_TIMEOUT: u32 = 1_000_000;
 
 #[link_section = ".sram1_bss"]
 #[no_mangle]
@@ -30,6 +95,12 @@ static mut TX_BUFFER: DmaBuffer = [0; DMA_BUFFER_SIZE];
 #[link_section = ".sram1_bss"]
 #[no_mangle]
 static mut RX_BUFFER: DmaBuffer = [0; DMA_BUFFER_SIZE];
+#[link_section = ".sram1_bss"]
+#[no_mangle]
+static mut SAI2_TX_BUFFER: DmaBuffer = [0; DMA_BUFFER_SIZE];
+#[link_section = ".sram1_bss"]
+#[no_mangle]
+static mut SAI2_RX_BUFFER: DmaBuffer = [0; DMA_BUFFER_SIZE];
 
 const FBIPMAX: f32 = 0.999985;
 const FBIPMIN: f32 = -FBIPMAX;
@@ -48,18 +119,28 @@ pub type AudioBuffer = [(f32, f32); BLOCK_SIZE_MAX];
 /// threaded and the references are short lived this should be fine.
 /// This wrapper is only, and may only be, pointing to memory with a 'static lifetime.
 struct DmaBufferRawRef {
-    ptr: *mut DmaBuffer,
+    ptr: *mut u32,
+    len: usize,
+}
+impl DmaBufferRawRef {
+    /// The start of `buffer` used by the DMA for two blocks of `block_size` stereo samples
+    fn new(buffer: *mut DmaBuffer, block_size: usize) -> Self {
+        Self {
+            ptr: buffer.cast(),
+            len: block_size * 2 * 2,
+        }
+    }
 }
 impl Deref for DmaBufferRawRef {
-    type Target = DmaBuffer;
+    type Target = [u32];
 
     fn deref(&self) -> &Self::Target {
-        unsafe { &*self.ptr }
+        unsafe { core::slice::from_raw_parts(self.ptr, self.len) }
     }
 }
 impl DerefMut for DmaBufferRawRef {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut *self.ptr }
+        unsafe { core::slice::from_raw_parts_mut(self.ptr, self.len) }
     }
 }
 unsafe impl stable_deref_trait::StableDeref for DmaBufferRawRef {}
@@ -82,6 +163,22 @@ type DmaOutputStream = dma::Transfer<
     dma::DBTransfer,
 >;
 
+type Sai2InputStream = dma::Transfer<
+    dma::dma::Stream1<stm32::DMA2>,
+    stm32::SAI2,
+    dma::PeripheralToMemory,
+    DmaBufferRawRef,
+    dma::DBTransfer,
+>;
+
+type Sai2OutputStream = dma::Transfer<
+    dma::dma::Stream0<stm32::DMA2>,
+    stm32::SAI2,
+    dma::MemoryToPeripheral,
+    DmaBufferRawRef,
+    dma::DBTransfer,
+>;
+
 type StereoIteratorHandle = fn(StereoIterator, &mut Output);
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -133,6 +230,7 @@ impl From<S24> for f32 {
 /// Core struct for handling audio I/O
 pub struct Audio {
     sai: sai::Sai<stm32::SAI1, sai::I2S>,
+    config: AudioConfig,
     input: Input,
     output: Output,
     input_stream: DmaInputStream,
@@ -157,15 +255,89 @@ impl Audio {
         mpu: &mut cortex_m::peripheral::MPU,
         scb: &mut cortex_m::peripheral::SCB,
     ) -> Self {
+        Self::try_new(
+            dma1_d,
+            dma1_p,
+            sai1_d,
+            sai1_p,
+            AudioPins {
+                pe2,
+                pe3,
+                pe4,
+                pe5,
+                pe6,
+            },
+            clocks,
+            mpu,
+            scb,
+        )
+        .expect("Failed to start audio")
+    }
+
+    /// Setup audio handler from the [Pins](crate::gpio::Pins), returns an error instead of
+    /// panicking or hanging when SAI1 doesn't start
+    #[allow(clippy::too_many_arguments)]
+    pub fn try_new(
+        dma1_d: stm32::DMA1,
+        dma1_p: rec::Dma1,
+        sai1_d: stm32::SAI1,
+        sai1_p: rec::Sai1,
+        pins: AudioPins,
+        clocks: &rcc::CoreClocks,
+        mpu: &mut cortex_m::peripheral::MPU,
+        scb: &mut cortex_m::peripheral::SCB,
+    ) -> Result<Self, AudioError> {
+        Self::try_new_with_config(
+            dma1_d,
+            dma1_p,
+            sai1_d,
+            sai1_p,
+            pins,
+            clocks,
+            mpu,
+            scb,
+            AudioConfig::default(),
+        )
+    }
+
+    /// Like [try_new](Audio::try_new) with another sample rate or block size.
+    ///
+    /// Remarks:
+    /// - PLL3 P must run at 96kHz * 256 as set up by
+    ///   [System::init_clocks](crate::system::System::init_clocks), SAI1 divides it down.
+    /// - The DMA1_STR1 interrupt fires once per block, [get_stereo](Audio#method.get_stereo)
+    ///   fills the first [block_size](Audio#method.block_size) samples of its buffer.
+    #[allow(clippy::too_many_arguments)]
+    pub fn try_new_with_config(
+        dma1_d: stm32::DMA1,
+        dma1_p: rec::Dma1,
+        sai1_d: stm32::SAI1,
+        sai1_p: rec::Sai1,
+        pins: AudioPins,
+        clocks: &rcc::CoreClocks,
+        mpu: &mut cortex_m::peripheral::MPU,
+        scb: &mut cortex_m::peripheral::SCB,
+        config: AudioConfig,
+    ) -> Result<Self, AudioError> {
+        if config.block_size == 0 || config.block_size > BLOCK_SIZE_MAX {
+            return Err(AudioError::BlockSize);
+        }
+        let block_size = config.block_size;
+        let AudioPins {
+            pe2,
+            pe3,
+            pe4,
+            pe5,
+            pe6,
+        } = pins;
+
         info!("Setup up DMA...");
-        crate::mpu::dma_init(mpu, scb, START_OF_DRAM2 as *mut u32, DMA_MEM_SIZE);
+        crate::mpu::try_dma_init(mpu, scb, START_OF_DRAM2 as *mut u32, DMA_MEM_SIZE)?;
 
         let dma1_streams = dma::dma::StreamsTuple::new(dma1_d, dma1_p);
 
         // dma1 stream 0
-        let tx_buffer = DmaBufferRawRef {
-            ptr: &raw mut TX_BUFFER,
-        };
+        let tx_buffer = DmaBufferRawRef::new(&raw mut TX_BUFFER, block_size);
         let dma_config = dma::dma::DmaConfig::default()
             .priority(dma::config::Priority::High)
             .memory_increment(true)
@@ -182,9 +354,7 @@ impl Audio {
             );
 
         // dma1 stream 1
-        let rx_buffer = DmaBufferRawRef {
-            ptr: &raw mut RX_BUFFER,
-        };
+        let rx_buffer = DmaBufferRawRef::new(&raw mut RX_BUFFER, block_size);
         let dma_config = dma_config
             .transfer_complete_interrupt(true)
             .half_transfer_interrupt(true);
@@ -215,7 +385,7 @@ impl Audio {
         // Hand off to audio module
         let mut sai = sai1_d.i2s_ch_a(
             pins_a,
-            crate::AUDIO_SAMPLE_HZ,
+            config.sample_rate.hz(),
             I2SDataSize::BITS_24,
             sai1_rec,
             clocks,
@@ -226,33 +396,39 @@ impl Audio {
             sai.enable_dma(SaiChannel::ChannelB);
         });
 
+        let mut started = Ok(());
         output_stream.start(|sai1_rb| {
             sai.enable_dma(SaiChannel::ChannelA);
 
             // wait until sai1's fifo starts to receive data
             info!("Sai1 fifo waiting to receive data.");
-            while sai1_rb.cha().sr.read().flvl().is_empty() {}
+            let mut timeout = SAI_START_TIMEOUT;
+            while sai1_rb.cha().sr.read().flvl().is_empty() {
+                if timeout == 0 {
+                    started = Err(AudioError::Timeout);
+                    return;
+                }
+                timeout -= 1;
+            }
             info!("Audio started!");
             sai.enable();
-            sai.try_send(0, 0).unwrap();
-        });
-        let input = Input::new(DmaBufferRawRef {
-            ptr: &raw mut RX_BUFFER,
-        });
-        let output = Output::new(DmaBufferRawRef {
-            ptr: &raw mut TX_BUFFER,
+            started = sai.try_send(0, 0).map_err(|_| AudioError::Sai);
         });
+        started?;
+        let input = Input::new(DmaBufferRawRef::new(&raw mut RX_BUFFER, block_size));
+        let output = Output::new(DmaBufferRawRef::new(&raw mut TX_BUFFER, block_size));
         info!(
             "{:?}, {:?}",
             &input.buffer[0] as *const u32, &output.buffer[0] as *const u32
         );
-        Audio {
+        Ok(Audio {
             sai,
+            config,
             input_stream,
             output_stream,
             input,
             output,
-        }
+        })
     }
 
     /// Check interrupts and set indexes for I/O
@@ -265,21 +441,31 @@ impl Audio {
             true
         } else if self.input_stream.get_transfer_complete_flag() {
             self.input_stream.clear_transfer_complete_interrupt();
-            self.input.set_index(MAX_TRANSFER_SIZE);
-            self.output.set_index(MAX_TRANSFER_SIZE);
+            self.input.set_index(self.input.transfer_size());
+            self.output.set_index(self.input.transfer_size());
             true
         } else {
             false
         }
     }
 
+    /// Samples per channel in each block
+    pub fn block_size(&self) -> usize {
+        self.config.block_size
+    }
+
+    /// The sample rate SAI1 runs at
+    pub fn sample_rate(&self) -> Hertz {
+        self.config.sample_rate.hz()
+    }
+
     /// Directly pass received audio to output without any processing.
     pub fn passthru(&mut self) {
         // Copy data
         if self.read() {
             let mut index = 0;
             let mut out_index = self.output.index;
-            while index < MAX_TRANSFER_SIZE {
+            while index < self.input.transfer_size() {
                 self.output.buffer[out_index] = self.input.buffer[index + self.input.index];
                 self.output.buffer[out_index + 1] = self.input.buffer[index + self.input.index + 1];
                 index += 2;
@@ -288,11 +474,12 @@ impl Audio {
         }
     }
 
-    /// Gets the audio input from the DMA memory and writes it to buffer
+    /// Gets the audio input from the DMA memory and writes it to the first
+    /// [block_size](Audio#method.block_size) samples of buffer
     pub fn get_stereo(&mut self, buffer: &mut AudioBuffer) -> bool {
         if self.read() {
             for (i, (left, right)) in StereoIterator::new(
-                &self.input.buffer[self.input.index..self.input.index + MAX_TRANSFER_SIZE],
+                &self.input.buffer[self.input.index..self.input.index + self.input.transfer_size()],
             )
             .enumerate()
             {
@@ -307,7 +494,7 @@ impl Audio {
     fn get_stereo_iter(&mut self) -> Option<StereoIterator> {
         if self.read() {
             return Some(StereoIterator::new(
-                &self.input.buffer[self.input.index..MAX_TRANSFER_SIZE],
+                &self.input.buffer[self.input.index..self.input.transfer_size()],
             ));
         }
         None
@@ -320,6 +507,174 @@ impl Audio {
     }
 }
 
+/// Audio I/O for a second codec on SAI2, e.g. the AK4556 of the Daisy Patch.
+///
+/// Works like [Audio], the DMA2_STR1 interrupt fires once per block.
+///
+/// Remarks:
+/// - Block B is the clock master and receives on SD_B, block A transmits on SD_A.
+/// - The SAI2 kernel clock must be PLL3 P, see
+///   [kernel_sai23_clk_mux](stm32h7xx_hal::rcc::rec::PeripheralREC).
+/// - Runs on the same clock as [Audio] but isn't synchronized to it, each has its own
+///   interrupt.
+/// - Always runs at 48kHz with blocks of [BLOCK_SIZE_MAX] samples.
+pub struct Sai2Audio {
+    sai: sai::Sai<stm32::SAI2, sai::I2S>,
+    input: Input,
+    output: Output,
+    input_stream: Sai2InputStream,
+    output_stream: Sai2OutputStream,
+}
+
+impl Sai2Audio {
+    /// Setup SAI2 with DMA2 streams 0 and 1. The DMA buffers share the MPU region set up by
+    /// [Audio], so start that first.
+    #[allow(clippy::too_many_arguments)]
+    pub fn try_new(
+        dma2_d: stm32::DMA2,
+        dma2_p: rec::Dma2,
+        sai2_d: stm32::SAI2,
+        sai2_p: rec::Sai2,
+        mclk_b: gpioa::PA1<Analog>,
+        sck_b: gpioa::PA2<Analog>,
+        fs_b: gpiog::PG9<Analog>,
+        sd_b: gpioa::PA0<Analog>,
+        sd_a: gpiod::PD11<Analog>,
+        clocks: &rcc::CoreClocks,
+    ) -> Result<Self, AudioError> {
+        let dma2_streams = dma::dma::StreamsTuple::new(dma2_d, dma2_p);
+
+        // dma2 stream 0
+        let dma_config = dma::dma::DmaConfig::default()
+            .priority(dma::config::Priority::High)
+            .memory_increment(true)
+            .peripheral_increment(false)
+            .circular_buffer(true)
+            .fifo_enable(false);
+        let mut output_stream: dma::Transfer<_, _, dma::MemoryToPeripheral, _, _> =
+            dma::Transfer::init(
+                dma2_streams.0,
+                unsafe { pac::Peripherals::steal().SAI2 },
+                DmaBufferRawRef::new(&raw mut SAI2_TX_BUFFER, BLOCK_SIZE_MAX),
+                None,
+                dma_config,
+            );
+
+        // dma2 stream 1
+        let dma_config = dma_config
+            .transfer_complete_interrupt(true)
+            .half_transfer_interrupt(true);
+        let mut input_stream: dma::Transfer<_, _, dma::PeripheralToMemory, _, _> =
+            dma::Transfer::init(
+                dma2_streams.1,
+                unsafe { pac::Peripherals::steal().SAI2 },
+                DmaBufferRawRef::new(&raw mut SAI2_RX_BUFFER, BLOCK_SIZE_MAX),
+                None,
+                dma_config,
+            );
+
+        info!("Setup up SAI2...");
+        let master_config = I2SChanConfig::new(I2SDir::Rx).set_frame_sync_active_high(true);
+        let slave_config = I2SChanConfig::new(I2SDir::Tx)
+            .set_sync_type(I2SSync::Internal)
+            .set_frame_sync_active_high(true);
+
+        let pins_b = (
+            mclk_b.into_alternate::<10>(),
+            sck_b.into_alternate::<8>(),
+            fs_b.into_alternate::<10>(),
+            sd_b.into_alternate::<10>(),
+            Some(sd_a.into_alternate::<10>()),
+        );
+
+        let mut sai = sai2_d.i2s_ch_b(
+            pins_b,
+            crate::AUDIO_SAMPLE_HZ,
+            I2SDataSize::BITS_24,
+            sai2_p,
+            clocks,
+            I2sUsers::new(master_config).add_slave(slave_config),
+        );
+
+        output_stream.start(|_sai2_rb| {
+            sai.enable_dma(SaiChannel::ChannelA);
+        });
+
+        let mut started = Ok(());
+        input_stream.start(|sai2_rb| {
+            sai.enable_dma(SaiChannel::ChannelB);
+
+            // wait until sai2's transmit fifo has been filled by the DMA
+            let mut timeout = SAI_START_TIMEOUT;
+            while sai2_rb.cha().sr.read().flvl().is_empty() {
+                if timeout == 0 {
+                    started = Err(AudioError::Timeout);
+                    return;
+                }
+                timeout -= 1;
+            }
+            sai.enable();
+            started = sai.try_send(0, 0).map_err(|_| AudioError::Sai);
+        });
+        started?;
+        info!("SAI2 started!");
+
+        Ok(Self {
+            sai,
+            input: Input::new(DmaBufferRawRef::new(
+                &raw mut SAI2_RX_BUFFER,
+                BLOCK_SIZE_MAX,
+            )),
+            output: Output::new(DmaBufferRawRef::new(
+                &raw mut SAI2_TX_BUFFER,
+                BLOCK_SIZE_MAX,
+            )),
+            input_stream,
+            output_stream,
+        })
+    }
+
+    /// Check interrupts and set indexes for I/O
+    fn read(&mut self) -> bool {
+        if self.input_stream.get_half_transfer_flag() {
+            self.input_stream.clear_half_transfer_interrupt();
+            self.input.set_index(0);
+            self.output.set_index(0);
+            true
+        } else if self.input_stream.get_transfer_complete_flag() {
+            self.input_stream.clear_transfer_complete_interrupt();
+            self.input.set_index(self.input.transfer_size());
+            self.output.set_index(self.input.transfer_size());
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Gets the audio input from the DMA memory and writes it to buffer
+    pub fn get_stereo(&mut self, buffer: &mut AudioBuffer) -> bool {
+        if self.read() {
+            for (i, (left, right)) in StereoIterator::new(
+                &self.input.buffer[self.input.index..self.input.index + self.input.transfer_size()],
+            )
+            .enumerate()
+            {
+                buffer[i] = (left, right);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Push data to the DMA buffer for output
+    /// Call this once per sample per call to [get_stereo()](Sai2Audio#method.get_stereo)
+    #[allow(clippy::result_unit_err)]
+    pub fn push_stereo(&mut self, data: (f32, f32)) -> Result<(), ()> {
+        self.output.push(data)
+    }
+}
+
 struct Input {
     index: usize,
     buffer: DmaBufferRawRef,
@@ -335,6 +690,11 @@ impl Input {
         self.index = index;
     }
 
+    /// Words in one half of the circular buffer, a block of stereo samples
+    fn transfer_size(&self) -> usize {
+        self.buffer.len() / 2
+    }
+
     /// Get StereoIterator(interleaved) iterator
     pub fn get_stereo_iter(&self) -> Option<StereoIterator> {
         Some(StereoIterator::new(&self.buffer[..2]))
@@ -357,7 +717,7 @@ impl Output {
     }
 
     pub fn push(&mut self, data: (f32, f32)) -> Result<(), ()> {
-        if self.index < (MAX_TRANSFER_SIZE * 2) {
+        if self.index < self.buffer.len() {
             self.buffer[self.index] = S24::from(data.0).into();
             self.buffer[self.index + 1] = S24::from(data.1).into();
             self.index += 2;
@@ -0,0 +1,7 @@
+//! Board support for the Daisy products built around the Seed.
+//!
+//! The Seed based boards take a [System](crate::system::System) and wire up their controls with
+//! the [hid](crate::hid) types. The Patch SM is a module of its own and replaces `System`.
+pub mod patch;
+pub mod patch_sm;
+pub mod pod;
@@ -0,0 +1,348 @@
+//! Daisy Patch: four CV/knob inputs, two gate inputs, a gate output, an encoder, a 128x64 OLED
+//! and a second codec for four audio channels.
+//!
+//! ```rust
+//! let Patch {
+//!     mut controls,
+//!     mut oled,
+//!     mut gate_out,
+//!     mut timer2,
+//!     audio,
+//!     audio2,
+//!     ..
+//! } = Patch::new(System::init(ctx.core, ctx.device));
+//!
+//! // TIM2 handler, runs at CONTROL_RATE
+//! timer2.clear_irq();
+//! controls.process_controls();
+//! if controls.ctrl1.get_value() > 0.5 {
+//!     gate_out.set_high();
+//! }
+//! if controls.gate_in1.trig() {
+//!     info!("Gate 1");
+//! }
+//! ```
+//!
+//! Remarks:
+//! - Audio channels 1 and 2 are the Seed's codec in `audio`, 3 and 4 the AK4556 in `audio2`.
+//! - The controls are read with ADC1, ADC2 is left free.
+//! - The MIDI pins and other unused pins are left in `gpio`.
+//! - The Patch takes SAI2, DMA2 and SPI1, which [System::init] leaves untouched.
+use stm32h7xx_hal::{
+    adc,
+    gpio::{Analog, Input, Output, PushPull},
+    hal::blocking::spi::Write,
+    prelude::*,
+    rcc::{self, rec::Sai23ClkSel},
+    spi, stm32,
+    time::Hertz,
+    timer::Timer,
+};
+
+use crate::audio::{Audio, AudioError, Sai2Audio};
+use crate::flash::Flash;
+use crate::gpio::*;
+use crate::hid::{AnalogControl, Encoder, GateIn, SwitchType};
+use crate::system::System;
+
+/// Rate of TIM2, call [process_controls](Controls#method.process_controls) from its interrupt
+pub const CONTROL_RATE: Hertz = Hertz::from_raw(1000);
+pub const OLED_WIDTH: usize = 128;
+pub const OLED_HEIGHT: usize = 64;
+
+// SSD1309 128x64, horizontal addressing
+const OLED_INIT: &[u8] = &[
+    0xAE, // display off
+    0xD5, 0x80, // clock divide
+    0xA8, 0x3F, // multiplex 64
+    0xD3, 0x00, // display offset
+    0x40, // start line 0
+    0x8D, 0x14, // charge pump on, SSD1306 panels stay dark without it, ignored by the SSD1309
+    0x20, 0x00, // horizontal addressing
+    0xA1, // segment remap
+    0xC8, // COM scan descending
+    0xDA, 0x12, // COM pins
+    0x81, 0x8F, // contrast
+    0xD9, 0x22, // precharge
+    0xDB, 0x34, // VCOMH
+    0xA4, // display follows RAM
+    0xA6, // not inverted
+    0xAF, // display on
+];
+
+pub type Ctrl1 = AnalogControl<Daisy21<Analog>>;
+pub type Ctrl2 = AnalogControl<Daisy15<Analog>>;
+pub type Ctrl3 = AnalogControl<Daisy16<Analog>>;
+pub type Ctrl4 = AnalogControl<Daisy18<Analog>>;
+pub type GateIn1 = GateIn<Daisy20<Input>>;
+pub type GateIn2 = GateIn<Daisy19<Input>>;
+pub type GateOut = Daisy17<Output<PushPull>>;
+pub type PatchEncoder = Encoder<Daisy12<Input>, Daisy11<Input>, Daisy0<Input>>;
+
+/// The CV/knob inputs, gate inputs and encoder of the Patch
+pub struct Controls {
+    /// Knob 1 plus CV 1
+    pub ctrl1: Ctrl1,
+    pub ctrl2: Ctrl2,
+    pub ctrl3: Ctrl3,
+    pub ctrl4: Ctrl4,
+    /// Gate inputs, `state` is the gate and `trig` fires on a rising edge
+    pub gate_in1: GateIn1,
+    pub gate_in2: GateIn2,
+    pub encoder: PatchEncoder,
+    pub adc1: adc::Adc<stm32::ADC1, adc::Enabled>,
+}
+
+/// SSD1309 OLED on SPI1 with a frame buffer, call [flush](Oled#method.flush) to show it
+pub struct Oled {
+    spi: spi::Spi<stm32::SPI1, spi::Enabled, u8>,
+    cs: Daisy7<Output<PushPull>>,
+    dc: Daisy9<Output<PushPull>>,
+    reset: Daisy30<Output<PushPull>>,
+    buffer: [u8; OLED_WIDTH * OLED_HEIGHT / 8],
+}
+
+/// The Patch's [Controls], OLED, gate output and second codec along with the rest of the
+/// [System]
+pub struct Patch {
+    pub controls: Controls,
+    pub gate_out: GateOut,
+    pub oled: Oled,
+    /// Audio channels 3 and 4
+    pub audio2: Sai2Audio,
+    /// GPIOs not used by the Patch
+    pub gpio: GPIO,
+    pub audio: Audio,
+    pub exti: stm32::EXTI,
+    pub syscfg: stm32::SYSCFG,
+    pub adc2: adc::Adc<stm32::ADC2, adc::Disabled>,
+    pub timer2: Timer<stm32::TIM2>,
+    pub sdram: &'static mut [f32],
+    pub flash: Flash,
+    /// MDMA and its clock, left free for [FlashDma::new](crate::flash::FlashDma::new)
+    pub mdma: stm32::MDMA,
+    pub mdma_rec: rcc::rec::Mdma,
+    pub clocks: rcc::CoreClocks,
+}
+
+impl Patch {
+    /// Take the Patch pins out of `system.gpio`, start the second codec and the OLED, enable
+    /// ADC1 and run TIM2 at `CONTROL_RATE`.
+    ///
+    /// Panics when one of the Patch pins was already taken or a peripheral fails to start, see
+    /// [try_new](Patch::try_new).
+    pub fn new(system: System) -> Self {
+        Self::try_new(system).expect("Failed to initialize the Patch")
+    }
+
+    /// Like [new](Patch::new), returns an error when the second codec or the OLED doesn't start
+    pub fn try_new(system: System) -> Result<Self, PatchError> {
+        let System {
+            mut gpio,
+            audio,
+            exti,
+            syscfg,
+            adc1,
+            adc2,
+            mut timer2,
+            sdram,
+            flash,
+            mdma,
+            mdma_rec,
+            clocks,
+        } = system;
+
+        timer2.set_freq(CONTROL_RATE);
+
+        let mut adc1 = adc1.enable();
+        adc1.set_resolution(adc::Resolution::SixteenBit);
+        let adc1_max = adc1.slope() as f32;
+
+        let ctrl1 = AnalogControl::new(take(&mut gpio.daisy21).into_analog(), adc1_max);
+        let ctrl2 = AnalogControl::new(take(&mut gpio.daisy15).into_analog(), adc1_max);
+        let ctrl3 = AnalogControl::new(take(&mut gpio.daisy16).into_analog(), adc1_max);
+        let ctrl4 = AnalogControl::new(take(&mut gpio.daisy18).into_analog(), adc1_max);
+
+        // Inverted by the input transistors, a high gate pulls the pin low
+        let gate_in1 = GateIn::new(take(&mut gpio.daisy20).into_floating_input(), true);
+        let gate_in2 = GateIn::new(take(&mut gpio.daisy19).into_floating_input(), true);
+        let gate_out = take(&mut gpio.daisy17).into_push_pull_output();
+
+        let encoder = Encoder::new(
+            take(&mut gpio.daisy12).into_pull_up_input(),
+            take(&mut gpio.daisy11).into_pull_up_input(),
+            take(&mut gpio.daisy0).into_pull_up_input(),
+            SwitchType::PullUp,
+        );
+
+        // SAFETY: System::init consumed the device peripherals without using SAI2, DMA2 or SPI1
+        // and the Patch is the only one taking them
+        let dp = unsafe { stm32::Peripherals::steal() };
+        let mut prec = unsafe { dp.RCC.constrain().steal_peripheral_rec() };
+        prec.kernel_sai23_clk_mux(Sai23ClkSel::Pll3P);
+
+        // Reset the AK4556 before its clocks start
+        let mut codec2_reset = take(&mut gpio.daisy29).into_push_pull_output();
+        codec2_reset.set_low();
+        crate::delay_ms(5);
+        codec2_reset.set_high();
+
+        let audio2 = Sai2Audio::try_new(
+            dp.DMA2,
+            prec.DMA2,
+            dp.SAI2,
+            prec.SAI2,
+            take(&mut gpio.daisy24),
+            take(&mut gpio.daisy28),
+            take(&mut gpio.daisy27),
+            take(&mut gpio.daisy25),
+            take(&mut gpio.daisy26),
+            &clocks,
+        )
+        .map_err(PatchError::Audio)?;
+
+        let sck = take(&mut gpio.daisy8).into_alternate::<5>();
+        let mosi = take(&mut gpio.daisy10).into_alternate::<5>();
+        let spi = dp.SPI1.spi(
+            (sck, spi::NoMiso, mosi),
+            spi::MODE_0,
+            8.MHz(),
+            prec.SPI1,
+            &clocks,
+        );
+        let mut oled = Oled {
+            spi,
+            cs: take(&mut gpio.daisy7).into_push_pull_output(),
+            dc: take(&mut gpio.daisy9).into_push_pull_output(),
+            reset: take(&mut gpio.daisy30).into_push_pull_output(),
+            buffer: [0; OLED_WIDTH * OLED_HEIGHT / 8],
+        };
+        oled.init().map_err(PatchError::Oled)?;
+
+        Ok(Self {
+            controls: Controls {
+                ctrl1,
+                ctrl2,
+                ctrl3,
+                ctrl4,
+                gate_in1,
+                gate_in2,
+                encoder,
+                adc1,
+            },
+            gate_out,
+            oled,
+            audio2,
+            gpio,
+            audio,
+            exti,
+            syscfg,
+            adc2,
+            timer2,
+            sdram,
+            flash,
+            mdma,
+            mdma_rec,
+            clocks,
+        })
+    }
+}
+
+/// Patch start up errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchError {
+    /// The second codec didn't start
+    Audio(AudioError),
+    /// The OLED couldn't be written
+    Oled(spi::Error),
+}
+
+impl Controls {
+    /// Sample the four controls and the gate inputs and debounce the encoder, call at
+    /// `CONTROL_RATE`.
+    pub fn process_controls(&mut self) {
+        if let Ok(data) = self.adc1.read(self.ctrl1.get_pin()) {
+            self.ctrl1.update(data);
+        }
+        if let Ok(data) = self.adc1.read(self.ctrl2.get_pin()) {
+            self.ctrl2.update(data);
+        }
+        if let Ok(data) = self.adc1.read(self.ctrl3.get_pin()) {
+            self.ctrl3.update(data);
+        }
+        if let Ok(data) = self.adc1.read(self.ctrl4.get_pin()) {
+            self.ctrl4.update(data);
+        }
+
+        self.gate_in1.update();
+        self.gate_in2.update();
+        self.encoder.update();
+    }
+}
+
+impl Oled {
+    /// Reset the display and turn it on with a blank screen
+    pub fn init(&mut self) -> Result<(), spi::Error> {
+        self.cs.set_high();
+        self.reset.set_low();
+        crate::delay_ms(1);
+        self.reset.set_high();
+        crate::delay_ms(1);
+
+        self.command(OLED_INIT)?;
+        self.clear();
+        self.flush()
+    }
+
+    /// Turn every pixel in the frame buffer off
+    pub fn clear(&mut self) {
+        self.buffer = [0; OLED_WIDTH * OLED_HEIGHT / 8];
+    }
+
+    /// Set a pixel in the frame buffer, (0, 0) is the top left. Pixels off screen are ignored.
+    pub fn set_pixel(&mut self, x: usize, y: usize, on: bool) {
+        if x >= OLED_WIDTH || y >= OLED_HEIGHT {
+            return;
+        }
+        let byte = &mut self.buffer[x + (y / 8) * OLED_WIDTH];
+        if on {
+            *byte |= 1 << (y % 8);
+        } else {
+            *byte &= !(1 << (y % 8));
+        }
+    }
+
+    /// The frame buffer, one byte is a column of 8 pixels, the lowest bit on top
+    pub fn buffer_mut(&mut self) -> &mut [u8; OLED_WIDTH * OLED_HEIGHT / 8] {
+        &mut self.buffer
+    }
+
+    /// Send the frame buffer to the display
+    pub fn flush(&mut self) -> Result<(), spi::Error> {
+        self.command(&[
+            0x21,
+            0,
+            OLED_WIDTH as u8 - 1,
+            0x22,
+            0,
+            OLED_HEIGHT as u8 / 8 - 1,
+        ])?;
+        self.dc.set_high();
+        self.cs.set_low();
+        let result = self.spi.write(&self.buffer);
+        self.cs.set_high();
+        result
+    }
+
+    fn command(&mut self, bytes: &[u8]) -> Result<(), spi::Error> {
+        self.dc.set_low();
+        self.cs.set_low();
+        let result = self.spi.write(bytes);
+        self.cs.set_high();
+        result
+    }
+}
+
+fn take<T>(pin: &mut Option<T>) -> T {
+    pin.take().expect("Patch pin already taken")
+}
@@ -0,0 +1,634 @@
+//! Daisy Patch Submodule (Patch SM): eight bipolar CV inputs, two CV outputs, two gate inputs
+//! and outputs and a PCM3060 codec.
+//!
+//! The Patch SM isn't a Seed, its pins are named by header and position, A1 to D10, instead of
+//! `Daisy0` to `Daisy30`. [PatchSm::new] replaces [System::init](crate::system::System::init)
+//! and sets up the memories, the codec and the controls:
+//!
+//! ```rust
+//! let PatchSm {
+//!     mut controls,
+//!     mut cv_out,
+//!     mut gate_out1,
+//!     audio,
+//!     mut timer2,
+//!     ..
+//! } = PatchSm::new(ctx.core, ctx.device);
+//!
+//! // TIM2 handler, runs at CONTROL_RATE
+//! timer2.clear_irq();
+//! controls.process_controls();
+//! // 1V/oct in on CV 1, out on CV out 1
+//! cv_out.set_voltage(CvOutChannel::One, controls.cv1.volts() + 1.0);
+//! if controls.gate_in1.state() {
+//!     gate_out1.set_high();
+//! }
+//! ```
+//!
+//! Remarks:
+//! - The CV inputs read -5V to 5V, see [CvCalibration] to correct for the tolerances of a
+//!   module.
+//! - The CV outputs are driven by the STM32 DAC, 0V to 5V.
+//! - The codec runs in its default 24 bit I2S format, only its power save is turned off.
+//! - The CV inputs are read with ADC1, ADC2 is left free.
+use stm32h7xx_hal::{
+    adc,
+    dac::{self, DacExt},
+    delay::Delay,
+    gpio::{self, Alternate, Analog, Input, Output, PushPull},
+    hal::blocking::i2c::Write,
+    i2c,
+    prelude::*,
+    rcc, stm32,
+    time::Hertz,
+    timer::{Event, Timer},
+    traits::DacOut,
+};
+
+use crate::audio::Audio;
+use crate::flash::Flash;
+use crate::gpio::SeedLed;
+use crate::gpio::{audio_pins, flash_pins, sdram_pins, AudioPins, FlashPins, SdramPins, UsbPins};
+use crate::hid::{AnalogControl, GateIn};
+use crate::sdram::Sdram;
+use crate::system::{System, SystemError};
+
+/// Rate of TIM2, call [process_controls](Controls#method.process_controls) from its interrupt
+pub const CONTROL_RATE: Hertz = Hertz::from_raw(1000);
+/// Highest voltage of the CV outputs
+pub const CV_OUT_MAX_VOLTS: f32 = 5.0;
+
+const PCM3060_ADDRESS: u8 = 0x46;
+const PCM3060_SYS: u8 = 0x40;
+// MRST low to reset the mode control registers, ADPSV and DAPSV high for power save
+const PCM3060_SYS_RESET: u8 = 0x70;
+// MRST and SRST high for normal operation, ADPSV and DAPSV low to power up, differential DAC
+const PCM3060_SYS_RUN: u8 = 0xC0;
+const DAC_MAX: f32 = 4095.0;
+
+/// The pins of the Patch SM by name, along with the on board memories and codec.
+///
+/// Remarks:
+/// - Only pins that are connected to the STM32 are included, e.g. B1-B4 are the audio jacks.
+/// - C1 and C10 are the CV outputs, C2-C9 the CV inputs, B5/B6 the gate outputs and B9/B10 the
+///   gate inputs, see [PatchSm].
+pub struct Pins {
+    /// User LED on the module
+    pub led: gpio::gpioc::PC7<Analog>,
+    /// UART4 RX
+    pub a2: gpio::gpioa::PA1<Analog>,
+    /// UART4 TX
+    pub a3: gpio::gpioa::PA0<Analog>,
+    /// USB HS D-
+    pub a8: gpio::gpiob::PB14<Analog>,
+    /// USB HS D+
+    pub a9: gpio::gpiob::PB15<Analog>,
+    /// Gate out 1
+    pub b5: gpio::gpioc::PC14<Analog>,
+    /// Gate out 2
+    pub b6: gpio::gpioc::PC13<Analog>,
+    /// I2C1 SCL
+    pub b7: gpio::gpiob::PB8<Analog>,
+    /// I2C1 SDA
+    pub b8: gpio::gpiob::PB9<Analog>,
+    /// Gate in 2
+    pub b9: gpio::gpiog::PG14<Analog>,
+    /// Gate in 1
+    pub b10: gpio::gpiog::PG13<Analog>,
+    /// CV out 2
+    pub c1: gpio::gpioa::PA5<Analog>,
+    /// CV in 4
+    pub c2: gpio::gpioa::PA7<Analog>,
+    /// CV in 3
+    pub c3: gpio::gpioa::PA2<Analog>,
+    /// CV in 2
+    pub c4: gpio::gpioa::PA6<Analog>,
+    /// CV in 1
+    pub c5: gpio::gpioa::PA3<Analog>,
+    /// CV in 5
+    pub c6: gpio::gpiob::PB1<Analog>,
+    /// CV in 6
+    pub c7: gpio::gpioc::PC4<Analog>,
+    /// CV in 7
+    pub c8: gpio::gpioc::PC0<Analog>,
+    /// CV in 8
+    pub c9: gpio::gpioc::PC1<Analog>,
+    /// CV out 1
+    pub c10: gpio::gpioa::PA4<Analog>,
+    /// SPI2 CS
+    pub d1: gpio::gpiob::PB4<Alternate<0>>,
+    /// SDMMC1 D3
+    pub d2: gpio::gpioc::PC11<Analog>,
+    /// SDMMC1 D2
+    pub d3: gpio::gpioc::PC10<Analog>,
+    /// SDMMC1 D1
+    pub d4: gpio::gpioc::PC9<Analog>,
+    /// SDMMC1 D0
+    pub d5: gpio::gpioc::PC8<Analog>,
+    /// SDMMC1 CK
+    pub d6: gpio::gpioc::PC12<Analog>,
+    /// SDMMC1 CMD
+    pub d7: gpio::gpiod::PD2<Analog>,
+    /// SPI2 MISO
+    pub d8: gpio::gpioc::PC2<Analog>,
+    /// SPI2 MOSI
+    pub d9: gpio::gpioc::PC3<Analog>,
+    /// SPI2 SCK
+    pub d10: gpio::gpiod::PD3<Analog>,
+    /// I2C2 SCL of the PCM3060 codec
+    pub codec_scl: gpio::gpioh::PH4<Analog>,
+    /// I2C2 SDA of the PCM3060 codec
+    pub codec_sda: gpio::gpiob::PB11<Analog>,
+    pub audio: AudioPins,
+    pub flash: FlashPins,
+    pub sdram: SdramPins,
+    pub usb: UsbPins,
+}
+
+impl Pins {
+    /// Sort the pins of the split ports by name, pins the Patch SM doesn't use are dropped
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        gpioa: gpio::gpioa::Parts,
+        gpiob: gpio::gpiob::Parts,
+        gpioc: gpio::gpioc::Parts,
+        gpiod: gpio::gpiod::Parts,
+        gpioe: gpio::gpioe::Parts,
+        gpiof: gpio::gpiof::Parts,
+        gpiog: gpio::gpiog::Parts,
+        gpioh: gpio::gpioh::Parts,
+        gpioi: gpio::gpioi::Parts,
+    ) -> Self {
+        Self {
+            led: gpioc.pc7,
+            a2: gpioa.pa1,
+            a3: gpioa.pa0,
+            a8: gpiob.pb14,
+            a9: gpiob.pb15,
+            b5: gpioc.pc14,
+            b6: gpioc.pc13,
+            b7: gpiob.pb8,
+            b8: gpiob.pb9,
+            b9: gpiog.pg14,
+            b10: gpiog.pg13,
+            c1: gpioa.pa5,
+            c2: gpioa.pa7,
+            c3: gpioa.pa2,
+            c4: gpioa.pa6,
+            c5: gpioa.pa3,
+            c6: gpiob.pb1,
+            c7: gpioc.pc4,
+            c8: gpioc.pc0,
+            c9: gpioc.pc1,
+            c10: gpioa.pa4,
+            d1: gpiob.pb4,
+            d2: gpioc.pc11,
+            d3: gpioc.pc10,
+            d4: gpioc.pc9,
+            d5: gpioc.pc8,
+            d6: gpioc.pc12,
+            d7: gpiod.pd2,
+            d8: gpioc.pc2,
+            d9: gpioc.pc3,
+            d10: gpiod.pd3,
+            codec_scl: gpioh.ph4,
+            codec_sda: gpiob.pb11,
+            audio: audio_pins!(gpioe),
+            flash: flash_pins!(gpiof, gpiog),
+            sdram: sdram_pins!(gpiod, gpioe, gpiof, gpiog, gpioh, gpioi),
+            usb: UsbPins {
+                pa10: gpioa.pa10,
+                pa11: gpioa.pa11,
+                pa12: gpioa.pa12,
+            },
+        }
+    }
+}
+
+/// The header pins [PatchSm] leaves free, see [Pins] for what they are
+pub struct Gpio {
+    pub a2: gpio::gpioa::PA1<Analog>,
+    pub a3: gpio::gpioa::PA0<Analog>,
+    pub a8: gpio::gpiob::PB14<Analog>,
+    pub a9: gpio::gpiob::PB15<Analog>,
+    pub b7: gpio::gpiob::PB8<Analog>,
+    pub b8: gpio::gpiob::PB9<Analog>,
+    pub d1: gpio::gpiob::PB4<Alternate<0>>,
+    pub d2: gpio::gpioc::PC11<Analog>,
+    pub d3: gpio::gpioc::PC10<Analog>,
+    pub d4: gpio::gpioc::PC9<Analog>,
+    pub d5: gpio::gpioc::PC8<Analog>,
+    pub d6: gpio::gpioc::PC12<Analog>,
+    pub d7: gpio::gpiod::PD2<Analog>,
+    pub d8: gpio::gpioc::PC2<Analog>,
+    pub d9: gpio::gpioc::PC3<Analog>,
+    pub d10: gpio::gpiod::PD3<Analog>,
+    pub usb: UsbPins,
+}
+
+/// Maps the normalized ADC reading of a CV input to volts, `volts = (reading - zero) * scale`.
+///
+/// The inputs are inverting, the default maps 0.0 to 5V and 1.0 to -5V. Measure two known
+/// voltages to correct a module:
+///
+/// ```rust
+/// // Readings with 0V and 3V patched into CV 1
+/// let calibration = CvCalibration::from_readings((0.502, 0.0), (0.198, 3.0)).unwrap();
+/// controls.cv1.set_calibration(calibration);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CvCalibration {
+    /// Reading at 0V
+    pub zero: f32,
+    /// Volts per full scale reading
+    pub scale: f32,
+}
+
+impl Default for CvCalibration {
+    fn default() -> Self {
+        Self {
+            zero: 0.5,
+            scale: -10.0,
+        }
+    }
+}
+
+impl CvCalibration {
+    /// Calibration from two `(reading, volts)` pairs, `None` when the readings or the voltages
+    /// are equal
+    pub fn from_readings(a: (f32, f32), b: (f32, f32)) -> Option<Self> {
+        if a.0 == b.0 || a.1 == b.1 {
+            return None;
+        }
+        let scale = (b.1 - a.1) / (b.0 - a.0);
+        Some(Self {
+            zero: a.0 - a.1 / scale,
+            scale,
+        })
+    }
+
+    /// Volts for a normalized reading
+    pub fn volts(&self, reading: f32) -> f32 {
+        (reading - self.zero) * self.scale
+    }
+}
+
+/// A bipolar CV input, an [AnalogControl] with a [CvCalibration]
+pub struct CvInput<T> {
+    control: AnalogControl<T>,
+    calibration: CvCalibration,
+}
+
+impl<T> CvInput<T> {
+    /// Create a new CvInput with the default calibration, `scale` is the ADC full scale
+    pub fn new(pin: T, scale: f32) -> Self {
+        Self {
+            control: AnalogControl::new(pin, scale),
+            calibration: CvCalibration::default(),
+        }
+    }
+
+    /// Replace the calibration, e.g. with one loaded from flash
+    pub fn set_calibration(&mut self, calibration: CvCalibration) {
+        self.calibration = calibration;
+    }
+
+    /// The current calibration
+    pub fn calibration(&self) -> CvCalibration {
+        self.calibration
+    }
+
+    /// Update with an ADC reading, see [AnalogControl::update]
+    pub fn update(&mut self, value: u32) {
+        self.control.update(value);
+    }
+
+    /// The averaged normalized reading from 0.0 to 1.0, before calibration
+    pub fn reading(&self) -> f32 {
+        self.control.get_value()
+    }
+
+    /// The input voltage
+    pub fn volts(&self) -> f32 {
+        self.calibration.volts(self.reading())
+    }
+
+    /// The input from -1.0 at -5V to 1.0 at 5V
+    pub fn get_value(&self) -> f32 {
+        self.volts() / 5.0
+    }
+
+    /// Get the pin associated with this input.
+    pub fn get_pin(&mut self) -> &mut T {
+        self.control.get_pin()
+    }
+}
+
+/// CV output channels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CvOutChannel {
+    /// C10
+    One,
+    /// C1
+    Two,
+}
+
+/// The two CV outputs, driven by the 12 bit STM32 DAC with its output buffer
+pub struct CvOut {
+    out1: dac::C1<stm32::DAC, dac::Enabled>,
+    out2: dac::C2<stm32::DAC, dac::Enabled>,
+}
+
+impl CvOut {
+    /// Set an output from 0V to `CV_OUT_MAX_VOLTS`, values outside are clamped
+    pub fn set_voltage(&mut self, channel: CvOutChannel, volts: f32) {
+        let value = (volts / CV_OUT_MAX_VOLTS * DAC_MAX).clamp(0.0, DAC_MAX) as u16;
+        match channel {
+            CvOutChannel::One => self.out1.set_value(value),
+            CvOutChannel::Two => self.out2.set_value(value),
+        }
+    }
+}
+
+pub type Cv1 = CvInput<gpio::gpioa::PA3<Analog>>;
+pub type Cv2 = CvInput<gpio::gpioa::PA6<Analog>>;
+pub type Cv3 = CvInput<gpio::gpioa::PA2<Analog>>;
+pub type Cv4 = CvInput<gpio::gpioa::PA7<Analog>>;
+pub type Cv5 = CvInput<gpio::gpiob::PB1<Analog>>;
+pub type Cv6 = CvInput<gpio::gpioc::PC4<Analog>>;
+pub type Cv7 = CvInput<gpio::gpioc::PC0<Analog>>;
+pub type Cv8 = CvInput<gpio::gpioc::PC1<Analog>>;
+pub type GateIn1 = GateIn<gpio::gpiog::PG13<Input>>;
+pub type GateIn2 = GateIn<gpio::gpiog::PG14<Input>>;
+pub type GateOut1 = gpio::gpioc::PC14<Output<PushPull>>;
+pub type GateOut2 = gpio::gpioc::PC13<Output<PushPull>>;
+
+/// The CV and gate inputs of the Patch SM
+pub struct Controls {
+    pub cv1: Cv1,
+    pub cv2: Cv2,
+    pub cv3: Cv3,
+    pub cv4: Cv4,
+    pub cv5: Cv5,
+    pub cv6: Cv6,
+    pub cv7: Cv7,
+    pub cv8: Cv8,
+    /// Gate inputs, `state` is the gate and `trig` fires on a rising edge
+    pub gate_in1: GateIn1,
+    pub gate_in2: GateIn2,
+    pub adc1: adc::Adc<stm32::ADC1, adc::Enabled>,
+}
+
+impl Controls {
+    /// Sample the eight CV inputs and the gate inputs, call at `CONTROL_RATE`.
+    pub fn process_controls(&mut self) {
+        if let Ok(data) = self.adc1.read(self.cv1.get_pin()) {
+            self.cv1.update(data);
+        }
+        if let Ok(data) = self.adc1.read(self.cv2.get_pin()) {
+            self.cv2.update(data);
+        }
+        if let Ok(data) = self.adc1.read(self.cv3.get_pin()) {
+            self.cv3.update(data);
+        }
+        if let Ok(data) = self.adc1.read(self.cv4.get_pin()) {
+            self.cv4.update(data);
+        }
+        if let Ok(data) = self.adc1.read(self.cv5.get_pin()) {
+            self.cv5.update(data);
+        }
+        if let Ok(data) = self.adc1.read(self.cv6.get_pin()) {
+            self.cv6.update(data);
+        }
+        if let Ok(data) = self.adc1.read(self.cv7.get_pin()) {
+            self.cv7.update(data);
+        }
+        if let Ok(data) = self.adc1.read(self.cv8.get_pin()) {
+            self.cv8.update(data);
+        }
+
+        self.gate_in1.update();
+        self.gate_in2.update();
+    }
+}
+
+/// Patch SM start up errors
+#[derive(Debug)]
+pub enum PatchSmError {
+    /// Same as for the Seed
+    System(SystemError),
+    /// The PCM3060 didn't answer on I2C2
+    Codec(i2c::Error),
+}
+
+impl From<SystemError> for PatchSmError {
+    fn from(e: SystemError) -> Self {
+        PatchSmError::System(e)
+    }
+}
+
+impl PatchSmError {
+    /// See [SystemError::code], 5 for the codec
+    pub fn code(&self) -> u8 {
+        match self {
+            PatchSmError::System(e) => e.code(),
+            PatchSmError::Codec(_) => 5,
+        }
+    }
+}
+
+/// The Patch SM hardware, the counterpart of [System] for the Seed
+pub struct PatchSm {
+    pub controls: Controls,
+    pub cv_out: CvOut,
+    pub gate_out1: GateOut1,
+    pub gate_out2: GateOut2,
+    pub led: SeedLed,
+    /// Header pins not used by the Patch SM
+    pub gpio: Gpio,
+    /// Core peripherals, given back after setup
+    pub core: rtic::export::Peripherals,
+    pub audio: Audio,
+    pub exti: stm32::EXTI,
+    pub syscfg: stm32::SYSCFG,
+    pub adc2: adc::Adc<stm32::ADC2, adc::Disabled>,
+    pub timer2: Timer<stm32::TIM2>,
+    pub sdram: &'static mut [f32],
+    pub flash: Flash,
+    /// MDMA and its clock, left free for [FlashDma::new](crate::flash::FlashDma::new)
+    pub mdma: stm32::MDMA,
+    pub mdma_rec: rcc::rec::Mdma,
+    pub clocks: rcc::CoreClocks,
+}
+
+impl PatchSm {
+    /// Batteries included initialization, panics when something fails to start, see
+    /// [try_new](PatchSm::try_new)
+    pub fn new(core: rtic::export::Peripherals, device: stm32::Peripherals) -> Self {
+        Self::try_new(core, device).expect("Patch SM init failed")
+    }
+
+    /// Batteries included initialization that reports what failed to start, see
+    /// [System::try_init]. TIM2 runs at `CONTROL_RATE`.
+    pub fn try_new(
+        mut core: rtic::export::Peripherals,
+        device: stm32::Peripherals,
+    ) -> Result<Self, PatchSmError> {
+        let ccdr = System::try_init_clocks(device.PWR, device.RCC, &device.SYSCFG)
+            .map_err(SystemError::Clocks)?;
+        let clocks = ccdr.clocks;
+
+        let mut delay = Delay::new(core.SYST, clocks);
+        let (adc1, adc2) = adc::adc12(
+            device.ADC1,
+            device.ADC2,
+            4.MHz(),
+            &mut delay,
+            ccdr.peripheral.ADC12,
+            &clocks,
+        );
+
+        System::init_debug(&mut core.DCB, &mut core.DWT);
+
+        let mut timer2 = device
+            .TIM2
+            .timer(CONTROL_RATE, ccdr.peripheral.TIM2, &clocks);
+        timer2.listen(Event::TimeOut);
+
+        let pins = Pins::new(
+            device.GPIOA.split(ccdr.peripheral.GPIOA),
+            device.GPIOB.split(ccdr.peripheral.GPIOB),
+            device.GPIOC.split(ccdr.peripheral.GPIOC),
+            device.GPIOD.split(ccdr.peripheral.GPIOD),
+            device.GPIOE.split(ccdr.peripheral.GPIOE),
+            device.GPIOF.split(ccdr.peripheral.GPIOF),
+            device.GPIOG.split(ccdr.peripheral.GPIOG),
+            device.GPIOH.split(ccdr.peripheral.GPIOH),
+            device.GPIOI.split(ccdr.peripheral.GPIOI),
+        );
+
+        let sdram = Sdram::try_new(
+            device.FMC,
+            ccdr.peripheral.FMC,
+            &clocks,
+            &mut delay,
+            &mut core.SCB,
+            &mut core.MPU,
+            pins.sdram,
+        )
+        .map_err(SystemError::Sdram)?
+        .into();
+
+        let audio = Audio::try_new(
+            device.DMA1,
+            ccdr.peripheral.DMA1,
+            device.SAI1,
+            ccdr.peripheral.SAI1,
+            pins.audio,
+            &clocks,
+            &mut core.MPU,
+            &mut core.SCB,
+        )
+        .map_err(SystemError::Audio)?;
+
+        // The PCM3060 needs its clocks running to leave reset
+        let mut codec = device.I2C2.i2c(
+            (
+                pins.codec_scl.into_alternate_open_drain::<4>(),
+                pins.codec_sda.into_alternate_open_drain::<4>(),
+            ),
+            400.kHz(),
+            ccdr.peripheral.I2C2,
+            &clocks,
+        );
+        codec
+            .write(PCM3060_ADDRESS, &[PCM3060_SYS, PCM3060_SYS_RESET])
+            .map_err(PatchSmError::Codec)?;
+        delay.delay_ms(4_u8);
+        codec
+            .write(PCM3060_ADDRESS, &[PCM3060_SYS, PCM3060_SYS_RUN])
+            .map_err(PatchSmError::Codec)?;
+
+        System::init_cache(&mut core.SCB, &mut core.CPUID);
+
+        let flash = Flash::new(
+            device.QUADSPI,
+            ccdr.peripheral.QSPI,
+            &clocks,
+            pins.flash.pf6,
+            pins.flash.pf7,
+            pins.flash.pf8,
+            pins.flash.pf9,
+            pins.flash.pf10,
+            pins.flash.pg6,
+        )
+        .map_err(SystemError::Flash)?;
+
+        let mut adc1 = adc1.enable();
+        adc1.set_resolution(adc::Resolution::SixteenBit);
+        let adc1_max = adc1.slope() as f32;
+
+        // Inverted by the input transistors, a high gate pulls the pin low
+        let controls = Controls {
+            cv1: CvInput::new(pins.c5.into_analog(), adc1_max),
+            cv2: CvInput::new(pins.c4.into_analog(), adc1_max),
+            cv3: CvInput::new(pins.c3.into_analog(), adc1_max),
+            cv4: CvInput::new(pins.c2.into_analog(), adc1_max),
+            cv5: CvInput::new(pins.c6.into_analog(), adc1_max),
+            cv6: CvInput::new(pins.c7.into_analog(), adc1_max),
+            cv7: CvInput::new(pins.c8.into_analog(), adc1_max),
+            cv8: CvInput::new(pins.c9.into_analog(), adc1_max),
+            gate_in1: GateIn::new(pins.b10.into_floating_input(), true),
+            gate_in2: GateIn::new(pins.b9.into_floating_input(), true),
+            adc1,
+        };
+
+        let (out1, out2) = device.DAC.dac((pins.c10, pins.c1), ccdr.peripheral.DAC12);
+        let mut cv_out = CvOut {
+            out1: out1.enable(),
+            out2: out2.enable(),
+        };
+        cv_out.set_voltage(CvOutChannel::One, 0.0);
+        cv_out.set_voltage(CvOutChannel::Two, 0.0);
+
+        core.SYST = delay.free();
+
+        Ok(Self {
+            controls,
+            cv_out,
+            gate_out1: pins.b5.into_push_pull_output(),
+            gate_out2: pins.b6.into_push_pull_output(),
+            led: pins.led.into_push_pull_output(),
+            gpio: Gpio {
+                a2: pins.a2,
+                a3: pins.a3,
+                a8: pins.a8,
+                a9: pins.a9,
+                b7: pins.b7,
+                b8: pins.b8,
+                d1: pins.d1,
+                d2: pins.d2,
+                d3: pins.d3,
+                d4: pins.d4,
+                d5: pins.d5,
+                d6: pins.d6,
+                d7: pins.d7,
+                d8: pins.d8,
+                d9: pins.d9,
+                d10: pins.d10,
+                usb: pins.usb,
+            },
+            core,
+            audio,
+            exti: device.EXTI,
+            syscfg: device.SYSCFG,
+            adc2,
+            timer2,
+            sdram,
+            flash,
+            mdma: device.MDMA,
+            mdma_rec: ccdr.peripheral.MDMA,
+            clocks,
+        })
+    }
+}
@@ -0,0 +1,196 @@
+//! Daisy Pod: two knobs, two buttons, an encoder and two RGB LEDs around a Seed.
+//!
+//! ```rust
+//! let Pod {
+//!     mut controls,
+//!     mut timer2,
+//!     audio,
+//!     ..
+//! } = Pod::new(System::init(ctx.core, ctx.device));
+//!
+//! // TIM2 handler, runs at CONTROL_RATE
+//! timer2.clear_irq();
+//! controls.process_controls();
+//! controls.led1.set(controls.knob1.get_value(), 0.0, controls.knob2.get_value());
+//! if controls.switch1.is_falling() {
+//!     info!("Button 1 pressed");
+//! }
+//! controls.update_leds();
+//! ```
+//!
+//! Remarks:
+//! - The knobs are read with ADC1, ADC2 is left free.
+//! - Pins the Pod doesn't use are left in `gpio`.
+use stm32h7xx_hal::{
+    adc,
+    gpio::{Analog, Input, Output, PushPull},
+    prelude::*,
+    rcc, stm32,
+    time::Hertz,
+    timer::Timer,
+};
+
+use crate::audio::Audio;
+use crate::flash::Flash;
+use crate::gpio::*;
+use crate::hid::{AnalogControl, Encoder, RgbLed, Switch, SwitchType};
+use crate::system::System;
+
+/// Rate of TIM2, call [process_controls](Controls#method.process_controls) and
+/// [update_leds](Controls#method.update_leds) from its interrupt
+pub const CONTROL_RATE: Hertz = Hertz::from_raw(1000);
+/// Brightness levels of the LEDs, at `CONTROL_RATE` they run at 100Hz
+pub const LED_RESOLUTION: u32 = 10;
+
+pub type Knob1 = AnalogControl<Daisy21<Analog>>;
+pub type Knob2 = AnalogControl<Daisy15<Analog>>;
+pub type Switch1 = Switch<Daisy27<Input>>;
+pub type Switch2 = Switch<Daisy28<Input>>;
+pub type PodEncoder = Encoder<Daisy26<Input>, Daisy25<Input>, Daisy13<Input>>;
+pub type Led1 =
+    RgbLed<Daisy20<Output<PushPull>>, Daisy19<Output<PushPull>>, Daisy18<Output<PushPull>>>;
+pub type Led2 =
+    RgbLed<Daisy17<Output<PushPull>>, Daisy24<Output<PushPull>>, Daisy23<Output<PushPull>>>;
+
+/// The knobs, buttons, encoder and LEDs of the Pod
+pub struct Controls {
+    pub knob1: Knob1,
+    pub knob2: Knob2,
+    pub switch1: Switch1,
+    pub switch2: Switch2,
+    pub encoder: PodEncoder,
+    pub led1: Led1,
+    pub led2: Led2,
+    pub adc1: adc::Adc<stm32::ADC1, adc::Enabled>,
+}
+
+/// The Pod's [Controls] along with the rest of the [System]
+pub struct Pod {
+    pub controls: Controls,
+    /// GPIOs not used by the Pod
+    pub gpio: GPIO,
+    pub audio: Audio,
+    pub exti: stm32::EXTI,
+    pub syscfg: stm32::SYSCFG,
+    pub adc2: adc::Adc<stm32::ADC2, adc::Disabled>,
+    pub timer2: Timer<stm32::TIM2>,
+    pub sdram: &'static mut [f32],
+    pub flash: Flash,
+    /// MDMA and its clock, left free for [FlashDma::new](crate::flash::FlashDma::new)
+    pub mdma: stm32::MDMA,
+    pub mdma_rec: rcc::rec::Mdma,
+    pub clocks: rcc::CoreClocks,
+}
+
+impl Pod {
+    /// Take the Pod pins out of `system.gpio`, enable ADC1 and run TIM2 at `CONTROL_RATE`.
+    ///
+    /// Panics when one of the Pod pins was already taken.
+    pub fn new(system: System) -> Self {
+        let System {
+            mut gpio,
+            audio,
+            exti,
+            syscfg,
+            adc1,
+            adc2,
+            mut timer2,
+            sdram,
+            flash,
+            mdma,
+            mdma_rec,
+            clocks,
+        } = system;
+
+        timer2.set_freq(CONTROL_RATE);
+
+        let mut adc1 = adc1.enable();
+        adc1.set_resolution(adc::Resolution::SixteenBit);
+        let adc1_max = adc1.slope() as f32;
+
+        let knob1 = AnalogControl::new(take(&mut gpio.daisy21).into_analog(), adc1_max);
+        let knob2 = AnalogControl::new(take(&mut gpio.daisy15).into_analog(), adc1_max);
+
+        let switch1 = Switch::new(
+            take(&mut gpio.daisy27).into_pull_up_input(),
+            SwitchType::PullUp,
+        );
+        let switch2 = Switch::new(
+            take(&mut gpio.daisy28).into_pull_up_input(),
+            SwitchType::PullUp,
+        );
+
+        let encoder = Encoder::new(
+            take(&mut gpio.daisy26).into_pull_up_input(),
+            take(&mut gpio.daisy25).into_pull_up_input(),
+            take(&mut gpio.daisy13).into_pull_up_input(),
+            SwitchType::PullUp,
+        );
+
+        // Common anode, a low pin turns the LED on
+        let led1 = RgbLed::new(
+            take(&mut gpio.daisy20).into_push_pull_output(),
+            take(&mut gpio.daisy19).into_push_pull_output(),
+            take(&mut gpio.daisy18).into_push_pull_output(),
+            true,
+            LED_RESOLUTION,
+        );
+        let led2 = RgbLed::new(
+            take(&mut gpio.daisy17).into_push_pull_output(),
+            take(&mut gpio.daisy24).into_push_pull_output(),
+            take(&mut gpio.daisy23).into_push_pull_output(),
+            true,
+            LED_RESOLUTION,
+        );
+
+        Self {
+            controls: Controls {
+                knob1,
+                knob2,
+                switch1,
+                switch2,
+                encoder,
+                led1,
+                led2,
+                adc1,
+            },
+            gpio,
+            audio,
+            exti,
+            syscfg,
+            adc2,
+            timer2,
+            sdram,
+            flash,
+            mdma,
+            mdma_rec,
+            clocks,
+        }
+    }
+}
+
+impl Controls {
+    /// Sample both knobs and debounce the buttons and the encoder, call at `CONTROL_RATE`.
+    pub fn process_controls(&mut self) {
+        if let Ok(data) = self.adc1.read(self.knob1.get_pin()) {
+            self.knob1.update(data);
+        }
+        if let Ok(data) = self.adc1.read(self.knob2.get_pin()) {
+            self.knob2.update(data);
+        }
+
+        self.switch1.update();
+        self.switch2.update();
+        self.encoder.update();
+    }
+
+    /// Run the software PWM of both LEDs, call at `CONTROL_RATE`.
+    pub fn update_leds(&mut self) {
+        self.led1.update();
+        self.led2.update();
+    }
+}
+
+fn take<T>(pin: &mut Option<T>) -> T {
+    pin.take().expect("Pod pin already taken")
+}
@@ -0,0 +1,34 @@
+//! CRC-32 (IEEE 802.3) used to check data stored in flash
+
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+/// Incremental CRC-32 calculation
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Crc32(u32);
+
+impl Crc32 {
+    pub(crate) fn new() -> Self {
+        Self(0xFFFF_FFFF)
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0 ^= *byte as u32;
+            for _ in 0..8 {
+                let mask = (self.0 & 1).wrapping_neg();
+                self.0 = (self.0 >> 1) ^ (POLYNOMIAL & mask);
+            }
+        }
+    }
+
+    pub(crate) fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+/// CRC-32 of `data`
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
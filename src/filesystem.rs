@@ -0,0 +1,1046 @@
+//! Small log-structured filesystem for presets, samples and wavetables in the QSPI flash.
+//!
+//! The filesystem lives in a sector aligned range of the flash, so the rest of the chip can be
+//! kept for the bootloader or XIP code, see [DEFAULT_START].
+//!
+//! Layout:
+//! - Two journal areas at the start of the range. Every change (create, replace, rename,
+//!   remove) appends a CRC checked record to the active area. When it is full, the current
+//!   table is written to the other area, which only becomes active once its header is written.
+//! - Data sectors after that. Every file is stored in its own contiguous run of sectors, so it
+//!   can also be read memory mapped or with the MDMA, see [Metadata::address].
+//!
+//! New file contents always go to free sectors and only replace the old contents once the record
+//! is written, so a power loss at any point leaves either the old or the new version. Partially
+//! written records are skipped on mount.
+//!
+//! The table of files and directories is kept in RAM and holds up to `N` entries.
+//!
+//! # Example
+//!
+//! ```rust
+//! let mut fs = match FileSystem::<64>::mount(&mut flash, DEFAULT_START, flash.info().size) {
+//!     Ok(fs) => fs,
+//!     Err(FsError::NotFormatted) => {
+//!         FileSystem::format(&mut flash, DEFAULT_START, flash.info().size).unwrap()
+//!     }
+//!     Err(e) => panic!("{:?}", e),
+//! };
+//! fs.create_dir(&mut flash, "/presets").unwrap();
+//! fs.write_file(&mut flash, "/presets/init.bin", &preset).unwrap();
+//!
+//! let mut buffer = [0; 64];
+//! let len = fs.read(&mut flash, "/presets/init.bin", 0, &mut buffer).unwrap();
+//! ```
+
+use core::convert::Infallible;
+
+use embedded_storage::nor_flash::{ErrorType, NorFlash};
+
+use crate::crc::{crc32, Crc32};
+
+/// Start of the filesystem used in the examples, the first 1M is left for the bootloader and
+/// programs executed from flash
+pub const DEFAULT_START: u32 = 0x10_0000;
+/// Longest file or directory name in bytes
+pub const NAME_MAX: usize = 32;
+
+// "DFS1"
+const AREA_MAGIC: u32 = 0x4446_5331;
+const RECORD_MAGIC: u16 = 0x5245;
+const RECORD_SIZE: usize = 64;
+// Data is written through a buffer of this size, it must be a multiple of the write size
+const BUFFER_SIZE: usize = 256;
+const ROOT: u16 = 0;
+
+const OP_PUT: u8 = 1;
+const OP_REMOVE: u8 = 2;
+
+/// Filesystem errors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsError<E> {
+    /// Error from the flash
+    Flash(E),
+    /// No valid journal was found, use `format`
+    NotFormatted,
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    /// Empty path or name, `.`, `..` or a directory moved into itself
+    InvalidPath,
+    /// A name is longer than [NAME_MAX]
+    NameTooLong,
+    /// Not enough contiguous free sectors
+    NoSpace,
+    /// The table of `N` entries is full
+    TooManyEntries,
+    /// Another [FileWriter] is in progress
+    Busy,
+    /// More or less data written than given to `create`
+    SizeMismatch,
+    /// File data does not match its CRC
+    Corrupt,
+}
+
+type FsResult<T, F> = Result<T, FsError<<F as ErrorType>::Error>>;
+
+/// Error of [metadata](FileSystem#method.metadata) and [read_dir](FileSystem#method.read_dir),
+/// which don't touch the flash
+pub type PathError = FsError<Infallible>;
+
+impl PathError {
+    /// The same error for any flash, e.g. `fs.metadata(path).map_err(FsError::widen)?`
+    pub fn widen<E>(self) -> FsError<E> {
+        match self {
+            FsError::Flash(e) => match e {},
+            FsError::NotFormatted => FsError::NotFormatted,
+            FsError::NotFound => FsError::NotFound,
+            FsError::AlreadyExists => FsError::AlreadyExists,
+            FsError::NotADirectory => FsError::NotADirectory,
+            FsError::IsADirectory => FsError::IsADirectory,
+            FsError::DirectoryNotEmpty => FsError::DirectoryNotEmpty,
+            FsError::InvalidPath => FsError::InvalidPath,
+            FsError::NameTooLong => FsError::NameTooLong,
+            FsError::NoSpace => FsError::NoSpace,
+            FsError::TooManyEntries => FsError::TooManyEntries,
+            FsError::Busy => FsError::Busy,
+            FsError::SizeMismatch => FsError::SizeMismatch,
+            FsError::Corrupt => FsError::Corrupt,
+        }
+    }
+}
+
+/// Kind of an entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+}
+
+/// Size and location of a file or directory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: EntryKind,
+    /// Length in bytes, 0 for directories
+    pub len: u32,
+    /// Flash address of the data, add `flash::MEMORY_MAPPED_ADDRESS` to read it memory mapped
+    pub address: u32,
+}
+
+/// A file or directory returned by [FileSystem::read_dir]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DirEntry<'a> {
+    pub name: &'a str,
+    pub kind: EntryKind,
+    /// Length in bytes, 0 for directories
+    pub len: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Entry {
+    id: u16,
+    parent: u16,
+    kind: EntryKind,
+    name: [u8; NAME_MAX],
+    name_len: u8,
+    /// First data sector
+    start: u32,
+    sectors: u32,
+    length: u32,
+    crc: u32,
+}
+
+impl Entry {
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or("")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Record {
+    Put(Entry),
+    Remove(u16),
+}
+
+impl Record {
+    fn to_bytes(self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0; RECORD_SIZE];
+        bytes[0..2].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        match self {
+            Record::Put(entry) => {
+                bytes[2] = OP_PUT;
+                bytes[3] = match entry.kind {
+                    EntryKind::File => 0,
+                    EntryKind::Dir => 1,
+                };
+                bytes[4..6].copy_from_slice(&entry.id.to_le_bytes());
+                bytes[6..8].copy_from_slice(&entry.parent.to_le_bytes());
+                bytes[8..12].copy_from_slice(&entry.start.to_le_bytes());
+                bytes[12..16].copy_from_slice(&entry.sectors.to_le_bytes());
+                bytes[16..20].copy_from_slice(&entry.length.to_le_bytes());
+                bytes[20..24].copy_from_slice(&entry.crc.to_le_bytes());
+                bytes[24] = entry.name_len;
+                bytes[25..25 + NAME_MAX].copy_from_slice(&entry.name);
+            }
+            Record::Remove(id) => {
+                bytes[2] = OP_REMOVE;
+                bytes[4..6].copy_from_slice(&id.to_le_bytes());
+            }
+        }
+        let crc = crc32(&bytes[..RECORD_SIZE - 4]);
+        bytes[RECORD_SIZE - 4..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; RECORD_SIZE]) -> Option<Self> {
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        if u16_at(0) != RECORD_MAGIC || u32_at(RECORD_SIZE - 4) != crc32(&bytes[..RECORD_SIZE - 4])
+        {
+            return None;
+        }
+        match bytes[2] {
+            OP_PUT => {
+                let mut name = [0; NAME_MAX];
+                name.copy_from_slice(&bytes[25..25 + NAME_MAX]);
+                Some(Record::Put(Entry {
+                    id: u16_at(4),
+                    parent: u16_at(6),
+                    kind: if bytes[3] == 0 {
+                        EntryKind::File
+                    } else {
+                        EntryKind::Dir
+                    },
+                    name,
+                    name_len: bytes[24].min(NAME_MAX as u8),
+                    start: u32_at(8),
+                    sectors: u32_at(12),
+                    length: u32_at(16),
+                    crc: u32_at(20),
+                }))
+            }
+            OP_REMOVE => Some(Record::Remove(u16_at(4))),
+            _ => None,
+        }
+    }
+}
+
+fn area_header(generation: u32) -> [u8; RECORD_SIZE] {
+    let mut bytes = [0; RECORD_SIZE];
+    bytes[0..4].copy_from_slice(&AREA_MAGIC.to_le_bytes());
+    bytes[4..8].copy_from_slice(&generation.to_le_bytes());
+    let crc = crc32(&bytes[..RECORD_SIZE - 4]);
+    bytes[RECORD_SIZE - 4..].copy_from_slice(&crc.to_le_bytes());
+    bytes
+}
+
+/// Generation of a valid area header
+fn parse_area_header(bytes: &[u8; RECORD_SIZE]) -> Option<u32> {
+    let u32_at =
+        |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+    if u32_at(0) != AREA_MAGIC || u32_at(RECORD_SIZE - 4) != crc32(&bytes[..RECORD_SIZE - 4]) {
+        return None;
+    }
+    Some(u32_at(4))
+}
+
+/// File being written with [FileSystem::create], finish it with [FileSystem::commit].
+pub struct FileWriter {
+    entry: Entry,
+    /// Bytes written to flash, the rest is in the buffer
+    flushed: u32,
+    crc: Crc32,
+    buffer: [u8; BUFFER_SIZE],
+    buffered: usize,
+}
+
+impl FileWriter {
+    /// Bytes written so far
+    pub fn written(&self) -> u32 {
+        self.flushed + self.buffered as u32
+    }
+}
+
+/// Iterator over the entries of a directory
+pub struct ReadDir<'a> {
+    entries: core::slice::Iter<'a, Option<Entry>>,
+    parent: u16,
+}
+
+impl<'a> Iterator for ReadDir<'a> {
+    type Item = DirEntry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let parent = self.parent;
+        self.entries
+            .by_ref()
+            .flatten()
+            .find(|entry| entry.parent == parent)
+            .map(|entry| DirEntry {
+                name: entry.name(),
+                kind: entry.kind,
+                len: entry.length,
+            })
+    }
+}
+
+/// Files and directories in a range of flash, holding up to `N` entries.
+pub struct FileSystem<const N: usize> {
+    start: u32,
+    sector_size: u32,
+    record_slot: u32,
+    area_sectors: u32,
+    records_per_area: u32,
+    data_sectors: u32,
+    entries: [Option<Entry>; N],
+    active_area: u32,
+    generation: u32,
+    next_slot: u32,
+    /// Where the search for free sectors starts, spreads the wear over the chip
+    cursor: u32,
+    /// Sectors of the `FileWriter` in progress
+    pending: Option<(u32, u32)>,
+}
+
+impl<const N: usize> FileSystem<N> {
+    /// Erase the journal of a new, empty filesystem in `start..end` and mount it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `start` and `end` are not sector aligned or the range is too small for the
+    /// journal and at least one data sector.
+    pub fn format<F: NorFlash>(flash: &mut F, start: u32, end: u32) -> FsResult<Self, F> {
+        let fs = Self::new::<F>(start, end);
+        for area in 0..2 {
+            let address = fs.area_address(area);
+            flash
+                .erase(address, address + fs.area_sectors * fs.sector_size)
+                .map_err(FsError::Flash)?;
+        }
+        fs.write_slot(flash, 0, 0, &area_header(0))?;
+        Self::mount(flash, start, end)
+    }
+
+    /// Load the filesystem in `start..end`, returns `FsError::NotFormatted` if there is none.
+    ///
+    /// # Panics
+    ///
+    /// See [format](FileSystem#method.format).
+    pub fn mount<F: NorFlash>(flash: &mut F, start: u32, end: u32) -> FsResult<Self, F> {
+        let mut fs = Self::new::<F>(start, end);
+
+        let mut active = None;
+        for area in 0..2 {
+            if let Some(generation) = parse_area_header(&fs.read_slot(flash, area, 0)?) {
+                match active {
+                    Some((_, newest)) if (generation.wrapping_sub(newest) as i32) <= 0 => {}
+                    _ => active = Some((area, generation)),
+                }
+            }
+        }
+        let (area, generation) = active.ok_or(FsError::NotFormatted)?;
+        fs.active_area = area;
+        fs.generation = generation;
+
+        fs.next_slot = fs.records_per_area;
+        for slot in 1..fs.records_per_area {
+            let bytes = fs.read_slot(flash, area, slot)?;
+            if bytes == [0xFF; RECORD_SIZE] {
+                fs.next_slot = slot;
+                break;
+            }
+            // Records torn by a power loss are skipped
+            if let Some(record) = Record::from_bytes(&bytes) {
+                Self::apply(&mut fs.entries, record)
+                    .map_err(|_| FsError::<F::Error>::TooManyEntries)?;
+            }
+        }
+        Ok(fs)
+    }
+
+    fn new<F: NorFlash>(start: u32, end: u32) -> Self {
+        let sector_size = F::ERASE_SIZE as u32;
+        let record_slot = RECORD_SIZE.next_multiple_of(F::WRITE_SIZE) as u32;
+        // Room for a full table and as many changes after compacting
+        let area_sectors = ((2 * N as u32 + 2) * record_slot).div_ceil(sector_size);
+
+        assert_eq!(F::READ_SIZE, 1, "Flash must support reads of any size");
+        assert_eq!(BUFFER_SIZE % F::WRITE_SIZE, 0, "Unsupported write size");
+        assert!(N < u16::MAX as usize, "Too many entries");
+        assert_eq!(start % sector_size, 0, "Start must be sector aligned");
+        assert_eq!(end % sector_size, 0, "End must be sector aligned");
+        assert!(
+            end > start + 2 * area_sectors * sector_size,
+            "Range too small for the journal"
+        );
+
+        Self {
+            start,
+            sector_size,
+            record_slot,
+            area_sectors,
+            records_per_area: area_sectors * sector_size / record_slot,
+            data_sectors: (end - start) / sector_size - 2 * area_sectors,
+            entries: [None; N],
+            active_area: 0,
+            generation: 0,
+            next_slot: 1,
+            cursor: 0,
+            pending: None,
+        }
+    }
+
+    /// Size and location of a file or directory
+    pub fn metadata(&self, path: &str) -> Result<Metadata, PathError> {
+        let id = self.resolve(path)?;
+        if id == ROOT {
+            return Ok(Metadata {
+                kind: EntryKind::Dir,
+                len: 0,
+                address: 0,
+            });
+        }
+        let entry = self.entry(id).ok_or(FsError::NotFound)?;
+        Ok(Metadata {
+            kind: entry.kind,
+            len: entry.length,
+            address: self.data_address(entry.start),
+        })
+    }
+
+    /// True if the path exists
+    pub fn exists(&self, path: &str) -> bool {
+        self.resolve::<Infallible>(path).is_ok()
+    }
+
+    /// Entries of a directory, `/` is the root
+    pub fn read_dir(&self, path: &str) -> Result<ReadDir<'_>, PathError> {
+        let parent = self.resolve(path)?;
+        if parent != ROOT && self.entry(parent).map(|e| e.kind) != Some(EntryKind::Dir) {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(ReadDir {
+            entries: self.entries.iter(),
+            parent,
+        })
+    }
+
+    /// Free space in bytes, not all of it may be contiguous
+    pub fn free_space(&self) -> u32 {
+        let used: u32 = self.entries.iter().flatten().map(|e| e.sectors).sum();
+        let pending = self.pending.map_or(0, |(_, sectors)| sectors);
+        (self.data_sectors - used - pending) * self.sector_size
+    }
+
+    /// Read from a file starting at `offset`, returns the number of bytes read.
+    pub fn read<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        path: &str,
+        offset: u32,
+        data: &mut [u8],
+    ) -> FsResult<usize, F> {
+        let entry = self.file(path)?;
+        let len = (entry.length.saturating_sub(offset) as usize).min(data.len());
+        if len > 0 {
+            flash
+                .read(self.data_address(entry.start) + offset, &mut data[..len])
+                .map_err(FsError::Flash)?;
+        }
+        Ok(len)
+    }
+
+    /// Check a whole file against the CRC stored when it was written
+    pub fn verify<F: NorFlash>(&self, flash: &mut F, path: &str) -> FsResult<(), F> {
+        let entry = self.file(path)?;
+        let mut crc = Crc32::new();
+        let mut buffer = [0; BUFFER_SIZE];
+        let mut offset = 0;
+        while offset < entry.length {
+            let len = ((entry.length - offset) as usize).min(BUFFER_SIZE);
+            flash
+                .read(self.data_address(entry.start) + offset, &mut buffer[..len])
+                .map_err(FsError::Flash)?;
+            crc.update(&buffer[..len]);
+            offset += len as u32;
+        }
+        if crc.finish() != entry.crc {
+            return Err(FsError::Corrupt);
+        }
+        Ok(())
+    }
+
+    /// Create or replace a file with `data`
+    pub fn write_file<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        path: &str,
+        data: &[u8],
+    ) -> FsResult<(), F> {
+        let mut writer = self.create(flash, path, data.len() as u32)?;
+        if let Err(e) = self.write(flash, &mut writer, data) {
+            self.abort(writer);
+            return Err(e);
+        }
+        self.commit(flash, writer)
+    }
+
+    /// Start writing a file of `len` bytes, e.g. a sample that doesn't fit in RAM. The file is
+    /// created or replaced by [commit](FileSystem#method.commit).
+    ///
+    /// Remarks:
+    /// - The sectors for the file are erased here.
+    /// - Only one file can be written at a time, others return `FsError::Busy` until it is
+    ///   committed or [aborted](FileSystem#method.abort).
+    pub fn create<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        path: &str,
+        len: u32,
+    ) -> FsResult<FileWriter, F> {
+        if self.pending.is_some() {
+            return Err(FsError::Busy);
+        }
+        let (parent, name) = self.split_parent(path)?;
+        let mut entry = self.new_entry(parent, name, EntryKind::File);
+        match self.find(parent, name) {
+            Some(existing) if existing.kind == EntryKind::Dir => return Err(FsError::IsADirectory),
+            Some(existing) => entry.id = existing.id,
+            None => self.check_capacity()?,
+        }
+
+        entry.sectors = len.div_ceil(self.sector_size);
+        entry.length = len;
+        entry.start = self.allocate(entry.sectors).ok_or(FsError::NoSpace)?;
+        if entry.sectors > 0 {
+            let address = self.data_address(entry.start);
+            flash
+                .erase(address, address + entry.sectors * self.sector_size)
+                .map_err(FsError::Flash)?;
+        }
+        self.pending = Some((entry.start, entry.sectors));
+
+        Ok(FileWriter {
+            entry,
+            flushed: 0,
+            crc: Crc32::new(),
+            buffer: [0xFF; BUFFER_SIZE],
+            buffered: 0,
+        })
+    }
+
+    /// Append `data` to a file started with [create](FileSystem#method.create)
+    pub fn write<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        writer: &mut FileWriter,
+        data: &[u8],
+    ) -> FsResult<(), F> {
+        if writer.written() + data.len() as u32 > writer.entry.length {
+            return Err(FsError::SizeMismatch);
+        }
+        writer.crc.update(data);
+
+        let address = self.data_address(writer.entry.start);
+        let mut data = data;
+        while !data.is_empty() {
+            let len = (BUFFER_SIZE - writer.buffered).min(data.len());
+            writer.buffer[writer.buffered..writer.buffered + len].copy_from_slice(&data[..len]);
+            writer.buffered += len;
+            data = &data[len..];
+            if writer.buffered == BUFFER_SIZE {
+                flash
+                    .write(address + writer.flushed, &writer.buffer)
+                    .map_err(FsError::Flash)?;
+                writer.flushed += BUFFER_SIZE as u32;
+                writer.buffered = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the rest of the file and replace the old version, if any.
+    pub fn commit<F: NorFlash>(&mut self, flash: &mut F, writer: FileWriter) -> FsResult<(), F> {
+        if writer.written() != writer.entry.length {
+            self.abort(writer);
+            return Err(FsError::SizeMismatch);
+        }
+        let mut writer = writer;
+        if writer.buffered > 0 {
+            let padded = writer.buffered.next_multiple_of(F::WRITE_SIZE);
+            writer.buffer[writer.buffered..padded].fill(0xFF);
+            let result = flash.write(
+                self.data_address(writer.entry.start) + writer.flushed,
+                &writer.buffer[..padded],
+            );
+            if let Err(e) = result {
+                self.abort(writer);
+                return Err(FsError::Flash(e));
+            }
+        }
+        self.pending = None;
+
+        let mut entry = writer.entry;
+        entry.crc = writer.crc.finish();
+        // The file may have been replaced or its directory removed in the meantime
+        if !self.is_dir(entry.parent) {
+            return Err(FsError::NotFound);
+        }
+        match self.find(entry.parent, entry.name()) {
+            Some(existing) if existing.kind == EntryKind::Dir => return Err(FsError::IsADirectory),
+            Some(existing) => entry.id = existing.id,
+            None if self.entry(entry.id).is_some() => entry.id = self.new_id(),
+            None => {}
+        }
+        self.cursor = entry.start + entry.sectors;
+        self.commit_record(flash, Record::Put(entry))
+    }
+
+    /// Give up on a file started with [create](FileSystem#method.create), the old version stays.
+    pub fn abort(&mut self, writer: FileWriter) {
+        if self.pending == Some((writer.entry.start, writer.entry.sectors)) {
+            self.pending = None;
+        }
+    }
+
+    /// Create a directory, its parent must exist
+    pub fn create_dir<F: NorFlash>(&mut self, flash: &mut F, path: &str) -> FsResult<(), F> {
+        let (parent, name) = self.split_parent(path)?;
+        if self.find(parent, name).is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+        self.check_capacity()?;
+        let entry = self.new_entry(parent, name, EntryKind::Dir);
+        self.commit_record(flash, Record::Put(entry))
+    }
+
+    /// Remove a file or an empty directory
+    pub fn remove<F: NorFlash>(&mut self, flash: &mut F, path: &str) -> FsResult<(), F> {
+        let id = self.resolve(path)?;
+        if id == ROOT {
+            return Err(FsError::InvalidPath);
+        }
+        if self.entries.iter().flatten().any(|e| e.parent == id) {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+        self.commit_record(flash, Record::Remove(id))
+    }
+
+    /// Rename or move a file or directory, `to` must not exist
+    pub fn rename<F: NorFlash>(&mut self, flash: &mut F, from: &str, to: &str) -> FsResult<(), F> {
+        let id = self.resolve(from)?;
+        if id == ROOT {
+            return Err(FsError::InvalidPath);
+        }
+        let (parent, name) = self.split_parent(to)?;
+        if self.find(parent, name).is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+        // A directory can't be moved into itself
+        let mut ancestor = parent;
+        while ancestor != ROOT {
+            if ancestor == id {
+                return Err(FsError::InvalidPath);
+            }
+            ancestor = self.entry(ancestor).map_or(ROOT, |e| e.parent);
+        }
+
+        let mut entry = self.entry(id).ok_or(FsError::NotFound)?;
+        entry.parent = parent;
+        entry.name = [0; NAME_MAX];
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+        entry.name_len = name.len() as u8;
+        self.commit_record(flash, Record::Put(entry))
+    }
+
+    /// Write a record to the journal and apply it, compacting the journal when it is full
+    fn commit_record<F: NorFlash>(&mut self, flash: &mut F, record: Record) -> FsResult<(), F> {
+        let mut entries = self.entries;
+        Self::apply(&mut entries, record).map_err(|_| FsError::TooManyEntries)?;
+
+        if self.next_slot < self.records_per_area {
+            self.write_slot(flash, self.active_area, self.next_slot, &record.to_bytes())?;
+            self.next_slot += 1;
+        } else {
+            self.compact(flash, &entries)?;
+        }
+        self.entries = entries;
+        Ok(())
+    }
+
+    /// Write `entries` to the other journal area and switch to it
+    fn compact<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        entries: &[Option<Entry>; N],
+    ) -> FsResult<(), F> {
+        let area = 1 - self.active_area;
+        let address = self.area_address(area);
+        flash
+            .erase(address, address + self.area_sectors * self.sector_size)
+            .map_err(FsError::Flash)?;
+
+        let mut slot = 1;
+        for entry in entries.iter().flatten() {
+            self.write_slot(flash, area, slot, &Record::Put(*entry).to_bytes())?;
+            slot += 1;
+        }
+        // The new area only becomes valid with its header
+        let generation = self.generation.wrapping_add(1);
+        self.write_slot(flash, area, 0, &area_header(generation))?;
+
+        self.active_area = area;
+        self.generation = generation;
+        self.next_slot = slot;
+        Ok(())
+    }
+
+    fn apply(entries: &mut [Option<Entry>; N], record: Record) -> Result<(), ()> {
+        match record {
+            Record::Put(entry) => {
+                let slot = match entries
+                    .iter()
+                    .position(|e| e.map(|e| e.id) == Some(entry.id))
+                {
+                    Some(slot) => slot,
+                    None => entries.iter().position(|e| e.is_none()).ok_or(())?,
+                };
+                entries[slot] = Some(entry);
+            }
+            Record::Remove(id) => {
+                for e in entries.iter_mut() {
+                    if e.map(|e| e.id) == Some(id) {
+                        *e = None;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn area_address(&self, area: u32) -> u32 {
+        self.start + area * self.area_sectors * self.sector_size
+    }
+
+    fn data_address(&self, sector: u32) -> u32 {
+        self.area_address(2) + sector * self.sector_size
+    }
+
+    fn read_slot<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        area: u32,
+        slot: u32,
+    ) -> FsResult<[u8; RECORD_SIZE], F> {
+        let mut bytes = [0; RECORD_SIZE];
+        flash
+            .read(
+                self.area_address(area) + slot * self.record_slot,
+                &mut bytes,
+            )
+            .map_err(FsError::Flash)?;
+        Ok(bytes)
+    }
+
+    fn write_slot<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        area: u32,
+        slot: u32,
+        bytes: &[u8; RECORD_SIZE],
+    ) -> FsResult<(), F> {
+        let mut buffer = [0xFF; BUFFER_SIZE];
+        buffer[..RECORD_SIZE].copy_from_slice(bytes);
+        let len = RECORD_SIZE.next_multiple_of(F::WRITE_SIZE);
+        flash
+            .write(
+                self.area_address(area) + slot * self.record_slot,
+                &buffer[..len],
+            )
+            .map_err(FsError::Flash)
+    }
+
+    /// First run of `sectors` free data sectors at or after the cursor, wrapping around once
+    fn allocate(&self, sectors: u32) -> Option<u32> {
+        if sectors == 0 {
+            return Some(0);
+        }
+        let used = |sector: u32| {
+            let in_run = |start: u32, len: u32| (start..start + len).contains(&sector);
+            self.entries
+                .iter()
+                .flatten()
+                .any(|e| in_run(e.start, e.sectors))
+                || self.pending.is_some_and(|(start, len)| in_run(start, len))
+        };
+
+        let cursor = if self.cursor + sectors > self.data_sectors {
+            0
+        } else {
+            self.cursor
+        };
+        let mut start = cursor;
+        let mut wrapped = false;
+        loop {
+            if start + sectors > self.data_sectors {
+                if wrapped {
+                    return None;
+                }
+                wrapped = true;
+                start = 0;
+            }
+            if wrapped && start >= cursor {
+                return None;
+            }
+            match (start..start + sectors).rev().find(|&s| used(s)) {
+                Some(s) => start = s + 1,
+                None => return Some(start),
+            }
+        }
+    }
+
+    fn entry(&self, id: u16) -> Option<Entry> {
+        self.entries.iter().flatten().find(|e| e.id == id).copied()
+    }
+
+    fn find(&self, parent: u16, name: &str) -> Option<Entry> {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|e| e.parent == parent && e.name() == name)
+            .copied()
+    }
+
+    fn is_dir(&self, id: u16) -> bool {
+        id == ROOT || self.entry(id).map(|e| e.kind) == Some(EntryKind::Dir)
+    }
+
+    fn file<E>(&self, path: &str) -> Result<Entry, FsError<E>> {
+        let id = self.resolve(path)?;
+        match self.entry(id) {
+            Some(entry) if entry.kind == EntryKind::File => Ok(entry),
+            Some(_) => Err(FsError::IsADirectory),
+            None if id == ROOT => Err(FsError::IsADirectory),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn new_id(&self) -> u16 {
+        (1..=u16::MAX)
+            .find(|&id| self.entry(id).is_none())
+            .unwrap_or(u16::MAX)
+    }
+
+    fn check_capacity<E>(&self) -> Result<(), FsError<E>> {
+        if self.entries.iter().all(Option::is_some) {
+            return Err(FsError::TooManyEntries);
+        }
+        Ok(())
+    }
+
+    fn new_entry(&self, parent: u16, name: &str, kind: EntryKind) -> Entry {
+        let mut entry = Entry {
+            id: self.new_id(),
+            parent,
+            kind,
+            name: [0; NAME_MAX],
+            name_len: name.len() as u8,
+            start: 0,
+            sectors: 0,
+            length: 0,
+            crc: crc32(&[]),
+        };
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+        entry
+    }
+
+    /// Id of the entry at `path`, `ROOT` for `/`
+    fn resolve<E>(&self, path: &str) -> Result<u16, FsError<E>> {
+        let mut id = ROOT;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if !self.is_dir(id) {
+                return Err(FsError::NotADirectory);
+            }
+            id = self.find(id, name).ok_or(FsError::NotFound)?.id;
+        }
+        Ok(id)
+    }
+
+    /// Parent directory and name of a new entry
+    fn split_parent<'a, E>(&self, path: &'a str) -> Result<(u16, &'a str), FsError<E>> {
+        let path = path.trim_end_matches('/');
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(FsError::InvalidPath);
+        }
+        if name.len() > NAME_MAX {
+            return Err(FsError::NameTooLong);
+        }
+        let parent = self.resolve(dir)?;
+        if !self.is_dir(parent) {
+            return Err(FsError::NotADirectory);
+        }
+        Ok((parent, name))
+    }
+}
+
+#[cfg(all(test, feature = "mock-flash"))]
+mod tests {
+    use super::*;
+    use crate::flash::{FlashError, FlashInfo, KNOWN_CHIPS};
+    use crate::mock_flash::MockFlash;
+    use std::{vec, vec::Vec};
+
+    // A small chip keeps copying the image for every power loss cheap
+    const INFO: FlashInfo = FlashInfo {
+        size: 0x2_0000,
+        ..KNOWN_CHIPS[0]
+    };
+    const END: u32 = INFO.size;
+
+    type Fs = FileSystem<4>;
+
+    fn data(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+            .collect()
+    }
+
+    /// Contents of a file, checked against its CRC, or `None` if it doesn't exist
+    fn contents(fs: &Fs, flash: &mut MockFlash, path: &str) -> Option<Vec<u8>> {
+        let len = match fs.metadata(path) {
+            Ok(metadata) => metadata.len as usize,
+            Err(FsError::NotFound) => return None,
+            Err(e) => panic!("{:?}", e),
+        };
+        fs.verify(flash, path).unwrap();
+        let mut buffer = vec![0; len];
+        assert_eq!(fs.read(flash, path, 0, &mut buffer).unwrap(), len);
+        Some(buffer)
+    }
+
+    /// Run `op` on a copy of `image` with a power loss after 0, 1, 2... bytes until it completes.
+    /// After every power loss the filesystem must mount, pass `check` and still take new files.
+    fn power_loss_at_every_step(
+        image: &[u8],
+        op: impl Fn(&mut Fs, &mut MockFlash) -> Result<(), FsError<FlashError>>,
+        check: impl Fn(&Fs, &mut MockFlash),
+    ) {
+        for budget in 0.. {
+            let mut flash = MockFlash::from_vec(INFO, image.to_vec());
+            let mut fs = Fs::mount(&mut flash, 0, END).unwrap();
+            flash.power_loss_after(budget);
+            let result = op(&mut fs, &mut flash);
+            flash.power_cycle();
+
+            let mut fs = Fs::mount(&mut flash, 0, END).unwrap();
+            check(&fs, &mut flash);
+            fs.write_file(&mut flash, "/other", &data(100, 7)).unwrap();
+            let fs = Fs::mount(&mut flash, 0, END).unwrap();
+            check(&fs, &mut flash);
+            assert_eq!(contents(&fs, &mut flash, "/other"), Some(data(100, 7)));
+
+            match result {
+                Ok(()) => break,
+                Err(FsError::Flash(FlashError::PowerLoss)) => {}
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn journal_is_replayed_on_mount() {
+        let mut flash = MockFlash::new(INFO);
+        let mut fs = Fs::format(&mut flash, 0, END).unwrap();
+        fs.create_dir(&mut flash, "/presets").unwrap();
+        fs.write_file(&mut flash, "/presets/a", &data(5000, 1))
+            .unwrap();
+        fs.write_file(&mut flash, "/presets/b", &data(10, 2))
+            .unwrap();
+        fs.rename(&mut flash, "/presets/b", "/c").unwrap();
+        fs.write_file(&mut flash, "/presets/a", &data(300, 3))
+            .unwrap();
+
+        let fs = Fs::mount(&mut flash, 0, END).unwrap();
+        assert_eq!(contents(&fs, &mut flash, "/presets/a"), Some(data(300, 3)));
+        assert_eq!(contents(&fs, &mut flash, "/c"), Some(data(10, 2)));
+        assert!(!fs.exists("/presets/b"));
+        assert_eq!(fs.read_dir("/").unwrap().count(), 2);
+    }
+
+    #[test]
+    fn power_loss_while_replacing_a_file() {
+        let mut flash = MockFlash::new(INFO);
+        let mut fs = Fs::format(&mut flash, 0, END).unwrap();
+        fs.write_file(&mut flash, "/a", &data(5000, 1)).unwrap();
+
+        power_loss_at_every_step(
+            flash.as_slice(),
+            |fs, flash| fs.write_file(flash, "/a", &data(3000, 2)),
+            |fs, flash| {
+                let a = contents(fs, flash, "/a").unwrap();
+                assert!(a == data(5000, 1) || a == data(3000, 2));
+            },
+        );
+    }
+
+    #[test]
+    fn power_loss_while_renaming_and_removing() {
+        let mut flash = MockFlash::new(INFO);
+        let mut fs = Fs::format(&mut flash, 0, END).unwrap();
+        fs.create_dir(&mut flash, "/dir").unwrap();
+        fs.write_file(&mut flash, "/a", &data(500, 1)).unwrap();
+
+        power_loss_at_every_step(
+            flash.as_slice(),
+            |fs, flash| fs.rename(flash, "/a", "/dir/b"),
+            |fs, flash| {
+                let a = contents(fs, flash, "/a");
+                let b = contents(fs, flash, "/dir/b");
+                assert!(a.is_some() != b.is_some());
+                assert_eq!(a.or(b), Some(data(500, 1)));
+            },
+        );
+        power_loss_at_every_step(
+            flash.as_slice(),
+            |fs, flash| fs.remove(flash, "/a"),
+            |fs, flash| {
+                let a = contents(fs, flash, "/a");
+                assert!(a.is_none() || a == Some(data(500, 1)));
+            },
+        );
+    }
+
+    #[test]
+    fn power_loss_while_compacting() {
+        let mut flash = MockFlash::new(INFO);
+        let mut fs = Fs::format(&mut flash, 0, END).unwrap();
+        fs.write_file(&mut flash, "/a", &data(500, 1)).unwrap();
+        fs.write_file(&mut flash, "/b", &data(5000, 2)).unwrap();
+        // Fill the journal, the next change is written by compacting it
+        let mut names = ["/b", "/c"];
+        while fs.next_slot < fs.records_per_area {
+            fs.rename(&mut flash, names[0], names[1]).unwrap();
+            names.swap(0, 1);
+        }
+        let generation = fs.generation;
+        let b = names[0];
+
+        power_loss_at_every_step(
+            flash.as_slice(),
+            |fs, flash| {
+                fs.write_file(flash, "/a", &data(700, 3))?;
+                assert_eq!(fs.generation, generation.wrapping_add(1));
+                Ok(())
+            },
+            |fs, flash| {
+                let a = contents(fs, flash, "/a").unwrap();
+                assert!(a == data(500, 1) || a == data(700, 3));
+                assert_eq!(contents(fs, flash, b), Some(data(5000, 2)));
+            },
+        );
+    }
+}
@@ -0,0 +1,559 @@
+//! A/B firmware slots in flash for safe updates in the field.
+//!
+//! A new image is written to the slot that is not active, checked against its length and CRC
+//! and marked pending. On the next boot [boot_slot](FirmwareSlots#method.boot_slot) picks the
+//! pending image for a limited number of attempts. Once the new firmware runs it confirms
+//! itself, otherwise the next boot rolls back to the previous image. The boot state is kept in a
+//! [PersistentStorage], so it survives power loss.
+//!
+//! The images run from the QSPI flash, which stays memory mapped, so they can't write the boot
+//! state themselves. The flow is:
+//! 1. A bootloader running from internal flash writes updates and boots a slot with [boot].
+//! 2. The new image calls [request_confirm], which only sets a flag in backup SRAM.
+//! 3. On the next reset the bootloader gets the flag with [take_confirm_request] and commits it
+//!    with [confirm](FirmwareSlots#method.confirm) before calling `boot_slot`.
+//!
+//! The CRC is CRC-32 (IEEE 802.3), the same as zlib's `crc32`.
+//!
+//! # Example
+//!
+//! ```rust
+//! const LAYOUT: SlotLayout = SlotLayout {
+//!     slot_a: 0x04_0000,
+//!     slot_b: 0x24_0000,
+//!     slot_size: 0x20_0000,
+//!     state: 0x44_0000,
+//!     state_size: 0x2000,
+//! };
+//!
+//! // Bootloader
+//! let mut slots = FirmwareSlots::new(&mut flash, LAYOUT, 3).unwrap();
+//! if firmware::take_confirm_request() {
+//!     slots.confirm(&mut flash).unwrap();
+//! }
+//! if update_requested() {
+//!     let mut update = slots.begin_update(&mut flash, image_len).unwrap();
+//!     while let Some(chunk) = receive() {
+//!         slots.write(&mut flash, &mut update, chunk).unwrap();
+//!     }
+//!     slots.finish(&mut flash, update, image_crc).unwrap();
+//! }
+//! let slot = slots.boot_slot(&mut flash).unwrap();
+//! let flash = flash.into_memory_mapped().ok().unwrap();
+//! firmware::boot(flash, LAYOUT.address(slot));
+//!
+//! // New firmware, once it runs fine
+//! firmware::request_confirm();
+//! ```
+
+use embedded_storage::nor_flash::{ErrorType, NorFlash};
+
+use crate::crc::Crc32;
+use crate::flash::{MemoryMappedFlash, MEMORY_MAPPED_ADDRESS};
+use crate::persistent_storage::{PersistentStorage, StorageData};
+
+// Bumped when BootState changes
+const STATE_VERSION: u32 = 1;
+// Images are written through a buffer of this size, it must be a multiple of the write size
+const BUFFER_SIZE: usize = 256;
+const NO_SLOT: u32 = 0xFFFF_FFFF;
+// Backup SRAM word for the confirm request, after the Daisy bootloader's boot info
+const CONFIRM_OFFSET: u32 = 0x10;
+// "CONF"
+const CONFIRM_MAGIC: u32 = 0x434F_4E46;
+
+/// Firmware update errors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateError<E> {
+    /// Error from the flash
+    Flash(E),
+    /// The image does not fit in a slot
+    TooLarge,
+    /// More or less data written than given to `begin_update`
+    LengthMismatch,
+    /// The image in flash does not match the expected CRC
+    CrcMismatch,
+    /// The pending image was booted but is not confirmed yet, it may be the one running
+    Unconfirmed,
+}
+
+type UpdateResult<T, F> = Result<T, UpdateError<<F as ErrorType>::Error>>;
+
+/// One of the two firmware slots
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    /// The other slot
+    pub fn other(self) -> Self {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Slot::A => 0,
+            Slot::B => 1,
+        }
+    }
+
+    fn from_index(index: u32) -> Option<Self> {
+        match index {
+            0 => Some(Slot::A),
+            1 => Some(Slot::B),
+            _ => None,
+        }
+    }
+}
+
+/// Where the slots and the boot state are in flash, all sector aligned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SlotLayout {
+    pub slot_a: u32,
+    pub slot_b: u32,
+    pub slot_size: u32,
+    /// Start and size of the [PersistentStorage] for the boot state, at least two sectors
+    pub state: u32,
+    pub state_size: u32,
+}
+
+impl SlotLayout {
+    /// Flash address of a slot
+    pub fn address(&self, slot: Slot) -> u32 {
+        match slot {
+            Slot::A => self.slot_a,
+            Slot::B => self.slot_b,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+struct BootState {
+    active: u32,
+    pending: u32,
+    /// Boots of the pending image so far
+    attempts: u32,
+    /// Length and CRC of the image in each slot, a length of 0 is not checked
+    len: [u32; 2],
+    crc: [u32; 2],
+}
+
+unsafe impl StorageData for BootState {}
+
+/// Image being written with [FirmwareSlots::begin_update], finish it with
+/// [FirmwareSlots::finish].
+pub struct ImageWriter {
+    slot: Slot,
+    len: u32,
+    /// Bytes written to flash, the rest is in the buffer
+    flushed: u32,
+    buffer: [u8; BUFFER_SIZE],
+    buffered: usize,
+}
+
+impl ImageWriter {
+    /// Bytes written so far
+    pub fn written(&self) -> u32 {
+        self.flushed + self.buffered as u32
+    }
+}
+
+/// Two firmware slots and the state of which one to boot
+pub struct FirmwareSlots {
+    layout: SlotLayout,
+    max_attempts: u32,
+    state: PersistentStorage<BootState>,
+}
+
+impl FirmwareSlots {
+    /// Load the boot state. Without one, slot A is active and nothing is pending.
+    ///
+    /// A pending image is booted up to `max_attempts` times without being confirmed before it
+    /// is rolled back.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the layout is not sector aligned, see also [PersistentStorage::new].
+    pub fn new<F: NorFlash>(
+        flash: &mut F,
+        layout: SlotLayout,
+        max_attempts: u32,
+    ) -> Result<Self, F::Error> {
+        let erase_size = F::ERASE_SIZE as u32;
+        assert_eq!(BUFFER_SIZE % F::WRITE_SIZE, 0, "Unsupported write size");
+        assert_eq!(
+            (layout.slot_a | layout.slot_b | layout.slot_size) % erase_size,
+            0,
+            "Slots must be sector aligned"
+        );
+
+        let defaults = BootState {
+            active: 0,
+            pending: NO_SLOT,
+            attempts: 0,
+            len: [0; 2],
+            crc: [0; 2],
+        };
+        let state = PersistentStorage::new(
+            flash,
+            layout.state,
+            layout.state_size,
+            STATE_VERSION,
+            defaults,
+        )?;
+        Ok(Self {
+            layout,
+            max_attempts,
+            state,
+        })
+    }
+
+    /// The slot layout
+    pub fn layout(&self) -> &SlotLayout {
+        &self.layout
+    }
+
+    /// The confirmed slot, which is booted when nothing is pending
+    pub fn active(&self) -> Slot {
+        Slot::from_index(self.state.settings().active).unwrap_or(Slot::A)
+    }
+
+    /// The slot with a new image that has not been confirmed yet
+    pub fn pending(&self) -> Option<Slot> {
+        Slot::from_index(self.state.settings().pending)
+    }
+
+    /// The slot that was booted last: the pending one once
+    /// [boot_slot](FirmwareSlots#method.boot_slot) picked it, otherwise the active one
+    pub fn booted(&self) -> Slot {
+        let state = self.state.settings();
+        match self.pending() {
+            Some(slot) if state.attempts > 0 => slot,
+            _ => self.active(),
+        }
+    }
+
+    /// Erase the slot that was not booted and start writing an image of `len` bytes to it. A
+    /// pending image that hasn't been booted yet is dropped.
+    ///
+    /// Remarks:
+    /// - Returns `UpdateError::Unconfirmed` while a booted pending image is neither confirmed
+    ///   nor rolled back, as it may be the one running.
+    /// - The flash must be in indirect mode, code running from the QSPI flash can't do this.
+    pub fn begin_update<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        len: u32,
+    ) -> UpdateResult<ImageWriter, F> {
+        if len > self.layout.slot_size {
+            return Err(UpdateError::TooLarge);
+        }
+        if self.booted() != self.active() {
+            return Err(UpdateError::Unconfirmed);
+        }
+        let slot = self.booted().other();
+        if self.pending() == Some(slot) {
+            self.state.settings_mut().pending = NO_SLOT;
+            self.state.save(flash).map_err(UpdateError::Flash)?;
+        }
+
+        let address = self.layout.address(slot);
+        let erase_len = len.next_multiple_of(F::ERASE_SIZE as u32);
+        flash
+            .erase(address, address + erase_len)
+            .map_err(UpdateError::Flash)?;
+
+        Ok(ImageWriter {
+            slot,
+            len,
+            flushed: 0,
+            buffer: [0xFF; BUFFER_SIZE],
+            buffered: 0,
+        })
+    }
+
+    /// Append `data` to the image
+    pub fn write<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        writer: &mut ImageWriter,
+        data: &[u8],
+    ) -> UpdateResult<(), F> {
+        if writer.written() + data.len() as u32 > writer.len {
+            return Err(UpdateError::LengthMismatch);
+        }
+        let address = self.layout.address(writer.slot);
+        let mut data = data;
+        while !data.is_empty() {
+            let len = (BUFFER_SIZE - writer.buffered).min(data.len());
+            writer.buffer[writer.buffered..writer.buffered + len].copy_from_slice(&data[..len]);
+            writer.buffered += len;
+            data = &data[len..];
+            if writer.buffered == BUFFER_SIZE {
+                flash
+                    .write(address + writer.flushed, &writer.buffer)
+                    .map_err(UpdateError::Flash)?;
+                writer.flushed += BUFFER_SIZE as u32;
+                writer.buffered = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the rest of the image, check it against `crc` as read back from flash and mark it
+    /// pending for the next boot.
+    pub fn finish<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        writer: ImageWriter,
+        crc: u32,
+    ) -> UpdateResult<(), F> {
+        let mut writer = writer;
+        if writer.written() != writer.len {
+            return Err(UpdateError::LengthMismatch);
+        }
+        let address = self.layout.address(writer.slot);
+        if writer.buffered > 0 {
+            let padded = writer.buffered.next_multiple_of(F::WRITE_SIZE);
+            writer.buffer[writer.buffered..padded].fill(0xFF);
+            flash
+                .write(address + writer.flushed, &writer.buffer[..padded])
+                .map_err(UpdateError::Flash)?;
+        }
+        if image_crc(flash, address, writer.len).map_err(UpdateError::Flash)? != crc {
+            return Err(UpdateError::CrcMismatch);
+        }
+
+        let index = writer.slot.index();
+        let state = self.state.settings_mut();
+        state.pending = index as u32;
+        state.attempts = 0;
+        state.len[index] = writer.len;
+        state.crc[index] = crc;
+        self.state.save(flash).map_err(UpdateError::Flash)
+    }
+
+    /// Pick the slot to boot, call this once early at startup.
+    ///
+    /// A pending image is chosen and its boot counted, unless it was already booted
+    /// `max_attempts` times without a [confirm](FirmwareSlots#method.confirm) or its CRC no
+    /// longer matches. Then the update is rolled back to the active slot.
+    pub fn boot_slot<F: NorFlash>(&mut self, flash: &mut F) -> UpdateResult<Slot, F> {
+        if let Some(slot) = self.pending() {
+            let state = *self.state.settings();
+            if state.attempts < self.max_attempts && self.check(flash, slot)? {
+                self.state.settings_mut().attempts += 1;
+                self.state.save(flash).map_err(UpdateError::Flash)?;
+                return Ok(slot);
+            }
+            self.rollback(flash)?;
+        }
+        Ok(self.active())
+    }
+
+    /// Make the pending image the active one. Does nothing if the pending image hasn't been
+    /// booted yet.
+    ///
+    /// This writes the boot state, so it can't be called from an image running from the QSPI
+    /// flash. Such an image calls [request_confirm] and the bootloader commits it here.
+    pub fn confirm<F: NorFlash>(&mut self, flash: &mut F) -> UpdateResult<(), F> {
+        let state = self.state.settings_mut();
+        if state.pending == NO_SLOT || state.attempts == 0 {
+            return Ok(());
+        }
+        state.active = state.pending;
+        state.pending = NO_SLOT;
+        state.attempts = 0;
+        self.state.save(flash).map_err(UpdateError::Flash)
+    }
+
+    /// Drop the pending image and stay on the active slot
+    pub fn rollback<F: NorFlash>(&mut self, flash: &mut F) -> UpdateResult<(), F> {
+        let state = self.state.settings_mut();
+        state.pending = NO_SLOT;
+        state.attempts = 0;
+        self.state.save(flash).map_err(UpdateError::Flash)
+    }
+
+    /// True if the image in `slot` matches the length and CRC it was written with
+    pub fn check<F: NorFlash>(&self, flash: &mut F, slot: Slot) -> UpdateResult<bool, F> {
+        let state = self.state.settings();
+        let len = state.len[slot.index()];
+        if len == 0 {
+            return Ok(true);
+        }
+        let crc = image_crc(flash, self.layout.address(slot), len).map_err(UpdateError::Flash)?;
+        Ok(crc == state.crc[slot.index()])
+    }
+}
+
+fn image_crc<F: NorFlash>(flash: &mut F, address: u32, len: u32) -> Result<u32, F::Error> {
+    let mut crc = Crc32::new();
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut offset = 0;
+    while offset < len {
+        let chunk = ((len - offset) as usize).min(BUFFER_SIZE);
+        flash.read(address + offset, &mut buffer[..chunk])?;
+        crc.update(&buffer[..chunk]);
+        offset += chunk as u32;
+    }
+    Ok(crc.finish())
+}
+
+/// Start the image at `address` in flash, which must be linked to run from
+/// [MEMORY_MAPPED_ADDRESS] + `address`.
+///
+/// Shuts down interrupts, caches and clocks like `System::enter_bootloader`. The flash stays
+/// memory mapped, the image must not initialize it again and confirms itself with
+/// [request_confirm].
+pub fn boot(_flash: MemoryMappedFlash, address: u32) -> ! {
+    crate::system::System::jump(MEMORY_MAPPED_ADDRESS + address)
+}
+
+/// Ask the bootloader to confirm the running image, for firmware that runs from the QSPI flash.
+///
+/// Only sets a flag in backup SRAM, the flash isn't touched. The bootloader commits it at the
+/// next reset, see [take_confirm_request].
+///
+/// Remarks:
+/// - The flag survives a reset but not a power loss. Until it is committed, a power cycle
+///   counts as another boot attempt, reset with `SCB::sys_reset` to commit it right away.
+pub fn request_confirm() {
+    crate::system::write_backup_sram(CONFIRM_OFFSET, CONFIRM_MAGIC);
+}
+
+/// True if the image booted last called [request_confirm], clears the request. Commit it with
+/// [confirm](FirmwareSlots#method.confirm) before [boot_slot](FirmwareSlots#method.boot_slot).
+pub fn take_confirm_request() -> bool {
+    let requested = crate::system::read_backup_sram(CONFIRM_OFFSET) == CONFIRM_MAGIC;
+    if requested {
+        crate::system::write_backup_sram(CONFIRM_OFFSET, 0);
+    }
+    requested
+}
+
+#[cfg(all(test, feature = "mock-flash"))]
+mod tests {
+    use super::*;
+    use crate::crc::crc32;
+    use crate::flash::KNOWN_CHIPS;
+    use crate::mock_flash::MockFlash;
+
+    const LAYOUT: SlotLayout = SlotLayout {
+        slot_a: 0x1_0000,
+        slot_b: 0x2_0000,
+        slot_size: 0x1_0000,
+        state: 0x3_0000,
+        state_size: 0x2000,
+    };
+
+    fn image(seed: u8) -> std::vec::Vec<u8> {
+        (0..1000u32).map(|i| (i as u8).wrapping_mul(seed)).collect()
+    }
+
+    fn update(slots: &mut FirmwareSlots, flash: &mut MockFlash, data: &[u8]) -> Slot {
+        let mut writer = slots.begin_update(flash, data.len() as u32).unwrap();
+        let slot = writer.slot;
+        for chunk in data.chunks(100) {
+            slots.write(flash, &mut writer, chunk).unwrap();
+        }
+        slots.finish(flash, writer, crc32(data)).unwrap();
+        slot
+    }
+
+    #[test]
+    fn confirm_makes_update_active() {
+        let mut flash = MockFlash::new(KNOWN_CHIPS[0]);
+        let mut slots = FirmwareSlots::new(&mut flash, LAYOUT, 3).unwrap();
+        assert_eq!(slots.active(), Slot::A);
+
+        assert_eq!(update(&mut slots, &mut flash, &image(3)), Slot::B);
+        assert_eq!(slots.pending(), Some(Slot::B));
+        // Not booted yet, nothing to confirm
+        slots.confirm(&mut flash).unwrap();
+        assert_eq!(slots.active(), Slot::A);
+
+        assert_eq!(slots.boot_slot(&mut flash).unwrap(), Slot::B);
+        assert_eq!(slots.booted(), Slot::B);
+
+        // After a reset the bootloader commits the request
+        let mut slots = FirmwareSlots::new(&mut flash, LAYOUT, 3).unwrap();
+        slots.confirm(&mut flash).unwrap();
+        assert_eq!(slots.active(), Slot::B);
+        assert_eq!(slots.pending(), None);
+        assert_eq!(slots.boot_slot(&mut flash).unwrap(), Slot::B);
+
+        // The next update goes to the slot that isn't running
+        assert_eq!(update(&mut slots, &mut flash, &image(5)), Slot::A);
+    }
+
+    #[test]
+    fn unconfirmed_update_rolls_back() {
+        let mut flash = MockFlash::new(KNOWN_CHIPS[0]);
+        let mut slots = FirmwareSlots::new(&mut flash, LAYOUT, 2).unwrap();
+        update(&mut slots, &mut flash, &image(3));
+
+        for _ in 0..2 {
+            let mut slots = FirmwareSlots::new(&mut flash, LAYOUT, 2).unwrap();
+            assert_eq!(slots.boot_slot(&mut flash).unwrap(), Slot::B);
+        }
+        let mut slots = FirmwareSlots::new(&mut flash, LAYOUT, 2).unwrap();
+        assert_eq!(slots.boot_slot(&mut flash).unwrap(), Slot::A);
+        assert_eq!(slots.pending(), None);
+        assert_eq!(slots.booted(), Slot::A);
+    }
+
+    #[test]
+    fn no_update_over_booted_pending_slot() {
+        let mut flash = MockFlash::new(KNOWN_CHIPS[0]);
+        let mut slots = FirmwareSlots::new(&mut flash, LAYOUT, 3).unwrap();
+        update(&mut slots, &mut flash, &image(3));
+        slots.boot_slot(&mut flash).unwrap();
+
+        let before = flash.as_slice().to_vec();
+        assert!(matches!(
+            slots.begin_update(&mut flash, 1000),
+            Err(UpdateError::Unconfirmed)
+        ));
+        assert_eq!(flash.as_slice(), &before[..]);
+
+        slots.rollback(&mut flash).unwrap();
+        assert_eq!(update(&mut slots, &mut flash, &image(7)), Slot::B);
+    }
+
+    #[test]
+    fn pending_update_not_booted_is_replaced() {
+        let mut flash = MockFlash::new(KNOWN_CHIPS[0]);
+        let mut slots = FirmwareSlots::new(&mut flash, LAYOUT, 3).unwrap();
+        update(&mut slots, &mut flash, &image(3));
+        assert_eq!(update(&mut slots, &mut flash, &image(7)), Slot::B);
+        assert!(slots.check(&mut flash, Slot::B).unwrap());
+    }
+
+    #[test]
+    fn bad_images_are_rejected() {
+        let mut flash = MockFlash::new(KNOWN_CHIPS[0]);
+        let mut slots = FirmwareSlots::new(&mut flash, LAYOUT, 3).unwrap();
+        let data = image(3);
+
+        let mut writer = slots.begin_update(&mut flash, data.len() as u32).unwrap();
+        slots.write(&mut flash, &mut writer, &data).unwrap();
+        assert_eq!(
+            slots.finish(&mut flash, writer, crc32(&data) ^ 1),
+            Err(UpdateError::CrcMismatch)
+        );
+        assert_eq!(slots.pending(), None);
+
+        // Damaged after it was written, rolled back on boot
+        update(&mut slots, &mut flash, &data);
+        flash.program_blocking(LAYOUT.slot_b + 1, &[0]).unwrap();
+        assert_eq!(slots.boot_slot(&mut flash).unwrap(), Slot::A);
+        assert_eq!(slots.pending(), None);
+    }
+}
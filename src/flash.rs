@@ -1,18 +1,250 @@
-//! IS25LP064A: 64Mbit/8Mbyte flash memory
+//! QSPI NOR flash, the Daisy Seed uses an IS25LP064A: 64Mbit/8Mbyte flash memory
 //!
 //! https://www.issi.com/WW/pdf/25LP032-64A-B.pdf
 //!
+//! Other chips are detected by their JEDEC ID, see [KNOWN_CHIPS].
+//!
+
+use core::{
+    convert::TryFrom,
+    future::Future,
+    ops::Range,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, MultiwriteNorFlash, NorFlash, NorFlashError,
+    NorFlashErrorKind, ReadNorFlash,
+};
 use stm32h7xx_hal::{
+    dma::mdma,
     gpio::{gpiof, gpiog, Analog, Speed},
-    nb::Error as nbError,
+    nb::{self, Error as nbError},
     prelude::*,
-    rcc,
+    rcc::{self, ResetEnable},
     xspi::{Config, QspiError, QspiMode, QspiWord},
 };
 
-pub type FlashResult<T> = Result<T, QspiError>;
-pub type NBFlashResult<T> = stm32h7xx_hal::nb::Result<T, QspiError>;
+pub type FlashResult<T> = Result<T, FlashError>;
+pub type NBFlashResult<T> = stm32h7xx_hal::nb::Result<T, FlashError>;
+
+/// Size of the smallest erasable sector in bytes
+pub const SECTOR_SIZE: u32 = 4 * 1024;
+/// Size of a program page in bytes
+pub const PAGE_SIZE: u32 = 256;
+/// Start of the flash in the memory-mapped address space (`QSPIFLASH` in `memory.x`)
+pub const MEMORY_MAPPED_ADDRESS: u32 = 0x9000_0000;
+
+// Largest transaction the QUADSPI FIFO can hold
+const FIFO_SIZE: usize = 32;
+
+// Fast read quad I/O, 0xEB, used for indirect and memory-mapped reads
+const READ_COMMAND: u8 = 0xEB;
+
+// Dummy cycles of the SFDP read (0x5A), fixed by JESD216
+const SFDP_DUMMY_CYCLES: u8 = 8;
+
+/// Number of one-time programmable information rows
+pub const OTP_ROWS: u8 = 4;
+/// Size of an information row in bytes
+pub const OTP_ROW_SIZE: u32 = 256;
+
+// Status register block protection bits, BP0-3 on ISSI, BP0-2 and TB on Winbond
+const BP_MASK: u8 = 0b0011_1100;
+// Status register write disable, SRP0 on Winbond
+const SRWD: u8 = 0b1000_0000;
+
+/// How the chip is switched to four lines
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuadEnable {
+    /// ISSI: enter QPI (0x35) directly, all phases use four lines. Status register 1 only
+    ///   holds protection bits.
+    Issi,
+    /// Winbond: set QE in status register 2 (0x31). The W25Q..JV have no QPI mode, commands stay
+    ///   on one line and only reads use four (fast read quad I/O, 1-4-4).
+    Winbond,
+}
+
+impl QuadEnable {
+    /// QUADSPI mode bits of the instruction phase of a read
+    fn instruction_mode(self) -> u8 {
+        match self {
+            QuadEnable::Issi => 0b11,
+            QuadEnable::Winbond => 0b01,
+        }
+    }
+}
+
+/// How the block protection bits (BP) in the status register map to protected ranges
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockProtection {
+    /// ISSI: BP0-3 protect one 64K block at the top of the chip, doubling with every step up
+    ///   to half of the chip, larger values protect everything.
+    Issi,
+    /// Winbond: BP0-2 protect 1/64 of the chip, doubling with every step up to half of the chip,
+    ///   7 protects everything. TB selects the bottom instead of the top.
+    Winbond,
+}
+
+/// Geometry and setup of a supported flash chip
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlashInfo {
+    pub name: &'static str,
+    /// Manufacturer, memory type and capacity as returned by 0x9F
+    pub jedec_id: [u8; 3],
+    /// Size in bytes
+    pub size: u32,
+    pub sector_size: u32,
+    pub block_32k_size: u32,
+    pub block_64k_size: u32,
+    pub page_size: u32,
+    pub quad_enable: QuadEnable,
+    pub block_protection: BlockProtection,
+    /// Value for the read parameter register (0xC0), which sets the dummy cycles. Only used in
+    /// QPI mode.
+    pub read_parameters: u8,
+    /// Dummy cycles between the mode byte and the data of a quad read
+    pub read_dummy_cycles: u8,
+}
+
+impl FlashInfo {
+    const fn new(
+        name: &'static str,
+        jedec_id: [u8; 3],
+        size: u32,
+        quad_enable: QuadEnable,
+        block_protection: BlockProtection,
+        read_parameters: u8,
+        read_dummy_cycles: u8,
+    ) -> Self {
+        Self {
+            name,
+            jedec_id,
+            size,
+            sector_size: SECTOR_SIZE,
+            block_32k_size: 32 * 1024,
+            block_64k_size: 64 * 1024,
+            page_size: PAGE_SIZE,
+            quad_enable,
+            block_protection,
+            read_parameters,
+            read_dummy_cycles,
+        }
+    }
+}
+
+// ISSI read parameters: no wrap, default strength, default burst, 8 dummy cycles (pg 19)
+const ISSI_READ_PARAMETERS: u8 = 0b1111_1000;
+// Winbond has no read parameters, 0xEB always takes 4 dummy clocks after the mode byte
+const WINBOND_READ_PARAMETERS: u8 = 0;
+
+/// Chips that `Flash::new` can set up
+pub const KNOWN_CHIPS: &[FlashInfo] = &[
+    FlashInfo::new(
+        "IS25LP064A",
+        [0x9D, 0x60, 0x17],
+        8 * 1024 * 1024,
+        QuadEnable::Issi,
+        BlockProtection::Issi,
+        ISSI_READ_PARAMETERS,
+        8,
+    ),
+    FlashInfo::new(
+        "IS25LP080D",
+        [0x9D, 0x60, 0x14],
+        1024 * 1024,
+        QuadEnable::Issi,
+        BlockProtection::Issi,
+        ISSI_READ_PARAMETERS,
+        8,
+    ),
+    FlashInfo::new(
+        "IS25LP128F",
+        [0x9D, 0x60, 0x18],
+        16 * 1024 * 1024,
+        QuadEnable::Issi,
+        BlockProtection::Issi,
+        ISSI_READ_PARAMETERS,
+        8,
+    ),
+    FlashInfo::new(
+        "W25Q64",
+        [0xEF, 0x40, 0x17],
+        8 * 1024 * 1024,
+        QuadEnable::Winbond,
+        BlockProtection::Winbond,
+        WINBOND_READ_PARAMETERS,
+        4,
+    ),
+    FlashInfo::new(
+        "W25Q128",
+        [0xEF, 0x40, 0x18],
+        16 * 1024 * 1024,
+        QuadEnable::Winbond,
+        BlockProtection::Winbond,
+        WINBOND_READ_PARAMETERS,
+        4,
+    ),
+];
+
+/// Flash errors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlashError {
+    /// Error from the QUADSPI peripheral
+    Bus(QspiError),
+    /// The detected chip does not support the command
+    Unsupported,
+    /// Another operation is still in progress, finish it before starting a new one
+    Busy,
+    /// Address range is outside the flash
+    OutOfRange,
+    /// Address or length is not a multiple of the read, write or erase size
+    Misaligned,
+    /// Called with different arguments than the operation in progress, or with none in progress
+    MismatchedOperation,
+    /// The operation is suspended, call `Flash::resume` to continue it
+    Suspended,
+    /// The chip is in deep power-down, call `Flash::wake` first
+    PoweredDown,
+    /// Simulated power loss, only returned by `MockFlash`
+    PowerLoss,
+    /// Data read back after programming differs, at the given address
+    VerifyFailed(u32),
+    /// The JEDEC ID does not match any of the [KNOWN_CHIPS]
+    UnknownChip([u8; 3]),
+    /// The address range is write protected, or the protection is locked
+    Protected,
+    /// The block protection bits can't protect exactly the requested range
+    InvalidProtection,
+    /// The MDMA reported a transfer error, e.g. the flash left memory-mapped mode
+    Dma,
+}
+
+impl From<QspiError> for FlashError {
+    fn from(e: QspiError) -> Self {
+        FlashError::Bus(e)
+    }
+}
+
+impl From<NorFlashErrorKind> for FlashError {
+    fn from(kind: NorFlashErrorKind) -> Self {
+        match kind {
+            NorFlashErrorKind::NotAligned => FlashError::Misaligned,
+            _ => FlashError::OutOfRange,
+        }
+    }
+}
+
+impl NorFlashError for FlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            FlashError::Misaligned => NorFlashErrorKind::NotAligned,
+            FlashError::OutOfRange => NorFlashErrorKind::OutOfBounds,
+            _ => NorFlashErrorKind::Other,
+        }
+    }
+}
 
 /// Flash erasure enum
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -30,14 +262,90 @@ pub enum FlashErase {
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum FlashState {
     Idle,
-    Programming { address: u32, chunk: u32 },
+    Programming { address: u32, len: u32, offset: u32 },
     Erasing(FlashErase),
+    ProgramSuspended { address: u32, len: u32, offset: u32 },
+    EraseSuspended(FlashErase),
+    PoweredDown,
+}
+
+impl FlashState {
+    /// Suspended state of the erase or program in progress
+    fn suspended(self) -> Option<Self> {
+        match self {
+            FlashState::Erasing(op) => Some(FlashState::EraseSuspended(op)),
+            FlashState::Programming {
+                address,
+                len,
+                offset,
+            } => Some(FlashState::ProgramSuspended {
+                address,
+                len,
+                offset,
+            }),
+            _ => None,
+        }
+    }
+
+    /// State of a suspended erase or program once it is resumed
+    fn resumed(self) -> Option<Self> {
+        match self {
+            FlashState::EraseSuspended(op) => Some(FlashState::Erasing(op)),
+            FlashState::ProgramSuspended {
+                address,
+                len,
+                offset,
+            } => Some(FlashState::Programming {
+                address,
+                len,
+                offset,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Poll the erase in progress, `complete` is true once the chip is done with it
+    fn poll_erase(&mut self, op: FlashErase, complete: bool) -> NBFlashResult<()> {
+        match *self {
+            FlashState::Erasing(e) if e != op => {
+                Err(nbError::Other(FlashError::MismatchedOperation))
+            }
+            FlashState::Erasing(_) if complete => {
+                *self = FlashState::Idle;
+                Ok(())
+            }
+            _ => Err(nbError::WouldBlock),
+        }
+    }
+}
+
+/// `FlashError::OutOfRange` unless `len` bytes from `address` fit in `size`, without overflowing
+pub(crate) fn check_bounds(address: u32, len: usize, size: u32) -> FlashResult<()> {
+    match u32::try_from(len)
+        .ok()
+        .and_then(|len| address.checked_add(len))
+    {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(FlashError::OutOfRange),
+    }
+}
+
+/// `FlashError::Protected` if `len` bytes from `address` overlap `protected`
+fn check_protected_range(protected: &Range<u32>, address: u32, len: u32) -> FlashResult<()> {
+    let end = address.checked_add(len).ok_or(FlashError::OutOfRange)?;
+    if len > 0 && address < protected.end && protected.start < end {
+        return Err(FlashError::Protected);
+    }
+    Ok(())
 }
 
 /// Flash memory peripheral
 pub struct Flash {
     qspi: stm32h7xx_hal::xspi::Qspi<stm32h7xx_hal::stm32::QUADSPI>,
     state: FlashState,
+    info: FlashInfo,
+    protected: Range<u32>,
+    protection_locked: bool,
 }
 
 /*
@@ -50,10 +358,16 @@ pub struct Flash {
  * 6: Quad enable, quad output function enable, (1 = enable)
  * 7: Status register write disable (1 == write protected [0 = default])
  *
+ * Winbond status register 1 differs in bits 5-7:
+ * 2-4: BP block protection
+ * 5: TB, protect from the bottom instead of the top
+ * 6: SEC, sector (4K) instead of block protection, always 0 here
+ * 7: SRP0, status register protect
+ *
  * 6.3 READ REGISTER
  *
  *
- * 8.11 SECTOR ERASE OPERATION (SER, D7h/20h)
+ * 8.11 SECTOR ERASE OPERATION (SER, D7h/20h, Winbond only supports 20h)
  *  * instruction, 3 byte address
  *  * WEL is reset after
  * 8.12 BLOCK ERASE OPERATION (BER32K:52h, BER64K:D8h)
@@ -75,10 +389,7 @@ impl Flash {
     }
 
     fn write_complete(&mut self) -> FlashResult<bool> {
-        match self.status() {
-            Ok(status) => Ok(status & 0x01 == 0),
-            Err(e) => return Err(e),
-        }
+        Ok(self.status()? & 0x01 == 0)
     }
 
     fn wait_write(&mut self) -> FlashResult<()> {
@@ -92,46 +403,45 @@ impl Flash {
     fn write_command(&mut self, cmd: u8) -> FlashResult<()> {
         self.wait();
         self.qspi
-            .write_extended(QspiWord::U8(cmd), QspiWord::None, QspiWord::None, &[])
+            .write_extended(QspiWord::U8(cmd), QspiWord::None, QspiWord::None, &[])?;
+        Ok(())
     }
 
     fn write_reg(&mut self, cmd: u8, data: u8) -> FlashResult<()> {
         self.wait();
         self.qspi
-            .write_extended(QspiWord::U8(cmd), QspiWord::None, QspiWord::None, &[data])
+            .write_extended(QspiWord::U8(cmd), QspiWord::None, QspiWord::None, &[data])?;
+        Ok(())
     }
 
     fn enable_write(&mut self) -> FlashResult<()> {
         self.write_command(0x06)
     }
 
-    fn assert_info(&mut self) {
-        let mut info: [u8; 3] = [0; 3];
+    fn read_jedec_id(&mut self) -> FlashResult<[u8; 3]> {
+        let mut id: [u8; 3] = [0; 3];
         self.wait();
-        self.qspi
-            .read_extended(
-                QspiWord::U8(0x9F),
-                QspiWord::None,
-                QspiWord::None,
-                0,
-                &mut info,
-            )
-            .unwrap();
-        assert_eq!(&info, &[157, 96, 23]);
+        self.qspi.read_extended(
+            QspiWord::U8(0x9F),
+            QspiWord::None,
+            QspiWord::None,
+            0,
+            &mut id,
+        )?;
+        Ok(id)
     }
 
     fn status(&mut self) -> FlashResult<u8> {
         let mut status: [u8; 1] = [0xFF];
         self.wait();
-        self.qspi
-            .read_extended(
-                QspiWord::U8(0x05),
-                QspiWord::None,
-                QspiWord::None,
-                0,
-                &mut status,
-            )
-            .map(|_| status[0])
+        self.qspi.read_extended(
+            QspiWord::U8(0x05),
+            QspiWord::None,
+            QspiWord::None,
+            0,
+            &mut status,
+        )?;
+        Ok(status[0])
     }
 
     /// Reset the internal state, only if you know what you're doing
@@ -140,6 +450,9 @@ impl Flash {
     }
 
     /// Initialize the flash quad spi interface
+    ///
+    /// The chip is detected by its JEDEC ID, `FlashError::UnknownChip` is returned when it is not
+    /// one of the [KNOWN_CHIPS].
     pub fn new(
         regs: stm32h7xx_hal::device::QUADSPI,
         prec: rcc::rec::Qspi,
@@ -150,7 +463,7 @@ impl Flash {
         pf9: gpiof::PF9<Analog>,
         pf10: gpiof::PF10<Analog>,
         pg6: gpiog::PG6<Analog>,
-    ) -> Self {
+    ) -> Result<Self, FlashError> {
         let _ncs = pg6.into_alternate::<10>().speed(Speed::VeryHigh); //QUADSPI_BK1_NCS
 
         let sck = pf10.into_alternate().speed(Speed::VeryHigh);
@@ -165,26 +478,70 @@ impl Flash {
         let mut flash = Flash {
             qspi,
             state: FlashState::Idle,
+            info: KNOWN_CHIPS[0],
+            protected: 0..0,
+            protection_locked: false,
         };
 
+        //the chip stays in QPI mode when only the MCU was reset, leave it (ISSI 0xF5, other
+        //chips 0xFF, which also ends a continuous read) and do a software reset so it starts
+        //out in SPI mode
+        flash.qspi.configure_mode(QspiMode::FourBit)?;
+        //release from deep power-down first, the chip ignores everything else while in it
+        flash.write_command(0xAB)?;
+        cortex_m::asm::delay(5 * crate::MILICYCLES / 1000);
+        flash.write_command(0xF5)?;
+        flash.write_command(0xFF)?;
+        flash.qspi.configure_mode(QspiMode::OneBit)?;
+        //again in SPI mode for chips without QPI, e.g. Winbond, which missed the one above
+        flash.write_command(0xAB)?;
+        cortex_m::asm::delay(5 * crate::MILICYCLES / 1000);
+        flash.write_command(0x66)?;
+        flash.write_command(0x99)?;
+        crate::delay_ms(1);
+
+        let id = flash.read_jedec_id()?;
+        flash.info = *KNOWN_CHIPS
+            .iter()
+            .find(|info| info.jedec_id == id)
+            .ok_or(FlashError::UnknownChip(id))?;
+
         //enable quad
-        flash.enable_write().unwrap();
-        flash.write_command(0x35).unwrap();
-        flash.qspi.configure_mode(QspiMode::FourBit).unwrap();
+        match flash.info.quad_enable {
+            QuadEnable::Issi => {
+                flash.enable_write()?;
+                flash.write_command(0x35)?;
+                flash.qspi.configure_mode(QspiMode::FourBit)?;
 
-        flash.enable_write().unwrap();
-        //only enable write, nothing else
-        flash.write_reg(0x01, 0b0000_0010).unwrap();
-        flash.wait_write().unwrap();
-        flash.assert_info();
+                //clear QE, keep the block protection and SRWD bits
+                let status = flash.status()? & (BP_MASK | SRWD);
+                flash.enable_write()?;
+                flash.write_reg(0x01, status)?;
+                flash.wait_write()?;
+
+                //setup read parameters
+                flash.enable_write()?;
+                flash.write_reg(0xC0, flash.info.read_parameters)?;
+                flash.wait_write()?;
+            }
+            QuadEnable::Winbond => {
+                //commands stay in SPI mode, QE only frees IO2 and IO3 for quad reads
+                flash.enable_write()?;
+                flash.write_reg(0x31, 0b0000_0010)?;
+                flash.wait_write()?;
+            }
+        }
 
-        //setup read parameters, no wrap, default strength, default burst, 8 dummy cycles
-        //pg 19
-        flash.enable_write().unwrap();
-        flash.write_reg(0xC0, 0b1111_1000).unwrap();
-        flash.wait_write().unwrap();
+        let status = flash.status()?;
+        flash.protected = flash.protected_range(status);
+        flash.protection_locked = status & SRWD != 0;
 
-        flash
+        Ok(flash)
+    }
+
+    /// Geometry of the detected chip
+    pub fn info(&self) -> &FlashInfo {
+        &self.info
     }
 
     /// Erase all or some of the chip.
@@ -196,109 +553,1098 @@ impl Flash {
     /// respectively).
     pub fn erase(&mut self, op: FlashErase) -> NBFlashResult<()> {
         match self.state {
-            FlashState::Erasing(e) => {
-                assert_eq!(e, op);
-                if self.write_complete()? {
-                    self.state = FlashState::Idle;
-                    Ok(())
-                } else {
-                    Err(nbError::WouldBlock)
-                }
+            FlashState::Erasing(_) => {
+                let complete = self.write_complete()?;
+                self.state.poll_erase(op, complete)
             }
+            FlashState::EraseSuspended(e) if e == op => Err(nbError::Other(FlashError::Suspended)),
             FlashState::Idle => {
+                let (address, size) = self.erase_range(op);
+                if address & (size - 1) != 0 {
+                    return Err(nbError::Other(FlashError::Misaligned));
+                }
+                if address >= self.info.size {
+                    return Err(nbError::Other(FlashError::OutOfRange));
+                }
+                self.check_protected(address, size)?;
+
                 self.enable_write()?;
                 self.wait();
-                match op {
-                    FlashErase::Chip => self.write_command(0x60),
-                    FlashErase::Sector4K(a) => self.qspi.write_extended(
-                        QspiWord::U8(0xD7),
-                        QspiWord::U24(a as _),
-                        QspiWord::None,
-                        &[],
-                    ),
-                    FlashErase::Block32K(a) => self.qspi.write_extended(
-                        QspiWord::U8(0x52),
-                        QspiWord::U24(a as _),
-                        QspiWord::None,
-                        &[],
-                    ),
-                    FlashErase::Block64K(a) => self.qspi.write_extended(
-                        QspiWord::U8(0xD8),
-                        QspiWord::U24(a as _),
-                        QspiWord::None,
-                        &[],
-                    ),
-                }?;
+                let cmd = match op {
+                    FlashErase::Chip => 0x60,
+                    FlashErase::Sector4K(_) => 0x20,
+                    FlashErase::Block32K(_) => 0x52,
+                    FlashErase::Block64K(_) => 0xD8,
+                };
+                if op == FlashErase::Chip {
+                    self.write_command(cmd)?;
+                } else {
+                    self.qspi
+                        .write_extended(
+                            QspiWord::U8(cmd),
+                            QspiWord::U24(address),
+                            QspiWord::None,
+                            &[],
+                        )
+                        .map_err(FlashError::from)?;
+                }
                 self.state = FlashState::Erasing(op);
                 Err(nbError::WouldBlock)
             }
-            _ => panic!("not idle or erasing"),
+            FlashState::PoweredDown => Err(nbError::Other(FlashError::PoweredDown)),
+            _ => Err(nbError::Other(FlashError::Busy)),
         }
     }
 
     /// Read `data` out of the flash starting at the given `address`
+    ///
+    /// While an erase or program is [suspended](Flash#method.suspend) everything but the
+    /// sectors being erased or the page being programmed can be read.
     pub fn read(&mut self, address: u32, data: &mut [u8]) -> FlashResult<()> {
-        assert_eq!(self.state, FlashState::Idle);
+        self.check_range(address, data.len())?;
+        let busy = match self.state {
+            FlashState::Idle => None,
+            FlashState::EraseSuspended(op) => Some(self.erase_range(op)),
+            FlashState::ProgramSuspended {
+                address, offset, ..
+            } => {
+                let page = (address + offset - 1) & !(PAGE_SIZE - 1);
+                Some((page, PAGE_SIZE))
+            }
+            FlashState::PoweredDown => return Err(FlashError::PoweredDown),
+            _ => return Err(FlashError::Busy),
+        };
+        if let Some((start, len)) = busy {
+            if address < start + len && start < address + data.len() as u32 {
+                return Err(FlashError::Busy);
+            }
+        }
         let mut addr = address;
         //see page 34 for allowing to skip instruction
-        assert!((addr as usize + data.len()) < 0x800000);
-        for chunk in data.chunks_mut(32) {
+        for chunk in data.chunks_mut(FIFO_SIZE) {
             self.wait();
-            self.qspi.read_extended(
-                QspiWord::U8(0xEB),
-                QspiWord::U24(addr),
-                QspiWord::U8(0x00), //only A in top byte does anything
-                8,
-                chunk,
-            )?;
-            addr += 32;
+            self.read_quad(addr, chunk);
+            addr += FIFO_SIZE as u32;
         }
         Ok(())
     }
 
+    /// Fast read quad I/O of up to `FIFO_SIZE` bytes. The HAL uses the same number of lines for
+    /// every phase, the Winbond chips need the instruction on one line.
+    fn read_quad(&mut self, address: u32, data: &mut [u8]) {
+        let info = self.info;
+        let regs = self.qspi.inner_mut();
+        regs.dlr
+            .write(|w| unsafe { w.dl().bits(data.len() as u32 - 1) });
+        //only A in top byte does anything, 0x00 also keeps Winbond chips out of continuous read
+        regs.abr.write(|w| unsafe { w.alternate().bits(0x00) });
+        regs.ccr.write(|w| unsafe {
+            w.fmode()
+                .bits(0b01) // indirect read
+                .instruction()
+                .bits(READ_COMMAND)
+                .imode()
+                .bits(info.quad_enable.instruction_mode())
+                .admode()
+                .bits(0b11)
+                .adsize()
+                .bits(0b10) // 24-bit address
+                .abmode()
+                .bits(0b11)
+                .absize()
+                .bits(0b00) // 8-bit alternate byte
+                .dcyc()
+                .bits(info.read_dummy_cycles)
+                .dmode()
+                .bits(0b11)
+        });
+        //starts the transfer
+        regs.ar.write(|w| unsafe { w.address().bits(address) });
+        while regs.sr.read().tcf().bit_is_clear() {}
+        for byte in data {
+            *byte = unsafe { core::ptr::read_volatile(&regs.dr as *const _ as *const u8) };
+        }
+        regs.fcr.write(|w| w.ctcf().set_bit());
+        self.wait();
+    }
+
     /// Program `data` into the flash starting at the given `address`
     ///
     /// Remarks:
     /// - This operation can only set 1s to 0s, you must use `erase` to set a 0 to a 1.
-    /// - The starting byte can be anywhere within the page (256 byte chunk). When the end of the
-    /// page is reached, the address will wrap around to the beginning of the same page. If the
-    /// data to be programmed are less than a full page, the data of all other bytes on the same
-    /// page will remain unchanged.
+    /// - `data` can be any length and start anywhere, it is split into transactions that never
+    ///   cross the end of a page (256 byte chunk), where the chip would wrap around to the
+    ///   beginning of the same page.
+    /// - Poll it with the same `address` and `data` until it returns `Ok`, a different address or
+    ///   length returns `FlashError::MismatchedOperation`, also while it is suspended.
     pub fn program(&mut self, address: u32, data: &[u8]) -> NBFlashResult<()> {
-        let prog = |flash: &mut Self, chunk_index: u32| -> NBFlashResult<()> {
-            if let Some(chunk) = data.chunks(32).nth(chunk_index as usize) {
-                flash.enable_write()?;
-                flash.wait();
-                flash.qspi.write_extended(
-                    QspiWord::U8(0x02),
-                    QspiWord::U24(address + chunk_index * 32),
-                    QspiWord::None,
-                    chunk,
-                )?;
-                flash.state = FlashState::Programming {
-                    address,
-                    chunk: chunk_index,
-                };
-                Err(nbError::WouldBlock)
-            } else {
+        let prog = |flash: &mut Self, offset: u32| -> NBFlashResult<()> {
+            let remaining = &data[offset as usize..];
+            if remaining.is_empty() {
                 flash.state = FlashState::Idle;
-                Ok(())
+                return Ok(());
             }
+            let addr = address + offset;
+            let page_remaining = (PAGE_SIZE - addr % PAGE_SIZE) as usize;
+            let len = remaining.len().min(page_remaining).min(FIFO_SIZE);
+            flash.enable_write()?;
+            flash.wait();
+            flash
+                .qspi
+                .write_extended(
+                    QspiWord::U8(0x02),
+                    QspiWord::U24(addr),
+                    QspiWord::None,
+                    &remaining[..len],
+                )
+                .map_err(FlashError::from)?;
+            flash.state = FlashState::Programming {
+                address,
+                len: data.len() as u32,
+                offset: offset + len as u32,
+            };
+            Err(nbError::WouldBlock)
         };
         match self.state {
-            FlashState::Idle => prog(self, 0),
+            FlashState::Idle => {
+                self.check_range(address, data.len())?;
+                self.check_protected(address, data.len() as u32)?;
+                prog(self, 0)
+            }
             FlashState::Programming {
                 address: addr,
-                chunk,
+                len,
+                offset,
             } => {
-                assert_eq!(addr, address);
+                if addr != address || len != data.len() as u32 {
+                    return Err(nbError::Other(FlashError::MismatchedOperation));
+                }
                 if self.write_complete()? {
-                    prog(self, chunk + 1)
+                    prog(self, offset)
                 } else {
                     Err(nbError::WouldBlock)
                 }
             }
-            _ => panic!("invalid state for programming"),
+            FlashState::ProgramSuspended {
+                address: addr, len, ..
+            } => {
+                if addr != address || len != data.len() as u32 {
+                    return Err(nbError::Other(FlashError::MismatchedOperation));
+                }
+                Err(nbError::Other(FlashError::Suspended))
+            }
+            FlashState::PoweredDown => Err(nbError::Other(FlashError::PoweredDown)),
+            _ => Err(nbError::Other(FlashError::Busy)),
+        }
+    }
+
+    /// Program `data` like [program](Flash#method.program), then read it back and compare.
+    ///
+    /// Returns `FlashError::VerifyFailed` with the first differing address, which usually means
+    /// the area was not erased first.
+    pub fn program_verified(&mut self, address: u32, data: &[u8]) -> NBFlashResult<()> {
+        self.program(address, data)?;
+
+        let mut buffer = [0; FIFO_SIZE];
+        let mut addr = address;
+        for chunk in data.chunks(FIFO_SIZE) {
+            let read_back = &mut buffer[..chunk.len()];
+            self.read(addr, read_back)?;
+            if let Some(i) = chunk.iter().zip(read_back.iter()).position(|(a, b)| a != b) {
+                return Err(nbError::Other(FlashError::VerifyFailed(addr + i as u32)));
+            }
+            addr += chunk.len() as u32;
+        }
+        Ok(())
+    }
+
+    /// Suspend the erase or program in progress, so the rest of the flash can be read.
+    ///
+    /// Remarks:
+    /// - Polling the suspended operation returns `FlashError::Suspended` until
+    ///   [resume](Flash#method.resume) is called, other erases and programs return
+    ///   `FlashError::Busy`.
+    /// - A chip erase can't be suspended and returns `FlashError::Unsupported`.
+    /// - An erase that already finished or a program between two pages is marked suspended
+    ///   without stopping the chip, after `resume` the next poll completes or continues it.
+    pub fn suspend(&mut self) -> FlashResult<()> {
+        if self.state == FlashState::Erasing(FlashErase::Chip) {
+            return Err(FlashError::Unsupported);
+        }
+        let Some(suspended) = self.state.suspended() else {
+            return Ok(());
+        };
+        if self.write_complete()? {
+            self.state = suspended;
+            return Ok(());
+        }
+        self.write_command(0x75)?;
+        //the chip is ready to read once WIP clears
+        self.wait_write()?;
+        self.state = suspended;
+        Ok(())
+    }
+
+    /// Resume a [suspended](Flash#method.suspend) erase or program, does nothing otherwise.
+    pub fn resume(&mut self) -> FlashResult<()> {
+        let Some(resumed) = self.state.resumed() else {
+            return Ok(());
+        };
+        //ignored by the chip if nothing was suspended, e.g. a finished erase
+        self.write_command(0x7A)?;
+        self.state = resumed;
+        Ok(())
+    }
+
+    /// True while an erase or program is suspended
+    pub fn is_suspended(&self) -> bool {
+        matches!(
+            self.state,
+            FlashState::EraseSuspended(_) | FlashState::ProgramSuspended { .. }
+        )
+    }
+
+    /// Put the chip into deep power-down (0xB9) to save power, it then ignores everything but
+    /// [wake](Flash#method.wake). Other operations return `FlashError::PoweredDown`.
+    pub fn power_down(&mut self) -> FlashResult<()> {
+        if self.state == FlashState::PoweredDown {
+            return Ok(());
+        }
+        self.check_idle()?;
+        self.write_command(0xB9)?;
+        self.state = FlashState::PoweredDown;
+        Ok(())
+    }
+
+    /// Release the chip from deep power-down (0xAB), does nothing if it isn't powered down.
+    pub fn wake(&mut self) -> FlashResult<()> {
+        if self.state != FlashState::PoweredDown {
+            return Ok(());
+        }
+        self.write_command(0xAB)?;
+        //tRES1 is 5us on ISSI and 3us on Winbond chips
+        cortex_m::asm::delay(5 * crate::MILICYCLES / 1000);
+        self.state = FlashState::Idle;
+        Ok(())
+    }
+
+    /// True after [power_down](Flash#method.power_down) until [wake](Flash#method.wake)
+    pub fn is_powered_down(&self) -> bool {
+        self.state == FlashState::PoweredDown
+    }
+
+    /// Erase and wait for it to finish, see [erase](Flash#method.erase).
+    pub fn erase_blocking(&mut self, op: FlashErase) -> FlashResult<()> {
+        nb::block!(self.erase(op))
+    }
+
+    /// Program and wait for it to finish, see [program](Flash#method.program).
+    pub fn program_blocking(&mut self, address: u32, data: &[u8]) -> FlashResult<()> {
+        nb::block!(self.program(address, data))
+    }
+
+    /// Erase, yielding to other tasks while the chip is busy, see [erase](Flash#method.erase).
+    pub async fn erase_async(&mut self, op: FlashErase) -> FlashResult<()> {
+        loop {
+            match self.erase(op) {
+                Ok(()) => return Ok(()),
+                Err(nbError::WouldBlock) => yield_now().await,
+                Err(nbError::Other(e)) => return Err(e),
+            }
+        }
+    }
+
+    /// Program, yielding to other tasks while the chip is busy, see
+    /// [program](Flash#method.program).
+    pub async fn program_async(&mut self, address: u32, data: &[u8]) -> FlashResult<()> {
+        loop {
+            match self.program(address, data) {
+                Ok(()) => return Ok(()),
+                Err(nbError::WouldBlock) => yield_now().await,
+                Err(nbError::Other(e)) => return Err(e),
+            }
+        }
+    }
+
+    /// Erase the sectors from `from` up to `to` and wait for it to finish.
+    ///
+    /// Both addresses must be sector aligned. The range is covered with as many 64K and 32K
+    /// block erases as possible, which are much faster than erasing each 4K sector.
+    pub fn erase_range_blocking(&mut self, from: u32, to: u32) -> FlashResult<()> {
+        self.check_erase_range(from, to)?;
+        let mut address = from;
+        while address < to {
+            let (op, size) = self.largest_erase(address, to);
+            self.erase_blocking(op)?;
+            address += size;
         }
+        Ok(())
+    }
+
+    /// Erase the sectors from `from` up to `to`, yielding to other tasks while the chip is busy.
+    /// See [erase_range_blocking](Flash#method.erase_range_blocking).
+    pub async fn erase_range_async(&mut self, from: u32, to: u32) -> FlashResult<()> {
+        self.check_erase_range(from, to)?;
+        let mut address = from;
+        while address < to {
+            let (op, size) = self.largest_erase(address, to);
+            self.erase_async(op).await?;
+            address += size;
+        }
+        Ok(())
+    }
+
+    fn check_erase_range(&self, from: u32, to: u32) -> FlashResult<()> {
+        if from > to || to > self.info.size {
+            return Err(FlashError::OutOfRange);
+        }
+        if (from | to) & (self.info.sector_size - 1) != 0 {
+            return Err(FlashError::Misaligned);
+        }
+        Ok(())
+    }
+
+    /// Start address and size of an erase
+    fn erase_range(&self, op: FlashErase) -> (u32, u32) {
+        match op {
+            FlashErase::Chip => (0, self.info.size),
+            FlashErase::Sector4K(a) => (a, self.info.sector_size),
+            FlashErase::Block32K(a) => (a, self.info.block_32k_size),
+            FlashErase::Block64K(a) => (a, self.info.block_64k_size),
+        }
+    }
+
+    /// Largest erase starting at `address` that does not go past `end`, and its size
+    fn largest_erase(&self, address: u32, end: u32) -> (FlashErase, u32) {
+        let fits = |size: u32| address & (size - 1) == 0 && address + size <= end;
+        if fits(self.info.block_64k_size) {
+            (FlashErase::Block64K(address), self.info.block_64k_size)
+        } else if fits(self.info.block_32k_size) {
+            (FlashErase::Block32K(address), self.info.block_32k_size)
+        } else {
+            (FlashErase::Sector4K(address), self.info.sector_size)
+        }
+    }
+
+    /// Switch the QUADSPI peripheral to memory-mapped mode so the flash can be read directly at
+    /// [MEMORY_MAPPED_ADDRESS].
+    ///
+    /// Remarks:
+    /// - The flash can't be erased or programmed while memory mapped, use
+    ///   [into_indirect](MemoryMappedFlash#method.into_indirect) to get the `Flash` back.
+    /// - Reads go through the D-cache, which is cleaned and invalidated here so data programmed
+    ///   before the switch is seen.
+    /// - The flash is given back if an erase or program is still in progress.
+    pub fn into_memory_mapped(mut self) -> Result<MemoryMappedFlash, Self> {
+        if self.state != FlashState::Idle {
+            return Err(self);
+        }
+        self.wait();
+
+        let info = self.info;
+        let regs = self.qspi.inner_mut();
+        // 2^(FSIZE + 1) bytes
+        regs.dcr
+            .modify(|_, w| unsafe { w.fsize().bits(info.size.trailing_zeros() as u8 - 1) });
+        regs.ccr.write(|w| unsafe {
+            w.fmode()
+                .bits(0b11) // memory-mapped
+                .instruction()
+                .bits(READ_COMMAND)
+                .imode()
+                .bits(info.quad_enable.instruction_mode())
+                .admode()
+                .bits(0b11)
+                .adsize()
+                .bits(0b10) // 24-bit address
+                .abmode()
+                .bits(0b11)
+                .absize()
+                .bits(0b00) // 8-bit alternate byte
+                .dcyc()
+                .bits(info.read_dummy_cycles)
+                .dmode()
+                .bits(0b11)
+        });
+        regs.abr.write(|w| unsafe { w.alternate().bits(0x00) });
+
+        let mut core = unsafe { cortex_m::Peripherals::steal() };
+        core.SCB.clean_invalidate_dcache(&mut core.CPUID);
+
+        Ok(MemoryMappedFlash { flash: self })
+    }
+
+    fn check_range(&self, address: u32, len: usize) -> FlashResult<()> {
+        check_bounds(address, len, self.info.size)
+    }
+
+    fn check_idle(&self) -> FlashResult<()> {
+        match self.state {
+            FlashState::Idle => Ok(()),
+            FlashState::PoweredDown => Err(FlashError::PoweredDown),
+            _ => Err(FlashError::Busy),
+        }
+    }
+
+    fn check_protected(&self, address: u32, len: u32) -> FlashResult<()> {
+        check_protected_range(&self.protected, address, len)
+    }
+
+    /// Range protected by the BP (and TB) bits of `status`
+    fn protected_range(&self, status: u8) -> Range<u32> {
+        let size = self.info.size;
+        let bp = u32::from((status & BP_MASK) >> 2);
+        match self.info.block_protection {
+            BlockProtection::Issi => {
+                if bp == 0 {
+                    return 0..0;
+                }
+                let len = (self.info.block_64k_size << (bp - 1)).min(size);
+                size - len..size
+            }
+            BlockProtection::Winbond => {
+                let bottom = bp & 0b1000 != 0;
+                let bp = bp & 0b0111;
+                if bp == 0 {
+                    return 0..0;
+                }
+                let len = if bp == 7 {
+                    size
+                } else {
+                    ((size / 64) << (bp - 1)).min(size)
+                };
+                if bottom {
+                    0..len
+                } else {
+                    size - len..size
+                }
+            }
+        }
+    }
+
+    /// Currently write protected range, empty if nothing is protected
+    pub fn protection(&mut self) -> FlashResult<Range<u32>> {
+        self.check_idle()?;
+        let status = self.status()?;
+        self.protected = self.protected_range(status);
+        Ok(self.protected.clone())
+    }
+
+    /// Write protect `range` with the block protection bits of the status register, so erasing
+    /// or programming it fails with `FlashError::Protected`. An empty range removes the
+    /// protection.
+    ///
+    /// Remarks:
+    /// - The protection is non-volatile and stays in place after a reset.
+    /// - Only ranges the chip supports can be protected, see [BlockProtection]. Other ranges
+    ///   return `FlashError::InvalidProtection`.
+    /// - Returns `FlashError::Protected` after [lock_protection](Flash#method.lock_protection).
+    pub fn protect(&mut self, range: Range<u32>) -> FlashResult<()> {
+        self.check_idle()?;
+        if self.protection_locked {
+            return Err(FlashError::Protected);
+        }
+        let range = if range.is_empty() { 0..0 } else { range };
+        let bits = (0..=BP_MASK >> 2)
+            .map(|bp| bp << 2)
+            .find(|&bits| self.protected_range(bits) == range)
+            .ok_or(FlashError::InvalidProtection)?;
+
+        let status = (self.status()? & !BP_MASK) | bits;
+        self.write_status(status)?;
+        self.protected = range;
+        Ok(())
+    }
+
+    /// Remove the write protection, the same as `protect(0..0)`
+    pub fn unprotect(&mut self) -> FlashResult<()> {
+        self.protect(0..0)
+    }
+
+    /// Lock the current protection by setting the status register write disable bit (SRWD,
+    /// SRP0 on Winbond chips).
+    ///
+    /// Remarks:
+    /// - The bit is non-volatile, `Flash` reads it on startup and then refuses to change the
+    ///   protection, so it can't be undone through this driver.
+    /// - The chip itself only enforces the lock while WP# is low. With quad reads WP# is used as
+    ///   IO2, so other software can still clear it.
+    pub fn lock_protection(&mut self) -> FlashResult<()> {
+        self.check_idle()?;
+        if self.protection_locked {
+            return Ok(());
+        }
+        let status = self.status()? | SRWD;
+        self.write_status(status)?;
+        self.protection_locked = true;
+        Ok(())
+    }
+
+    /// True if the protection was locked with [lock_protection](Flash#method.lock_protection)
+    pub fn is_protection_locked(&self) -> bool {
+        self.protection_locked
+    }
+
+    /// Read the factory programmed 128 bit unique ID, e.g. for a device serial number.
+    pub fn unique_id(&mut self) -> FlashResult<[u8; 16]> {
+        self.check_security_commands()?;
+        let mut id = [0; 16];
+        self.read_command(0x4B, 0, self.info.read_dummy_cycles, &mut id)?;
+        Ok(id)
+    }
+
+    /// Read the Serial Flash Discoverable Parameters (JESD216) starting at `address`. The table
+    /// starts with the "SFDP" signature at address 0.
+    pub fn read_sfdp(&mut self, address: u32, data: &mut [u8]) -> FlashResult<()> {
+        self.check_security_commands()?;
+        self.read_command(0x5A, address, SFDP_DUMMY_CYCLES, data)
+    }
+
+    /// Read one of the one-time programmable information rows, starting at `offset` in the row.
+    pub fn read_otp(&mut self, row: u8, offset: u32, data: &mut [u8]) -> FlashResult<()> {
+        self.check_security_commands()?;
+        if row >= OTP_ROWS {
+            return Err(FlashError::OutOfRange);
+        }
+        check_bounds(offset, data.len(), OTP_ROW_SIZE)?;
+        self.read_command(
+            0x68,
+            Self::otp_address(row) + offset,
+            self.info.read_dummy_cycles,
+            data,
+        )
+    }
+
+    /// True if an information row was locked by [program_otp_once](Flash#method.program_otp_once)
+    pub fn is_otp_locked(&mut self, row: u8) -> FlashResult<bool> {
+        self.check_security_commands()?;
+        if row >= OTP_ROWS {
+            return Err(FlashError::OutOfRange);
+        }
+        Ok(self.function_register()? & Self::otp_lock_bit(row) != 0)
+    }
+
+    /// Write `data` to the start of an information row, check it and lock the row for good.
+    ///
+    /// Remarks:
+    /// - Locking can't be undone, the row can only be read afterwards. A locked row returns
+    ///   `FlashError::Protected`.
+    /// - The row is erased first, the rest of it reads as `0xFF`.
+    /// - This blocks until the erase and program are done.
+    pub fn program_otp_once(&mut self, row: u8, data: &[u8]) -> FlashResult<()> {
+        if self.is_otp_locked(row)? {
+            return Err(FlashError::Protected);
+        }
+        if data.len() as u32 > OTP_ROW_SIZE {
+            return Err(FlashError::OutOfRange);
+        }
+        let address = Self::otp_address(row);
+
+        self.enable_write()?;
+        self.wait();
+        self.qspi.write_extended(
+            QspiWord::U8(0x64),
+            QspiWord::U24(address),
+            QspiWord::None,
+            &[],
+        )?;
+        self.wait_write()?;
+
+        let mut offset = 0;
+        for chunk in data.chunks(FIFO_SIZE) {
+            self.enable_write()?;
+            self.wait();
+            self.qspi.write_extended(
+                QspiWord::U8(0x62),
+                QspiWord::U24(address + offset),
+                QspiWord::None,
+                chunk,
+            )?;
+            self.wait_write()?;
+            offset += chunk.len() as u32;
+        }
+
+        let mut buffer = [0; FIFO_SIZE];
+        let mut offset = 0;
+        for chunk in data.chunks(FIFO_SIZE) {
+            let read_back = &mut buffer[..chunk.len()];
+            self.read_otp(row, offset, read_back)?;
+            if let Some(i) = chunk.iter().zip(read_back.iter()).position(|(a, b)| a != b) {
+                return Err(FlashError::VerifyFailed(address + offset + i as u32));
+            }
+            offset += chunk.len() as u32;
+        }
+
+        let function = self.function_register()? | Self::otp_lock_bit(row);
+        self.enable_write()?;
+        self.write_reg(0x42, function)?;
+        self.wait_write()
+    }
+
+    /// The unique ID, SFDP and information row commands follow the ISSI chips, the Winbond
+    /// chips use other dummy cycles and security register commands
+    fn check_security_commands(&self) -> FlashResult<()> {
+        self.check_idle()?;
+        match self.info.quad_enable {
+            QuadEnable::Issi => Ok(()),
+            QuadEnable::Winbond => Err(FlashError::Unsupported),
+        }
+    }
+
+    fn otp_address(row: u8) -> u32 {
+        u32::from(row) * 0x1000
+    }
+
+    /// IRL0-3, bits 4-7 of the function register
+    fn otp_lock_bit(row: u8) -> u8 {
+        1 << (4 + row)
+    }
+
+    fn function_register(&mut self) -> FlashResult<u8> {
+        let mut function = [0];
+        self.wait();
+        self.qspi.read_extended(
+            QspiWord::U8(0x48),
+            QspiWord::None,
+            QspiWord::None,
+            0,
+            &mut function,
+        )?;
+        Ok(function[0])
+    }
+
+    /// Read `data` with an instruction that takes a 24-bit address and dummy cycles
+    fn read_command(
+        &mut self,
+        cmd: u8,
+        address: u32,
+        dummy_cycles: u8,
+        data: &mut [u8],
+    ) -> FlashResult<()> {
+        let mut addr = address;
+        for chunk in data.chunks_mut(FIFO_SIZE) {
+            self.wait();
+            self.qspi.read_extended(
+                QspiWord::U8(cmd),
+                QspiWord::U24(addr),
+                QspiWord::None,
+                dummy_cycles,
+                chunk,
+            )?;
+            addr += FIFO_SIZE as u32;
+        }
+        Ok(())
+    }
+
+    /// Write status register 1 and check that the chip accepted it
+    fn write_status(&mut self, status: u8) -> FlashResult<()> {
+        self.enable_write()?;
+        match self.info.quad_enable {
+            QuadEnable::Issi => self.write_reg(0x01, status)?,
+            QuadEnable::Winbond => {
+                //status register 2 follows, keep QE set
+                self.wait();
+                self.qspi.write_extended(
+                    QspiWord::U8(0x01),
+                    QspiWord::None,
+                    QspiWord::None,
+                    &[status, 0b0000_0010],
+                )?;
+            }
+        }
+        self.wait_write()?;
+        if self.status()? & (BP_MASK | SRWD) != status & (BP_MASK | SRWD) {
+            return Err(FlashError::Protected);
+        }
+        Ok(())
+    }
+}
+
+/// Flash in memory-mapped (XIP) mode, see [Flash::into_memory_mapped].
+pub struct MemoryMappedFlash {
+    flash: Flash,
+}
+
+impl MemoryMappedFlash {
+    /// The whole flash as a byte slice
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                MEMORY_MAPPED_ADDRESS as *const u8,
+                self.flash.info.size as usize,
+            )
+        }
+    }
+
+    /// Abort memory-mapped mode and return to indirect mode for erasing and programming.
+    pub fn into_indirect(mut self) -> Flash {
+        let regs = self.flash.qspi.inner_mut();
+        regs.cr.modify(|_, w| w.abort().set_bit());
+        while regs.cr.read().abort().bit_is_set() {}
+        self.flash.wait();
+        self.flash
+    }
+}
+
+// Largest MDMA block transfer in bytes
+const MDMA_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Bulk reads from the memory-mapped flash into RAM using MDMA channel 0, so the CPU and
+/// interrupts such as audio keep running while e.g. samples are copied to SDRAM.
+///
+/// The transfer is split into 64K blocks. Each block raises the MDMA interrupt when
+/// [listen](FlashDma#method.listen) was called, call [poll](FlashDma#method.poll) from the
+/// interrupt (or a loop) to start the next block and get the buffer back when all are done.
+///
+/// Remarks:
+/// - The destination must be in AXI SRAM, SDRAM or DTCM and should not be cached. SDRAM set up by
+///   `sdram::Sdram` isn't, otherwise invalidate the D-cache for the buffer after the transfer.
+/// - Keep the flash memory mapped until the transfer is done, leaving memory-mapped mode makes the
+///   transfer fail with `FlashError::Dma`.
+pub struct FlashDma {
+    mdma: stm32h7xx_hal::device::MDMA,
+    buffer: Option<&'static mut [u8]>,
+    listening: bool,
+    source: u32,
+    destination: u32,
+    len: usize,
+    offset: usize,
+}
+
+impl FlashDma {
+    /// Take the MDMA peripheral and enable its clock
+    pub fn new(mdma: stm32h7xx_hal::device::MDMA, prec: rcc::rec::Mdma) -> Self {
+        prec.enable();
+        Self {
+            mdma,
+            buffer: None,
+            listening: false,
+            source: 0,
+            destination: 0,
+            len: 0,
+            offset: 0,
+        }
+    }
+
+    /// Raise the `MDMA` interrupt after every block and on errors
+    pub fn listen(&mut self) {
+        self.listening = true;
+    }
+
+    /// Stop raising the `MDMA` interrupt
+    pub fn unlisten(&mut self) {
+        self.listening = false;
+        self.mdma
+            .ch0
+            .cr
+            .modify(|_, w| w.ctcie().clear_bit().teie().clear_bit());
+    }
+
+    /// True while a transfer is in progress or its buffer hasn't been taken back by `poll`
+    pub fn is_busy(&self) -> bool {
+        self.buffer.is_some()
+    }
+
+    /// Start copying `buffer.len()` bytes of `flash` from `address` into `buffer`.
+    ///
+    /// The buffer is given back with the error if a transfer is already in progress or the range
+    /// is outside the flash.
+    pub fn start(
+        &mut self,
+        flash: &MemoryMappedFlash,
+        address: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (FlashError, &'static mut [u8])> {
+        if self.buffer.is_some() {
+            return Err((FlashError::Busy, buffer));
+        }
+        if let Err(e) = flash.flash.check_range(address, buffer.len()) {
+            return Err((e, buffer));
+        }
+
+        self.source = MEMORY_MAPPED_ADDRESS + address;
+        self.destination = buffer.as_mut_ptr() as u32;
+        self.len = buffer.len();
+        self.offset = 0;
+        self.buffer = Some(buffer);
+        self.start_block();
+        Ok(())
+    }
+
+    /// Check the transfer, starting the next block when the previous one is done. Returns the
+    /// buffer once all data has been copied.
+    ///
+    /// After `FlashError::Dma` the buffer can be taken back with [abort](FlashDma#method.abort).
+    pub fn poll(&mut self) -> NBFlashResult<&'static mut [u8]> {
+        if self.buffer.is_none() {
+            return Err(nbError::Other(FlashError::MismatchedOperation));
+        }
+
+        let ch = &self.mdma.ch0;
+        let isr = ch.isr.read();
+        if isr.teif().bit_is_set() {
+            ch.cr.modify(|_, w| w.en().clear_bit());
+            Self::clear_flags(ch);
+            return Err(nbError::Other(FlashError::Dma));
+        }
+        if isr.ctcif().bit_is_clear() {
+            return Err(nbError::WouldBlock);
+        }
+        Self::clear_flags(ch);
+
+        self.offset += MDMA_BLOCK_SIZE.min(self.len - self.offset);
+        if self.offset < self.len {
+            self.start_block();
+            return Err(nbError::WouldBlock);
+        }
+        Ok(self.buffer.take().unwrap())
+    }
+
+    /// Stop the transfer and give back the buffer, if any. Its contents are undefined.
+    pub fn abort(&mut self) -> Option<&'static mut [u8]> {
+        let ch = &self.mdma.ch0;
+        ch.cr.modify(|_, w| w.en().clear_bit());
+        while ch.cr.read().en().bit_is_set() {}
+        Self::clear_flags(ch);
+        self.buffer.take()
+    }
+
+    /// Give back the MDMA peripheral
+    pub fn free(mut self) -> stm32h7xx_hal::device::MDMA {
+        self.abort();
+        self.mdma
+    }
+
+    fn clear_flags(ch: &stm32h7xx_hal::device::mdma::CH) {
+        ch.ifcr.write(|w| {
+            w.cteif()
+                .set_bit()
+                .cctcif()
+                .set_bit()
+                .cbrtif()
+                .set_bit()
+                .cbtif()
+                .set_bit()
+                .cltcif()
+                .set_bit()
+        });
+    }
+
+    fn start_block(&mut self) {
+        let source = self.source + self.offset as u32;
+        let destination = self.destination + self.offset as u32;
+        let len = MDMA_BLOCK_SIZE.min(self.len - self.offset);
+        // Word transfers if everything is aligned, bytes otherwise
+        let size = if (self.source | self.destination | self.len as u32) & 0b11 == 0 {
+            0b10
+        } else {
+            0b00
+        };
+
+        let ch = &self.mdma.ch0;
+        ch.cr.write(|w| w.en().clear_bit());
+        Self::clear_flags(ch);
+        ch.tcr.write(|w| unsafe {
+            w.sinc()
+                .bits(0b10) // increment
+                .dinc()
+                .bits(0b10)
+                .ssize()
+                .bits(size)
+                .dsize()
+                .bits(size)
+                .sincos()
+                .bits(size)
+                .dincos()
+                .bits(size)
+                .sburst()
+                .bits(0b100) // 16 beats
+                .dburst()
+                .bits(0b100)
+                .tlen()
+                .bits(127) // 128 byte buffer
+                .trgm()
+                .bits(0b01) // a request transfers a whole block
+                .swrm()
+                .set_bit()
+                .bwm()
+                .set_bit()
+        });
+        ch.bndtr
+            .write(|w| unsafe { w.bndt().bits(len as u32).brc().bits(0) });
+        ch.sar.write(|w| unsafe { w.sar().bits(source) });
+        ch.dar.write(|w| unsafe { w.dar().bits(destination) });
+        ch.tbr.write(|w| {
+            w.sbus()
+                .bit(mdma::is_ahb_port(source as usize))
+                .dbus()
+                .bit(mdma::is_ahb_port(destination as usize))
+        });
+        ch.lar.write(|w| unsafe { w.lar().bits(0) });
+        ch.cr.write(|w| unsafe {
+            w.pl()
+                .bits(0b10)
+                .ctcie()
+                .bit(self.listening)
+                .teie()
+                .bit(self.listening)
+                .en()
+                .set_bit()
+        });
+        ch.cr.modify(|_, w| w.swrq().set_bit());
+    }
+}
+
+/// Future that is pending once, so other tasks get a chance to run between status polls
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+fn yield_now() -> YieldNow {
+    YieldNow(false)
+}
+
+impl ErrorType for Flash {
+    type Error = FlashError;
+}
+
+impl ReadNorFlash for Flash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        Flash::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.info.size as usize
+    }
+}
+
+impl NorFlash for Flash {
+    const WRITE_SIZE: usize = PAGE_SIZE as usize;
+    const ERASE_SIZE: usize = SECTOR_SIZE as usize;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        self.erase_range_blocking(from, to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        self.program_blocking(offset, bytes)
+    }
+}
+
+// Programming can only clear bits, so writing the same location twice is fine
+impl MultiwriteNorFlash for Flash {}
+
+impl embedded_storage_async::nor_flash::ReadNorFlash for Flash {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        ReadNorFlash::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.info.size as usize
+    }
+}
+
+impl embedded_storage_async::nor_flash::NorFlash for Flash {
+    const WRITE_SIZE: usize = PAGE_SIZE as usize;
+    const ERASE_SIZE: usize = SECTOR_SIZE as usize;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        self.erase_range_async(from, to).await
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        self.program_async(offset, bytes).await
+    }
+}
+
+impl embedded_storage_async::nor_flash::MultiwriteNorFlash for Flash {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finished_erase_stays_suspended() {
+        let op = FlashErase::Sector4K(0x1000);
+        let mut state = FlashState::Erasing(op);
+        // Suspended after the chip finished the erase
+        state = state.suspended().unwrap();
+        assert_eq!(state, FlashState::EraseSuspended(op));
+        assert_eq!(state.resumed(), Some(FlashState::Erasing(op)));
+        state = state.resumed().unwrap();
+
+        // The next poll sees WIP clear and completes instead of erasing again
+        assert_eq!(
+            state.poll_erase(FlashErase::Sector4K(0x2000), true),
+            Err(nbError::Other(FlashError::MismatchedOperation))
+        );
+        assert_eq!(state.poll_erase(op, false), Err(nbError::WouldBlock));
+        assert_eq!(state.poll_erase(op, true), Ok(()));
+        assert_eq!(state, FlashState::Idle);
+    }
+
+    #[test]
+    fn bounds_checks_dont_overflow() {
+        let size = KNOWN_CHIPS[0].size;
+        assert_eq!(check_bounds(0, size as usize, size), Ok(()));
+        assert_eq!(check_bounds(size - 1, 2, size), Err(FlashError::OutOfRange));
+        assert_eq!(
+            check_bounds(u32::MAX - 1, 4, size),
+            Err(FlashError::OutOfRange)
+        );
+        assert_eq!(
+            check_bounds(4, usize::MAX, size),
+            Err(FlashError::OutOfRange)
+        );
+        assert_eq!(
+            check_bounds(u32::MAX - 1, 4, OTP_ROW_SIZE),
+            Err(FlashError::OutOfRange)
+        );
+
+        let protected = size - 0x1_0000..size;
+        assert_eq!(check_protected_range(&protected, 0, 256), Ok(()));
+        assert_eq!(
+            check_protected_range(&protected, size - 1, 256),
+            Err(FlashError::Protected)
+        );
+        assert_eq!(
+            check_protected_range(&(0..0x1000), u32::MAX - 1, 256),
+            Err(FlashError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn only_operations_in_progress_are_suspended() {
+        let program = FlashState::Programming {
+            address: 0x100,
+            len: 300,
+            offset: 256,
+        };
+        let suspended = program.suspended().unwrap();
+        assert_eq!(suspended.resumed(), Some(program));
+        assert_eq!(FlashState::Idle.suspended(), None);
+        assert_eq!(FlashState::Idle.resumed(), None);
+        assert_eq!(FlashState::PoweredDown.suspended(), None);
     }
 }
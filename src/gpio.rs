@@ -166,3 +166,294 @@ impl GPIO {
         self.codec.set_high();
     }
 }
+
+/// Every pin of the Seed by name, built from the split GPIO ports.
+///
+/// Pins are moved out of it one at a time, so a peripheral only takes what it uses:
+///
+/// ```rust
+/// let pins = gpio::Pins::new(gpioa, gpiob, gpioc, gpiod, gpioe, gpiof, gpiog, gpioh, gpioi);
+/// let sd = sdmmc::init_1bit(pins.daisy4, pins.daisy5, pins.daisy6, sdmmc1, rec, clocks);
+/// let led = pins.led.into_push_pull_output();
+/// ```
+pub struct Pins {
+    /// Seed LED
+    pub led: gpio::gpioc::PC7<Analog>,
+    /// AK4556 codec reset
+    pub codec_reset: gpio::gpiob::PB11<Analog>,
+    pub daisy0: gpio::gpiob::PB12<Analog>,
+    pub daisy1: gpio::gpioc::PC11<Analog>,
+    pub daisy2: gpio::gpioc::PC10<Analog>,
+    pub daisy3: gpio::gpioc::PC9<Analog>,
+    pub daisy4: gpio::gpioc::PC8<Analog>,
+    pub daisy5: gpio::gpiod::PD2<Analog>,
+    pub daisy6: gpio::gpioc::PC12<Analog>,
+    pub daisy7: gpio::gpiog::PG10<Analog>,
+    pub daisy8: gpio::gpiog::PG11<Analog>,
+    pub daisy9: gpio::gpiob::PB4<Alternate<0>>,
+    pub daisy10: gpio::gpiob::PB5<Analog>,
+    pub daisy11: gpio::gpiob::PB8<Analog>,
+    pub daisy12: gpio::gpiob::PB9<Analog>,
+    pub daisy13: gpio::gpiob::PB6<Analog>,
+    pub daisy14: gpio::gpiob::PB7<Analog>,
+    pub daisy15: gpio::gpioc::PC0<Analog>,
+    pub daisy16: gpio::gpioa::PA3<Analog>,
+    pub daisy17: gpio::gpiob::PB1<Analog>,
+    pub daisy18: gpio::gpioa::PA7<Analog>,
+    pub daisy19: gpio::gpioa::PA6<Analog>,
+    pub daisy20: gpio::gpioc::PC1<Analog>,
+    pub daisy21: gpio::gpioc::PC4<Analog>,
+    pub daisy22: gpio::gpioa::PA5<Analog>,
+    pub daisy23: gpio::gpioa::PA4<Analog>,
+    pub daisy24: gpio::gpioa::PA1<Analog>,
+    pub daisy25: gpio::gpioa::PA0<Analog>,
+    pub daisy26: gpio::gpiod::PD11<Analog>,
+    pub daisy27: gpio::gpiog::PG9<Analog>,
+    pub daisy28: gpio::gpioa::PA2<Analog>,
+    pub daisy29: gpio::gpiob::PB14<Analog>,
+    pub daisy30: gpio::gpiob::PB15<Analog>,
+    pub audio: AudioPins,
+    pub flash: FlashPins,
+    pub sdram: SdramPins,
+    pub usb: UsbPins,
+}
+
+/// SAI1 pins of the on board codec, see [Audio::new](crate::audio::Audio::new)
+pub struct AudioPins {
+    pub pe2: gpio::gpioe::PE2<Analog>,
+    pub pe3: gpio::gpioe::PE3<Analog>,
+    pub pe4: gpio::gpioe::PE4<Analog>,
+    pub pe5: gpio::gpioe::PE5<Analog>,
+    pub pe6: gpio::gpioe::PE6<Analog>,
+}
+
+/// QUADSPI pins of the on board flash, see [Flash::new](crate::flash::Flash::new)
+pub struct FlashPins {
+    pub pf6: gpio::gpiof::PF6<Analog>,
+    pub pf7: gpio::gpiof::PF7<Analog>,
+    pub pf8: gpio::gpiof::PF8<Analog>,
+    pub pf9: gpio::gpiof::PF9<Analog>,
+    pub pf10: gpio::gpiof::PF10<Analog>,
+    pub pg6: gpio::gpiog::PG6<Analog>,
+}
+
+/// FMC pins of the on board SDRAM, see [Sdram::new](crate::sdram::Sdram::new)
+pub struct SdramPins {
+    pub pd0: gpio::gpiod::PD0<Analog>,
+    pub pd1: gpio::gpiod::PD1<Analog>,
+    pub pd8: gpio::gpiod::PD8<Analog>,
+    pub pd9: gpio::gpiod::PD9<Analog>,
+    pub pd10: gpio::gpiod::PD10<Analog>,
+    pub pd14: gpio::gpiod::PD14<Analog>,
+    pub pd15: gpio::gpiod::PD15<Analog>,
+    pub pe0: gpio::gpioe::PE0<Analog>,
+    pub pe1: gpio::gpioe::PE1<Analog>,
+    pub pe7: gpio::gpioe::PE7<Analog>,
+    pub pe8: gpio::gpioe::PE8<Analog>,
+    pub pe9: gpio::gpioe::PE9<Analog>,
+    pub pe10: gpio::gpioe::PE10<Analog>,
+    pub pe11: gpio::gpioe::PE11<Analog>,
+    pub pe12: gpio::gpioe::PE12<Analog>,
+    pub pe13: gpio::gpioe::PE13<Analog>,
+    pub pe14: gpio::gpioe::PE14<Analog>,
+    pub pe15: gpio::gpioe::PE15<Analog>,
+    pub pf0: gpio::gpiof::PF0<Analog>,
+    pub pf1: gpio::gpiof::PF1<Analog>,
+    pub pf2: gpio::gpiof::PF2<Analog>,
+    pub pf3: gpio::gpiof::PF3<Analog>,
+    pub pf4: gpio::gpiof::PF4<Analog>,
+    pub pf5: gpio::gpiof::PF5<Analog>,
+    pub pf11: gpio::gpiof::PF11<Analog>,
+    pub pf12: gpio::gpiof::PF12<Analog>,
+    pub pf13: gpio::gpiof::PF13<Analog>,
+    pub pf14: gpio::gpiof::PF14<Analog>,
+    pub pf15: gpio::gpiof::PF15<Analog>,
+    pub pg0: gpio::gpiog::PG0<Analog>,
+    pub pg1: gpio::gpiog::PG1<Analog>,
+    pub pg2: gpio::gpiog::PG2<Analog>,
+    pub pg4: gpio::gpiog::PG4<Analog>,
+    pub pg5: gpio::gpiog::PG5<Analog>,
+    pub pg8: gpio::gpiog::PG8<Analog>,
+    pub pg15: gpio::gpiog::PG15<Analog>,
+    pub ph2: gpio::gpioh::PH2<Analog>,
+    pub ph3: gpio::gpioh::PH3<Analog>,
+    pub ph5: gpio::gpioh::PH5<Analog>,
+    pub ph8: gpio::gpioh::PH8<Analog>,
+    pub ph9: gpio::gpioh::PH9<Analog>,
+    pub ph10: gpio::gpioh::PH10<Analog>,
+    pub ph11: gpio::gpioh::PH11<Analog>,
+    pub ph12: gpio::gpioh::PH12<Analog>,
+    pub ph13: gpio::gpioh::PH13<Analog>,
+    pub ph14: gpio::gpioh::PH14<Analog>,
+    pub ph15: gpio::gpioh::PH15<Analog>,
+    pub pi0: gpio::gpioi::PI0<Analog>,
+    pub pi1: gpio::gpioi::PI1<Analog>,
+    pub pi2: gpio::gpioi::PI2<Analog>,
+    pub pi3: gpio::gpioi::PI3<Analog>,
+    pub pi4: gpio::gpioi::PI4<Analog>,
+    pub pi5: gpio::gpioi::PI5<Analog>,
+    pub pi6: gpio::gpioi::PI6<Analog>,
+    pub pi7: gpio::gpioi::PI7<Analog>,
+    pub pi9: gpio::gpioi::PI9<Analog>,
+    pub pi10: gpio::gpioi::PI10<Analog>,
+}
+
+/// USB pins of the micro USB connector
+pub struct UsbPins {
+    pub pa10: gpio::gpioa::PA10<Analog>,
+    pub pa11: gpio::gpioa::PA11<Analog>,
+    pub pa12: gpio::gpioa::PA12<Analog>,
+}
+
+// Build the pin groups shared by the Seed and the Patch SM from the split ports
+macro_rules! audio_pins {
+    ($gpioe:ident) => {
+        $crate::gpio::AudioPins {
+            pe2: $gpioe.pe2,
+            pe3: $gpioe.pe3,
+            pe4: $gpioe.pe4,
+            pe5: $gpioe.pe5,
+            pe6: $gpioe.pe6,
+        }
+    };
+}
+pub(crate) use audio_pins;
+
+macro_rules! flash_pins {
+    ($gpiof:ident, $gpiog:ident) => {
+        $crate::gpio::FlashPins {
+            pf6: $gpiof.pf6,
+            pf7: $gpiof.pf7,
+            pf8: $gpiof.pf8,
+            pf9: $gpiof.pf9,
+            pf10: $gpiof.pf10,
+            pg6: $gpiog.pg6,
+        }
+    };
+}
+pub(crate) use flash_pins;
+
+macro_rules! sdram_pins {
+    ($gpiod:ident, $gpioe:ident, $gpiof:ident, $gpiog:ident, $gpioh:ident, $gpioi:ident) => {
+        $crate::gpio::SdramPins {
+            pd0: $gpiod.pd0,
+            pd1: $gpiod.pd1,
+            pd8: $gpiod.pd8,
+            pd9: $gpiod.pd9,
+            pd10: $gpiod.pd10,
+            pd14: $gpiod.pd14,
+            pd15: $gpiod.pd15,
+            pe0: $gpioe.pe0,
+            pe1: $gpioe.pe1,
+            pe7: $gpioe.pe7,
+            pe8: $gpioe.pe8,
+            pe9: $gpioe.pe9,
+            pe10: $gpioe.pe10,
+            pe11: $gpioe.pe11,
+            pe12: $gpioe.pe12,
+            pe13: $gpioe.pe13,
+            pe14: $gpioe.pe14,
+            pe15: $gpioe.pe15,
+            pf0: $gpiof.pf0,
+            pf1: $gpiof.pf1,
+            pf2: $gpiof.pf2,
+            pf3: $gpiof.pf3,
+            pf4: $gpiof.pf4,
+            pf5: $gpiof.pf5,
+            pf11: $gpiof.pf11,
+            pf12: $gpiof.pf12,
+            pf13: $gpiof.pf13,
+            pf14: $gpiof.pf14,
+            pf15: $gpiof.pf15,
+            pg0: $gpiog.pg0,
+            pg1: $gpiog.pg1,
+            pg2: $gpiog.pg2,
+            pg4: $gpiog.pg4,
+            pg5: $gpiog.pg5,
+            pg8: $gpiog.pg8,
+            pg15: $gpiog.pg15,
+            ph2: $gpioh.ph2,
+            ph3: $gpioh.ph3,
+            ph5: $gpioh.ph5,
+            ph8: $gpioh.ph8,
+            ph9: $gpioh.ph9,
+            ph10: $gpioh.ph10,
+            ph11: $gpioh.ph11,
+            ph12: $gpioh.ph12,
+            ph13: $gpioh.ph13,
+            ph14: $gpioh.ph14,
+            ph15: $gpioh.ph15,
+            pi0: $gpioi.pi0,
+            pi1: $gpioi.pi1,
+            pi2: $gpioi.pi2,
+            pi3: $gpioi.pi3,
+            pi4: $gpioi.pi4,
+            pi5: $gpioi.pi5,
+            pi6: $gpioi.pi6,
+            pi7: $gpioi.pi7,
+            pi9: $gpioi.pi9,
+            pi10: $gpioi.pi10,
+        }
+    };
+}
+pub(crate) use sdram_pins;
+
+impl Pins {
+    /// Sort the pins of the split ports by name, pins the Seed doesn't use are dropped
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        gpioa: gpio::gpioa::Parts,
+        gpiob: gpio::gpiob::Parts,
+        gpioc: gpio::gpioc::Parts,
+        gpiod: gpio::gpiod::Parts,
+        gpioe: gpio::gpioe::Parts,
+        gpiof: gpio::gpiof::Parts,
+        gpiog: gpio::gpiog::Parts,
+        gpioh: gpio::gpioh::Parts,
+        gpioi: gpio::gpioi::Parts,
+    ) -> Self {
+        Self {
+            led: gpioc.pc7,
+            codec_reset: gpiob.pb11,
+            daisy0: gpiob.pb12,
+            daisy1: gpioc.pc11,
+            daisy2: gpioc.pc10,
+            daisy3: gpioc.pc9,
+            daisy4: gpioc.pc8,
+            daisy5: gpiod.pd2,
+            daisy6: gpioc.pc12,
+            daisy7: gpiog.pg10,
+            daisy8: gpiog.pg11,
+            daisy9: gpiob.pb4,
+            daisy10: gpiob.pb5,
+            daisy11: gpiob.pb8,
+            daisy12: gpiob.pb9,
+            daisy13: gpiob.pb6,
+            daisy14: gpiob.pb7,
+            daisy15: gpioc.pc0,
+            daisy16: gpioa.pa3,
+            daisy17: gpiob.pb1,
+            daisy18: gpioa.pa7,
+            daisy19: gpioa.pa6,
+            daisy20: gpioc.pc1,
+            daisy21: gpioc.pc4,
+            daisy22: gpioa.pa5,
+            daisy23: gpioa.pa4,
+            daisy24: gpioa.pa1,
+            daisy25: gpioa.pa0,
+            daisy26: gpiod.pd11,
+            daisy27: gpiog.pg9,
+            daisy28: gpioa.pa2,
+            daisy29: gpiob.pb14,
+            daisy30: gpiob.pb15,
+            audio: audio_pins!(gpioe),
+            flash: flash_pins!(gpiof, gpiog),
+            sdram: sdram_pins!(gpiod, gpioe, gpiof, gpiog, gpioh, gpioi),
+            usb: UsbPins {
+                pa10: gpioa.pa10,
+                pa11: gpioa.pa11,
+                pa12: gpioa.pa12,
+            },
+        }
+    }
+}
@@ -170,6 +170,56 @@ where
     }
 }
 
+/// Gate or trigger input without debouncing, so short triggers are not lost.
+///
+/// Remarks:
+/// - `invert` is for inputs behind an inverting transistor stage, where a high gate pulls the
+///   pin low.
+/// - A trigger must last until the next [update](GateIn#method.update) to be seen.
+pub struct GateIn<T> {
+    pin: T,
+    invert: bool,
+    previous: bool,
+    state: bool,
+}
+
+impl<T> GateIn<T>
+where
+    T: InputPin,
+    <T as InputPin>::Error: core::fmt::Debug,
+{
+    /// Create a new GateIn.
+    pub fn new(pin: T, invert: bool) -> Self {
+        Self {
+            pin,
+            invert,
+            previous: false,
+            state: false,
+        }
+    }
+
+    /// Read the gate and update status. This should be called on a timer.
+    pub fn update(&mut self) {
+        self.previous = self.state;
+        self.state = self.pin.is_high().unwrap() != self.invert;
+    }
+
+    /// If the gate went high since the last update
+    pub fn trig(&self) -> bool {
+        self.state && !self.previous
+    }
+
+    /// If the gate is high
+    pub fn state(&self) -> bool {
+        self.state
+    }
+
+    /// Get the pin
+    pub fn get_pin(&mut self) -> &mut T {
+        &mut self.pin
+    }
+}
+
 const ANALOG_ARR_SIZE: usize = 4;
 const ANALOG_ARR_SIZE_F32: f32 = ANALOG_ARR_SIZE as f32;
 
@@ -357,3 +407,105 @@ where
         };
     }
 }
+
+/// An RGB LED made of three [Led]s sharing the same settings.
+pub struct RgbLed<R, G, B> {
+    pub red: Led<R>,
+    pub green: Led<G>,
+    pub blue: Led<B>,
+}
+
+impl<R, G, B> RgbLed<R, G, B>
+where
+    R: OutputPin,
+    G: OutputPin,
+    B: OutputPin,
+{
+    /// Create a new RGB LED, see [Led::new].
+    pub fn new(red: R, green: G, blue: B, invert: bool, resolution: u32) -> Self {
+        Self {
+            red: Led::new(red, invert, resolution),
+            green: Led::new(green, invert, resolution),
+            blue: Led::new(blue, invert, resolution),
+        }
+    }
+
+    /// Set the brightness of each color from 0.0 to 1.0.
+    pub fn set(&mut self, red: f32, green: f32, blue: f32) {
+        self.red.set_brightness(red);
+        self.green.set_brightness(green);
+        self.blue.set_brightness(blue);
+    }
+
+    /// Update LED status. This should be called on a timer.
+    pub fn update(&mut self) {
+        self.red.update();
+        self.green.update();
+        self.blue.update();
+    }
+}
+
+/// Quadrature encoder with a push button.
+///
+/// Remarks:
+/// - A detent is counted on the falling edge of either phase, A and B need pull-ups.
+/// - Call [update](Encoder#method.update) at about 1kHz.
+pub struct Encoder<A, B, C> {
+    a: A,
+    b: B,
+    a_state: u8,
+    b_state: u8,
+    increment: i32,
+    switch: Switch<C>,
+}
+
+impl<A, B, C> Encoder<A, B, C>
+where
+    A: InputPin,
+    B: InputPin,
+    C: InputPin,
+    <A as InputPin>::Error: core::fmt::Debug,
+    <B as InputPin>::Error: core::fmt::Debug,
+    <C as InputPin>::Error: core::fmt::Debug,
+{
+    /// Create a new Encoder, `click` is the push button.
+    pub fn new(a: A, b: B, click: C, switch_type: SwitchType) -> Self {
+        Self {
+            a,
+            b,
+            a_state: 0xFF,
+            b_state: 0xFF,
+            increment: 0,
+            switch: Switch::new(click, switch_type),
+        }
+    }
+
+    /// Read the phases and the button and update status. This should be called on a timer.
+    pub fn update(&mut self) {
+        self.a_state = (self.a_state << 1) | self.a.is_high().unwrap() as u8;
+        self.b_state = (self.b_state << 1) | self.b.is_high().unwrap() as u8;
+
+        self.increment = match (self.a_state & 0x03, self.b_state & 0x03) {
+            (0x02, 0x00) => 1,
+            (0x00, 0x02) => -1,
+            _ => 0,
+        };
+
+        self.switch.update();
+    }
+
+    /// 1 or -1 when the encoder was turned by one detent since the last update, otherwise 0
+    pub fn increment(&self) -> i32 {
+        self.increment
+    }
+
+    /// The push button
+    pub fn switch(&self) -> &Switch<C> {
+        &self.switch
+    }
+
+    /// The push button, e.g. to set its thresholds
+    pub fn switch_mut(&mut self) -> &mut Switch<C> {
+        &mut self.switch
+    }
+}
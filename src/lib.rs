@@ -1,6 +1,9 @@
 #![no_std]
 #![allow(dead_code)]
 
+#[cfg(all(feature = "mock-flash", not(target_os = "none")))]
+extern crate std;
+
 // #[macro_use(singleton)]
 // extern crate cortex_m;
 
@@ -22,11 +25,18 @@ pub type FrameTimer = stm32h7xx_hal::timer::Timer<stm32h7xx_hal::stm32::TIM2>;
 pub use stm32h7xx_hal as hal;
 
 pub mod audio;
+pub mod boards;
+mod crc;
+pub mod filesystem;
+pub mod firmware;
 pub mod flash;
 pub mod gpio;
 pub mod hid;
 pub mod logger;
+#[cfg(all(feature = "mock-flash", not(target_os = "none")))]
+pub mod mock_flash;
 pub mod mpu;
+pub mod persistent_storage;
 pub mod prelude;
 pub mod sdmmc;
 pub mod sdram;
@@ -89,6 +89,10 @@ cfg_if::cfg_if! {
         /// no log and no panic handler
         pub fn init() {}
     }
+    else if #[cfg(all(feature = "mock-flash", not(target_os = "none")))] {
+        /// no log, std provides the panic handler on the host
+        pub fn init() {}
+    }
     else {
         use panic_halt as _;
         /// Initialize logging if feature is enabled, otherwise does nothing
@@ -0,0 +1,398 @@
+//! In-memory stand-in for [Flash](crate::flash::Flash) to test storage code on the host.
+//!
+//! `MockFlash` implements the same `embedded-storage` traits and erase/program/read methods as
+//! `Flash` and behaves like NOR flash:
+//! - Programming can only clear bits, a byte becomes `old & new`.
+//! - Erases must be aligned to their size and set everything to `0xFF`.
+//! - A single page program wraps around to the start of the page, see
+//!   [page_program](MockFlash#method.page_program).
+//!
+//! A power loss can be simulated part way through an erase or program with
+//! [power_loss_after](MockFlash#method.power_loss_after).
+//!
+//! Requires the `mock-flash` feature, which needs `std`. Build for the host, e.g.
+//! `cargo test --features mock-flash --target x86_64-unknown-linux-gnu`.
+//!
+//! # Example
+//!
+//! ```rust
+//! let mut flash = MockFlash::new(KNOWN_CHIPS[0]);
+//! flash.program_blocking(0, &[0x0F]).unwrap();
+//! flash.program_blocking(0, &[0xF5]).unwrap();
+//! assert_eq!(flash.as_slice()[0], 0x05);
+//!
+//! // Lose power after 100 bytes, the storage must recover from it after a power cycle
+//! flash.power_loss_after(100);
+//! assert_eq!(flash.program_blocking(0x1000, &[0; 256]), Err(FlashError::PowerLoss));
+//! flash.power_cycle();
+//! ```
+
+use std::{fs, io, path::Path, vec, vec::Vec};
+
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, MultiwriteNorFlash, NorFlash, ReadNorFlash,
+};
+use stm32h7xx_hal::nb;
+
+use crate::flash::{check_bounds, FlashErase, FlashError, FlashInfo, FlashResult, NBFlashResult};
+use crate::flash::{PAGE_SIZE, SECTOR_SIZE};
+
+/// Flash memory backed by a `Vec<u8>`
+pub struct MockFlash {
+    info: FlashInfo,
+    data: Vec<u8>,
+    /// Bytes that can still be erased or programmed before the simulated power loss
+    power_budget: Option<usize>,
+    powered: bool,
+}
+
+impl MockFlash {
+    /// Erased flash with the geometry of `info`, e.g. one of the
+    /// [KNOWN_CHIPS](crate::flash::KNOWN_CHIPS)
+    pub fn new(info: FlashInfo) -> Self {
+        Self::from_vec(info, vec![0xFF; info.size as usize])
+    }
+
+    /// Flash with the given contents, padded with `0xFF` or truncated to `info.size`
+    pub fn from_vec(info: FlashInfo, mut data: Vec<u8>) -> Self {
+        data.resize(info.size as usize, 0xFF);
+        Self {
+            info,
+            data,
+            power_budget: None,
+            powered: true,
+        }
+    }
+
+    /// Load an image file, see [from_vec](MockFlash#method.from_vec)
+    pub fn load<P: AsRef<Path>>(info: FlashInfo, path: P) -> io::Result<Self> {
+        Ok(Self::from_vec(info, fs::read(path)?))
+    }
+
+    /// Write the contents to an image file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, &self.data)
+    }
+
+    /// Geometry of the simulated chip
+    pub fn info(&self) -> &FlashInfo {
+        &self.info
+    }
+
+    /// The current contents
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Give back the contents
+    pub fn into_vec(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Lose power once `bytes` more bytes were erased or programmed. The operation in progress
+    /// stops half way and returns `FlashError::PowerLoss`, as does everything after it until
+    /// [power_cycle](MockFlash#method.power_cycle).
+    pub fn power_loss_after(&mut self, bytes: usize) {
+        self.power_budget = Some(bytes);
+    }
+
+    /// Restore power after a simulated power loss and cancel a pending one
+    pub fn power_cycle(&mut self) {
+        self.power_budget = None;
+        self.powered = true;
+    }
+
+    /// Read `data` out of the flash starting at the given `address`
+    pub fn read(&mut self, address: u32, data: &mut [u8]) -> FlashResult<()> {
+        self.check_powered()?;
+        let range = self.range(address, data.len())?;
+        data.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    /// Erase all or some of the chip, completes immediately
+    pub fn erase(&mut self, op: FlashErase) -> NBFlashResult<()> {
+        let (address, size) = match op {
+            FlashErase::Chip => (0, self.info.size),
+            FlashErase::Sector4K(a) => (a, self.info.sector_size),
+            FlashErase::Block32K(a) => (a, self.info.block_32k_size),
+            FlashErase::Block64K(a) => (a, self.info.block_64k_size),
+        };
+        if address & (size - 1) != 0 {
+            return Err(nb::Error::Other(FlashError::Misaligned));
+        }
+        self.check_powered()?;
+        let range = self.range(address, size as usize)?;
+        for byte in &mut self.data[range] {
+            Self::consume(&mut self.power_budget, &mut self.powered)?;
+            *byte = 0xFF;
+        }
+        Ok(())
+    }
+
+    /// Program `data` into the flash starting at the given `address`, completes immediately.
+    ///
+    /// Like [Flash::program](crate::flash::Flash#method.program) it is split into page programs
+    /// that don't cross the end of a page.
+    pub fn program(&mut self, address: u32, data: &[u8]) -> NBFlashResult<()> {
+        self.range(address, data.len())?;
+        let mut offset = 0;
+        while offset < data.len() {
+            let addr = address + offset as u32;
+            let page_remaining = (PAGE_SIZE - addr % PAGE_SIZE) as usize;
+            let len = (data.len() - offset).min(page_remaining);
+            self.page_program(addr, &data[offset..offset + len])?;
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// A single page program command. Like on the chip, data past the end of the page wraps
+    /// around to the start of the same page, and only the last 256 bytes are kept.
+    pub fn page_program(&mut self, address: u32, data: &[u8]) -> FlashResult<()> {
+        self.check_powered()?;
+        self.range(address, 1)?;
+        let page = (address & !(PAGE_SIZE - 1)) as usize;
+        let page_size = PAGE_SIZE as usize;
+        let skip = data.len().saturating_sub(page_size);
+        let start = address as usize - page + skip;
+        for (i, byte) in data[skip..].iter().enumerate() {
+            Self::consume(&mut self.power_budget, &mut self.powered)?;
+            self.data[page + (start + i) % page_size] &= byte;
+        }
+        Ok(())
+    }
+
+    /// Erase, see [erase](MockFlash#method.erase).
+    pub fn erase_blocking(&mut self, op: FlashErase) -> FlashResult<()> {
+        nb::block!(self.erase(op))
+    }
+
+    /// Program, see [program](MockFlash#method.program).
+    pub fn program_blocking(&mut self, address: u32, data: &[u8]) -> FlashResult<()> {
+        nb::block!(self.program(address, data))
+    }
+
+    /// Erase every sector in `from..to`, which must be sector aligned.
+    pub fn erase_range_blocking(&mut self, from: u32, to: u32) -> FlashResult<()> {
+        if from > to || to > self.info.size {
+            return Err(FlashError::OutOfRange);
+        }
+        if (from | to) & (self.info.sector_size - 1) != 0 {
+            return Err(FlashError::Misaligned);
+        }
+        for address in (from..to).step_by(self.info.sector_size as usize) {
+            self.erase_blocking(FlashErase::Sector4K(address))?;
+        }
+        Ok(())
+    }
+
+    fn check_powered(&self) -> FlashResult<()> {
+        if self.powered {
+            Ok(())
+        } else {
+            Err(FlashError::PowerLoss)
+        }
+    }
+
+    fn range(&self, address: u32, len: usize) -> FlashResult<core::ops::Range<usize>> {
+        check_bounds(address, len, self.info.size)?;
+        let start = address as usize;
+        Ok(start..start + len)
+    }
+
+    /// Use up one byte of the power budget
+    fn consume(budget: &mut Option<usize>, powered: &mut bool) -> FlashResult<()> {
+        match budget {
+            Some(0) => {
+                *powered = false;
+                Err(FlashError::PowerLoss)
+            }
+            Some(bytes) => {
+                *bytes -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl ErrorType for MockFlash {
+    type Error = FlashError;
+}
+
+impl ReadNorFlash for MockFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        MockFlash::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.info.size as usize
+    }
+}
+
+impl NorFlash for MockFlash {
+    const WRITE_SIZE: usize = PAGE_SIZE as usize;
+    const ERASE_SIZE: usize = SECTOR_SIZE as usize;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        self.erase_range_blocking(from, to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        self.program_blocking(offset, bytes)
+    }
+}
+
+impl MultiwriteNorFlash for MockFlash {}
+
+impl embedded_storage_async::nor_flash::ReadNorFlash for MockFlash {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        ReadNorFlash::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.info.size as usize
+    }
+}
+
+impl embedded_storage_async::nor_flash::NorFlash for MockFlash {
+    const WRITE_SIZE: usize = PAGE_SIZE as usize;
+    const ERASE_SIZE: usize = SECTOR_SIZE as usize;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        NorFlash::erase(self, from, to)
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        NorFlash::write(self, offset, bytes)
+    }
+}
+
+impl embedded_storage_async::nor_flash::MultiwriteNorFlash for MockFlash {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::KNOWN_CHIPS;
+
+    #[test]
+    fn program_only_clears_bits() {
+        let mut flash = MockFlash::new(KNOWN_CHIPS[0]);
+        flash.program_blocking(10, &[0x0F, 0xF0]).unwrap();
+        flash.program_blocking(10, &[0xF5, 0xFF]).unwrap();
+        assert_eq!(&flash.as_slice()[9..13], &[0xFF, 0x05, 0xF0, 0xFF]);
+
+        flash.erase_blocking(FlashErase::Sector4K(0)).unwrap();
+        assert!(flash.as_slice()[..SECTOR_SIZE as usize]
+            .iter()
+            .all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn program_is_split_at_pages() {
+        let mut flash = MockFlash::new(KNOWN_CHIPS[0]);
+        flash
+            .program_blocking(PAGE_SIZE - 2, &[1, 2, 3, 4])
+            .unwrap();
+        assert_eq!(
+            &flash.as_slice()[PAGE_SIZE as usize - 2..][..4],
+            &[1, 2, 3, 4]
+        );
+        assert_eq!(flash.as_slice()[0], 0xFF);
+
+        // A single page program wraps around instead
+        flash
+            .page_program(2 * PAGE_SIZE - 2, &[1, 2, 3, 4])
+            .unwrap();
+        let page = &flash.as_slice()[PAGE_SIZE as usize..2 * PAGE_SIZE as usize];
+        assert_eq!(&page[..2], &[3, 4]);
+        assert_eq!(&page[PAGE_SIZE as usize - 2..], &[1, 2]);
+    }
+
+    #[test]
+    fn misaligned_and_out_of_range() {
+        let mut flash = MockFlash::new(KNOWN_CHIPS[0]);
+        let size = flash.info().size;
+        assert_eq!(
+            flash.erase_blocking(FlashErase::Sector4K(0x100)),
+            Err(FlashError::Misaligned)
+        );
+        assert_eq!(
+            flash.erase_range_blocking(0, 0x1800),
+            Err(FlashError::Misaligned)
+        );
+        assert_eq!(
+            flash.program_blocking(size - 1, &[0, 0]),
+            Err(FlashError::OutOfRange)
+        );
+        assert_eq!(
+            NorFlash::write(&mut flash, 1, &[0; 256]),
+            Err(FlashError::Misaligned)
+        );
+    }
+
+    #[test]
+    fn addresses_near_the_end_dont_wrap() {
+        let mut flash = MockFlash::new(KNOWN_CHIPS[0]);
+        let address = u32::MAX - 1;
+        let mut data = [0; 4];
+        assert_eq!(flash.read(address, &mut data), Err(FlashError::OutOfRange));
+        assert_eq!(
+            flash.program_blocking(address, &data),
+            Err(FlashError::OutOfRange)
+        );
+        assert_eq!(
+            ReadNorFlash::read(&mut flash, address, &mut data),
+            Err(FlashError::OutOfRange)
+        );
+        assert_eq!(
+            flash.erase_blocking(FlashErase::Sector4K(u32::MAX - 0xFFF)),
+            Err(FlashError::OutOfRange)
+        );
+        assert!(flash.as_slice().iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn power_loss_stops_half_way() {
+        let mut flash = MockFlash::new(KNOWN_CHIPS[0]);
+        flash.program_blocking(0, &[0; 16]).unwrap();
+
+        flash.power_loss_after(100);
+        assert_eq!(
+            flash.erase_blocking(FlashErase::Sector4K(0)),
+            Err(FlashError::PowerLoss)
+        );
+        let mut byte = [0];
+        assert_eq!(flash.read(0, &mut byte), Err(FlashError::PowerLoss));
+        assert_eq!(
+            flash.program_blocking(0x1000, &[0]),
+            Err(FlashError::PowerLoss)
+        );
+
+        flash.power_cycle();
+        // Only the first 100 bytes were erased
+        assert!(flash.as_slice()[..16].iter().all(|&b| b == 0xFF));
+        flash.read(0x1000, &mut byte).unwrap();
+        assert_eq!(byte, [0xFF]);
+
+        flash.power_loss_after(3);
+        assert_eq!(
+            flash.program_blocking(0x2000, &[0; 8]),
+            Err(FlashError::PowerLoss)
+        );
+        flash.power_cycle();
+        assert_eq!(&flash.as_slice()[0x2000..0x2005], &[0, 0, 0, 0xFF, 0xFF]);
+        // Power cycling cancels a pending power loss
+        flash.power_loss_after(0);
+        flash.power_cycle();
+        flash.program_blocking(0x3000, &[0; 300]).unwrap();
+    }
+}
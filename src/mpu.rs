@@ -9,7 +9,8 @@
 /// # Panics
 ///
 /// Function will panic if `size` is not a power of 2. Function
-/// will panic if `size` is not at least 32 bytes.
+/// will panic if `size` is not at least 32 bytes. The `try_` variants
+/// return an `MpuError` instead.
 
 /// Refer to ARM®v7-M Architecture Reference Manual ARM DDI 0403
 /// Version E.b Section B3.5
@@ -45,13 +46,24 @@ pub fn enable(mpu: &mut cortex_m::peripheral::MPU, scb: &mut cortex_m::periphera
     }
 }
 
-fn log2minus1(sz: u32) -> u32 {
-    for x in 5..=31 {
-        if sz == (1 << x) {
-            return x - 1;
-        }
+/// Invalid MPU region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MpuError {
+    /// Region size must be a power of 2
+    NotPowerOfTwo,
+    /// Region size must be 32 bytes or more
+    TooSmall,
+}
+
+/// Region size field of RASR, log2(size) - 1
+fn log2minus1(sz: u32) -> Result<u32, MpuError> {
+    if sz & sz.wrapping_sub(1) != 0 || sz == 0 {
+        return Err(MpuError::NotPowerOfTwo);
+    }
+    if sz < 32 {
+        return Err(MpuError::TooSmall);
     }
-    panic!("Unknown memory region size!");
+    Ok(sz.trailing_zeros() - 1)
 }
 
 /// Setup MPU for dma
@@ -61,25 +73,25 @@ pub fn dma_init(
     location: *mut u32,
     size: usize,
 ) {
-    disable(mpu, scb);
+    try_dma_init(mpu, scb, location, size).expect("Invalid DMA memory region size");
+}
 
+/// Setup MPU for dma, returns an error instead of panicking on an invalid size
+pub fn try_dma_init(
+    mpu: &mut cortex_m::peripheral::MPU,
+    scb: &mut cortex_m::peripheral::SCB,
+    location: *mut u32,
+    size: usize,
+) -> Result<(), MpuError> {
     const REGION_NUMBER0: u32 = 0x00;
     const REGION_SHAREABLE: u32 = 0x01;
     const REGION_TEX: u32 = 0b001;
     const REGION_CB: u32 = 0b00;
 
-    assert_eq!(
-        size & (size - 1),
-        0,
-        "Memory region size must be a power of 2"
-    );
-    assert_eq!(
-        size & 0x1F,
-        0,
-        "Memory region size must be 32 bytes or more"
-    );
+    let region_size = log2minus1(size as u32)?;
+    disable(mpu, scb);
 
-    info!("Memory Size 0x{:x}", log2minus1(size as u32));
+    info!("Memory Size 0x{:x}", region_size);
 
     // Configure region 0
     //
@@ -92,12 +104,13 @@ pub fn dma_init(
                 | (REGION_TEX << 19)
                 | (REGION_SHAREABLE << 18)
                 | (REGION_CB << 16)
-                | (log2minus1(size as u32) << 1)
+                | (region_size << 1)
                 | REGION_ENABLE,
         );
     }
 
     enable(mpu, scb);
+    Ok(())
 }
 
 /// Setup MPU for the sdram
@@ -107,23 +120,23 @@ pub fn sdram_init(
     location: *mut u32,
     size: usize,
 ) {
-    disable(mpu, scb);
+    try_sdram_init(mpu, scb, location, size).expect("Invalid SDRAM memory region size");
+}
 
+/// Setup MPU for the sdram, returns an error instead of panicking on an invalid size
+pub fn try_sdram_init(
+    mpu: &mut cortex_m::peripheral::MPU,
+    scb: &mut cortex_m::peripheral::SCB,
+    location: *mut u32,
+    size: usize,
+) -> Result<(), MpuError> {
     // SDRAM
     const REGION_NUMBER1: u32 = 0x01;
 
-    assert_eq!(
-        size & (size - 1),
-        0,
-        "SDRAM memory region size must be a power of 2"
-    );
-    assert_eq!(
-        size & 0x1F,
-        0,
-        "SDRAM memory region size must be 32 bytes or more"
-    );
+    let region_size = log2minus1(size as u32)?;
+    disable(mpu, scb);
 
-    info!("SDRAM Memory Size 0x{:x}", log2minus1(size as u32));
+    info!("SDRAM Memory Size 0x{:x}", region_size);
 
     // Configure region 1
     //
@@ -132,8 +145,23 @@ pub fn sdram_init(
         mpu.rnr.write(REGION_NUMBER1);
         mpu.rbar.write((location as u32) & !0x1F);
         mpu.rasr
-            .write((REGION_FULL_ACCESS << 24) | (log2minus1(size as u32) << 1) | REGION_ENABLE);
+            .write((REGION_FULL_ACCESS << 24) | (region_size << 1) | REGION_ENABLE);
     }
 
     enable(mpu, scb);
+    Ok(())
+}
+
+/// Disable the MPU and clear the regions set up by `dma_init` and `sdram_init`
+pub fn deinit(mpu: &mut cortex_m::peripheral::MPU, scb: &mut cortex_m::peripheral::SCB) {
+    disable(mpu, scb);
+
+    // Regions 0 (DMA) and 1 (SDRAM)
+    unsafe {
+        for region in 0..=1 {
+            mpu.rnr.write(region);
+            mpu.rbar.write(0);
+            mpu.rasr.write(0);
+        }
+    }
 }
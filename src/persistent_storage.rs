@@ -0,0 +1,399 @@
+//! Wear-leveled storage for a settings struct in flash, similar to libDaisy's `PersistentStorage`.
+//!
+//! Every save writes a new copy of the settings into the next slot of a reserved range of
+//! sectors, together with a sequence number, a version and a CRC. On start up the newest valid
+//! copy is loaded, so a save that is interrupted by a power loss falls back to the previous copy.
+//! A sector is only erased once the writes move into it.
+//!
+//! # Example
+//!
+//! ```rust
+//! #[derive(Clone, Copy, PartialEq)]
+//! #[repr(C)]
+//! struct Settings {
+//!     gain: f32,
+//!     preset: u32,
+//! }
+//! unsafe impl StorageData for Settings {}
+//!
+//! // Last 64K of the flash, 16 sectors
+//! let mut storage =
+//!     PersistentStorage::new(&mut flash, 0x7F_0000, 0x1_0000, 1, Settings { gain: 1.0, preset: 0 })
+//!         .unwrap();
+//! storage.settings_mut().gain = 0.5;
+//! storage.save(&mut flash).unwrap();
+//! ```
+
+use core::mem::size_of;
+
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::crc::Crc32;
+
+// "SETT"
+const MAGIC: u32 = 0x5345_5454;
+const HEADER_SIZE: usize = 5 * 4;
+// Slots are written through a buffer of this size, it must be a multiple of the write size
+const BUFFER_SIZE: usize = 256;
+
+/// Data that can be stored in flash as raw bytes.
+///
+/// # Safety
+/// Every byte pattern of `size_of::<Self>()` must be a valid value and the type must not contain
+/// padding, e.g. a `#[repr(C)]` struct of integers and floats. `bool`, enums and references are
+/// not allowed.
+pub unsafe trait StorageData: Copy + PartialEq {}
+
+/// Where the current settings came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageState {
+    /// No valid copy was found in flash, the defaults are in use
+    Factory,
+    /// The settings were loaded from or saved to flash
+    User,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Header {
+    magic: u32,
+    sequence: u32,
+    version: u32,
+    length: u32,
+    crc: u32,
+}
+
+impl Header {
+    fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        let words = [
+            self.magic,
+            self.sequence,
+            self.version,
+            self.length,
+            self.crc,
+        ];
+        for (chunk, word) in bytes.chunks_mut(4).zip(words.iter()) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; HEADER_SIZE]) -> Self {
+        let word =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        Self {
+            magic: word(0),
+            sequence: word(4),
+            version: word(8),
+            length: word(12),
+            crc: word(16),
+        }
+    }
+
+    /// CRC of everything but the magic and the CRC itself, followed by the data
+    fn crc(&self, data: &[u8]) -> u32 {
+        let bytes = self.to_bytes();
+        let mut crc = Crc32::new();
+        crc.update(&bytes[4..16]);
+        crc.update(data);
+        crc.finish()
+    }
+}
+
+fn as_bytes<T: StorageData>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+fn as_bytes_mut<T: StorageData>(value: &mut T) -> &mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(value as *mut T as *mut u8, size_of::<T>()) }
+}
+
+/// Versioned, CRC checked copies of `T` rotated across a range of flash sectors.
+pub struct PersistentStorage<T: StorageData> {
+    address: u32,
+    version: u32,
+    slot_size: u32,
+    erase_size: u32,
+    slots: u32,
+    defaults: T,
+    settings: T,
+    saved: T,
+    state: StorageState,
+    /// Slot and sequence number of the newest valid copy
+    latest: Option<(u32, u32)>,
+    /// Next slot to write
+    next: u32,
+}
+
+impl<T: StorageData> PersistentStorage<T> {
+    /// Use the `size` bytes of flash starting at `address` to store `T` and load the newest copy
+    /// that matches `version`. Otherwise `defaults` are used.
+    ///
+    /// Change `version` whenever the layout of `T` changes, older copies are then ignored.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `address` and `size` are not multiples of the erase size, if the range holds
+    /// less than two sectors or if `T` does not fit in a sector.
+    pub fn new<F: NorFlash>(
+        flash: &mut F,
+        address: u32,
+        size: u32,
+        version: u32,
+        defaults: T,
+    ) -> Result<Self, F::Error> {
+        let erase_size = F::ERASE_SIZE as u32;
+        let slot_size = (HEADER_SIZE + size_of::<T>()).next_multiple_of(F::WRITE_SIZE) as u32;
+
+        assert_eq!(F::READ_SIZE, 1, "Flash must support reads of any size");
+        assert_eq!(BUFFER_SIZE % F::WRITE_SIZE, 0, "Unsupported write size");
+        assert_eq!(address % erase_size, 0, "Address must be sector aligned");
+        assert_eq!(
+            size % erase_size,
+            0,
+            "Size must be a multiple of the sector size"
+        );
+        assert!(size >= 2 * erase_size, "At least two sectors are required");
+        assert!(slot_size <= erase_size, "Data does not fit in a sector");
+
+        let mut storage = Self {
+            address,
+            version,
+            slot_size,
+            erase_size,
+            slots: (size / erase_size) * (erase_size / slot_size),
+            defaults,
+            settings: defaults,
+            saved: defaults,
+            state: StorageState::Factory,
+            latest: None,
+            next: 0,
+        };
+        storage.load(flash)?;
+        Ok(storage)
+    }
+
+    /// Where the current settings came from
+    pub fn state(&self) -> StorageState {
+        self.state
+    }
+
+    /// Get the current settings
+    pub fn settings(&self) -> &T {
+        &self.settings
+    }
+
+    /// Get the current settings for modification, call `save` to store them.
+    pub fn settings_mut(&mut self) -> &mut T {
+        &mut self.settings
+    }
+
+    /// Write the current settings to flash, if they changed since the last save or load.
+    pub fn save<F: NorFlash>(&mut self, flash: &mut F) -> Result<(), F::Error> {
+        if self.latest.is_some() && self.settings == self.saved {
+            return Ok(());
+        }
+        self.write(flash)
+    }
+
+    /// Go back to the defaults and save them.
+    pub fn restore_defaults<F: NorFlash>(&mut self, flash: &mut F) -> Result<(), F::Error> {
+        self.settings = self.defaults;
+        self.write(flash)
+    }
+
+    /// Write the current settings to the next slot
+    fn write<F: NorFlash>(&mut self, flash: &mut F) -> Result<(), F::Error> {
+        let slot = self.next;
+        let address = self.slot_address(slot);
+        if self.index_in_sector(slot) == 0 {
+            flash.erase(address, address + self.erase_size)?;
+        }
+
+        let sequence = self
+            .latest
+            .map_or(0, |(_, sequence)| sequence.wrapping_add(1));
+        let data = as_bytes(&self.settings);
+        let mut header = Header {
+            magic: MAGIC,
+            sequence,
+            version: self.version,
+            length: data.len() as u32,
+            crc: 0,
+        };
+        header.crc = header.crc(data);
+        let header = header.to_bytes();
+
+        let mut bytes = header.iter().chain(data.iter());
+        let mut buffer = [0xFF; BUFFER_SIZE];
+        let mut offset = 0;
+        loop {
+            let mut len: usize = 0;
+            for (dest, src) in buffer.iter_mut().zip(&mut bytes) {
+                *dest = *src;
+                len += 1;
+            }
+            if len == 0 {
+                break;
+            }
+            let padded = len.next_multiple_of(F::WRITE_SIZE);
+            buffer[len..padded].fill(0xFF);
+            flash.write(address + offset, &buffer[..padded])?;
+            offset += padded as u32;
+        }
+
+        self.saved = self.settings;
+        self.latest = Some((slot, sequence));
+        self.next = (slot + 1) % self.slots;
+        self.state = StorageState::User;
+        Ok(())
+    }
+
+    fn index_in_sector(&self, slot: u32) -> u32 {
+        slot % (self.erase_size / self.slot_size)
+    }
+
+    fn slot_address(&self, slot: u32) -> u32 {
+        let sector = slot / (self.erase_size / self.slot_size);
+        self.address + sector * self.erase_size + self.index_in_sector(slot) * self.slot_size
+    }
+
+    fn read_header<F: NorFlash>(&self, flash: &mut F, slot: u32) -> Result<Header, F::Error> {
+        let mut bytes = [0; HEADER_SIZE];
+        flash.read(self.slot_address(slot), &mut bytes)?;
+        Ok(Header::from_bytes(&bytes))
+    }
+
+    /// Find the newest valid copy and the next slot to write
+    fn load<F: NorFlash>(&mut self, flash: &mut F) -> Result<(), F::Error> {
+        for slot in 0..self.slots {
+            let header = self.read_header(flash, slot)?;
+            if header.magic != MAGIC
+                || header.version != self.version
+                || header.length != size_of::<T>() as u32
+            {
+                continue;
+            }
+            if let Some((_, sequence)) = self.latest {
+                if (header.sequence.wrapping_sub(sequence) as i32) <= 0 {
+                    continue;
+                }
+            }
+
+            let mut value = self.defaults;
+            flash.read(
+                self.slot_address(slot) + HEADER_SIZE as u32,
+                as_bytes_mut(&mut value),
+            )?;
+            if header.crc(as_bytes(&value)) == header.crc {
+                self.settings = value;
+                self.saved = value;
+                self.latest = Some((slot, header.sequence));
+                self.state = StorageState::User;
+            }
+        }
+
+        // Skip partially written slots after the newest copy, a new sector is erased first
+        self.next = self.latest.map_or(0, |(slot, _)| (slot + 1) % self.slots);
+        while self.index_in_sector(self.next) != 0 {
+            if self.read_header(flash, self.next)?.to_bytes() == [0xFF; HEADER_SIZE] {
+                break;
+            }
+            self.next = (self.next + 1) % self.slots;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "mock-flash"))]
+mod tests {
+    use super::*;
+    use crate::flash::KNOWN_CHIPS;
+    use crate::mock_flash::MockFlash;
+
+    const ADDRESS: u32 = 0x1_0000;
+    const SIZE: u32 = 0x2000;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[repr(C)]
+    struct Settings {
+        gain: f32,
+        preset: u32,
+    }
+
+    unsafe impl StorageData for Settings {}
+
+    const DEFAULTS: Settings = Settings {
+        gain: 1.0,
+        preset: 0,
+    };
+
+    fn storage(flash: &mut MockFlash, version: u32) -> PersistentStorage<Settings> {
+        PersistentStorage::new(flash, ADDRESS, SIZE, version, DEFAULTS).unwrap()
+    }
+
+    #[test]
+    fn blank_flash_uses_defaults() {
+        let mut flash = MockFlash::new(KNOWN_CHIPS[0]);
+        let storage = storage(&mut flash, 1);
+        assert_eq!(storage.state(), StorageState::Factory);
+        assert_eq!(*storage.settings(), DEFAULTS);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut flash = MockFlash::new(KNOWN_CHIPS[0]);
+        let mut storage = storage(&mut flash, 1);
+        // More saves than fit in the two sectors, so the writes wrap around
+        for preset in 0..40 {
+            storage.settings_mut().preset = preset;
+            storage.save(&mut flash).unwrap();
+        }
+        assert_eq!(storage.state(), StorageState::User);
+
+        let loaded = self::storage(&mut flash, 1);
+        assert_eq!(loaded.state(), StorageState::User);
+        assert_eq!(loaded.settings().preset, 39);
+
+        // Another version ignores the saved copies
+        assert_eq!(self::storage(&mut flash, 2).state(), StorageState::Factory);
+    }
+
+    #[test]
+    fn corrupted_crc_falls_back_to_previous_copy() {
+        let mut flash = MockFlash::new(KNOWN_CHIPS[0]);
+        let mut storage = storage(&mut flash, 1);
+        storage.settings_mut().preset = 1;
+        storage.save(&mut flash).unwrap();
+        storage.settings_mut().preset = 2;
+        storage.save(&mut flash).unwrap();
+
+        // Clear a bit of the newest copy's data, its CRC no longer matches
+        let (slot, _) = storage.latest.unwrap();
+        let address = storage.slot_address(slot) + HEADER_SIZE as u32 + 4;
+        flash.program_blocking(address, &[0xFD]).unwrap();
+
+        let loaded = self::storage(&mut flash, 1);
+        assert_eq!(loaded.settings().preset, 1);
+    }
+
+    #[test]
+    fn power_loss_during_save_keeps_previous_copy() {
+        let mut flash = MockFlash::new(KNOWN_CHIPS[0]);
+        let mut storage = storage(&mut flash, 1);
+        storage.settings_mut().preset = 1;
+        storage.save(&mut flash).unwrap();
+
+        storage.settings_mut().preset = 2;
+        flash.power_loss_after(HEADER_SIZE + 2);
+        assert!(storage.save(&mut flash).is_err());
+        flash.power_cycle();
+
+        let mut loaded = self::storage(&mut flash, 1);
+        assert_eq!(loaded.settings().preset, 1);
+        // The torn slot is skipped by the next save
+        loaded.settings_mut().preset = 3;
+        loaded.save(&mut flash).unwrap();
+        assert_eq!(self::storage(&mut flash, 1).settings().preset, 3);
+    }
+}
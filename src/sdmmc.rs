@@ -1,11 +1,82 @@
+//! SD card access over SDMMC1.
+//!
+//! [init] sets up the pins and returns the HAL `Sdmmc` driver. [SdCard] wraps it with card
+//! initialisation and a FAT filesystem from `embedded-sdmmc`:
+//!
+//! ```rust
+//! let sd = sdmmc::init(daisy1, daisy2, daisy3, daisy4, daisy5, daisy6, sdmmc1, rec, clocks);
+//! let mut card = SdCard::new(sd, 50.MHz(), FixedTime::default());
+//! card.mount()?;
+//! card.read_dir("/", |entry| info!("{} {}", entry.name, entry.size))?;
+//! let mut file = card.open("LOGS/BOOT.TXT", Mode::ReadWriteCreateOrAppend)?;
+//! card.write(&mut file, b"hello\n")?;
+//! card.close(file)?;
+//! ```
+//!
+//! Remarks:
+//! - Paths are `/` separated and relative to the root directory, names are 8.3 short names.
+//! - Card errors, e.g. from removing the card, are returned as [SdError::Card]. Close any open
+//!   files and [mount](SdCard#method.mount) again once a card is back.
+//! - [init_1bit] only uses CLK, CMD and D0, which leaves daisy1 - daisy3 free.
+//! - With a [CardDetect] switch, [poll](SdCard#method.poll) reports insert and remove events and
+//!   mounts a newly inserted card.
+//! - [SdBlockDevice] also moves raw blocks with the SDMMC internal DMA (IDMA) while the CPU keeps
+//!   running, see [start_read](SdBlockDevice#method.start_read) and
+//!   [read_blocks](SdBlockDevice#method.read_blocks).
+
+use core::{
+    cell::{Cell, RefCell},
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use cortex_m::interrupt::{self, Mutex};
+
+use debouncr::{debounce_4, Debouncer, Edge, Repeat4};
+
+use embedded_sdmmc::{
+    Block, BlockCount, BlockDevice, BlockIdx, Directory, TimeSource, Timestamp, Volume, VolumeIdx,
+    VolumeManager,
+};
+pub use embedded_sdmmc::{DirEntry, File, Mode};
 use stm32h7xx_hal::{
     self as hal,
-    gpio::{self, Analog, Speed},
+    gpio::{self, Analog, ErasedPin, Input, Speed},
+    nb,
     prelude::*,
-    sdmmc::SdmmcPeripheral,
-    stm32,
+    sdmmc::{self as hal_sdmmc, Sdmmc, SdmmcPeripheral},
+    stm32::{self, SDMMC1},
+    time::Hertz,
 };
 
+/// Bus frequencies tried by [SdBlockDevice::init], fastest first
+const FREQUENCIES: [Hertz; 4] = [
+    Hertz::from_raw(50_000_000),
+    Hertz::from_raw(25_000_000),
+    Hertz::from_raw(12_500_000),
+    Hertz::from_raw(6_250_000),
+];
+/// Attempts at each frequency before trying a slower one
+const INIT_RETRIES: usize = 3;
+
+// SD commands used for IDMA transfers
+const CMD_STOP_TRANSMISSION: u8 = 12;
+const CMD_READ_MULTIPLE_BLOCK: u8 = 18;
+const CMD_WRITE_MULTIPLE_BLOCK: u8 = 25;
+// All flags cleared through SDMMC_ICR
+const ICR_ALL: u32 = 0x1FE0_0FFF;
+// Data length register is 25 bits
+const MAX_TRANSFER_SIZE: usize = (1 << 25) - Block::LEN;
+// Memory the IDMA can reach: AXI SRAM (D1) and SDRAM
+const AXI_SRAM: core::ops::Range<u32> = 0x2400_0000..0x2408_0000;
+const SDRAM: core::ops::Range<u32> = 0xC000_0000..0xC400_0000;
+// AXI SRAM is cached, its buffers must cover whole cache lines
+const CACHE_LINE: u32 = 32;
+
+/// Woken by [on_interrupt] when an async transfer needs attention
+static WAKER: Mutex<RefCell<Option<Waker>>> = Mutex::new(RefCell::new(None));
+
 /// Boiler plate to create SDMMC1
 pub fn init<P: SdmmcPeripheral>(
     daisy1: gpio::gpioc::PC11<Analog>,
@@ -58,3 +129,917 @@ pub fn init<P: SdmmcPeripheral>(
     // Create SDMMC
     device.sdmmc((clk, cmd, d0, d1, d2, d3), sdmmc1, clocks)
 }
+
+/// Boiler plate to create SDMMC1 with a 1 bit bus, only CLK, CMD and D0 are used
+pub fn init_1bit<P: SdmmcPeripheral>(
+    daisy4: gpio::gpioc::PC8<Analog>,
+    daisy5: gpio::gpiod::PD2<Analog>,
+    daisy6: gpio::gpioc::PC12<Analog>,
+
+    device: stm32::SDMMC1,
+    sdmmc1: hal::rcc::rec::Sdmmc1,
+    clocks: &hal::rcc::CoreClocks,
+) -> hal::sdmmc::Sdmmc<stm32::SDMMC1, P> {
+    // SDMMC pins
+    let clk = daisy6
+        .into_alternate()
+        .internal_pull_up(false)
+        .speed(Speed::VeryHigh);
+    let cmd = daisy5
+        .into_alternate()
+        .internal_pull_up(true)
+        .speed(Speed::VeryHigh);
+    let d0 = daisy4
+        .into_alternate()
+        .internal_pull_up(true)
+        .speed(Speed::VeryHigh);
+
+    // Create SDMMC
+    device.sdmmc((clk, cmd, d0), sdmmc1, clocks)
+}
+
+/// Errors from [SdCard] and [SdBlockDevice]
+#[derive(Debug)]
+pub enum SdError {
+    /// The card failed or is missing, e.g. it was removed
+    Card(hal_sdmmc::Error),
+    /// Filesystem error, e.g. a file was not found
+    Fat(embedded_sdmmc::Error<hal_sdmmc::Error>),
+    /// No volume is mounted
+    NotMounted,
+    /// An IDMA transfer is in progress
+    Busy,
+    /// No IDMA transfer was started
+    NoTransfer,
+    /// The buffer can't be used for IDMA, it must be a multiple of 512 bytes and either 32 byte
+    /// aligned in AXI SRAM or word aligned in SDRAM
+    InvalidBuffer,
+}
+
+impl From<hal_sdmmc::Error> for SdError {
+    fn from(e: hal_sdmmc::Error) -> Self {
+        SdError::Card(e)
+    }
+}
+
+impl From<embedded_sdmmc::Error<hal_sdmmc::Error>> for SdError {
+    fn from(e: embedded_sdmmc::Error<hal_sdmmc::Error>) -> Self {
+        match e {
+            embedded_sdmmc::Error::DeviceError(e) => SdError::Card(e),
+            e => SdError::Fat(e),
+        }
+    }
+}
+
+pub type SdResult<T> = Result<T, SdError>;
+
+/// Timestamp source for cards without a real time clock, every file gets the same time
+#[derive(Clone, Copy, Debug)]
+pub struct FixedTime(pub Timestamp);
+
+impl Default for FixedTime {
+    /// 2022-01-01 00:00:00
+    fn default() -> Self {
+        FixedTime(Timestamp {
+            year_since_1970: 52,
+            zero_indexed_month: 0,
+            zero_indexed_day: 0,
+            hours: 0,
+            minutes: 0,
+            seconds: 0,
+        })
+    }
+}
+
+impl TimeSource for FixedTime {
+    fn get_timestamp(&self) -> Timestamp {
+        self.0
+    }
+}
+
+/// A change of the card detect switch
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CardEvent {
+    Inserted,
+    Removed,
+}
+
+/// Debounced card detect switch.
+/// [Debouncr](https://github.com/dbrgn/debouncr/) with a 4 sample array is used for debouncing.
+pub struct CardDetect {
+    pin: ErasedPin<Input>,
+    active_low: bool,
+    state: Debouncer<u8, Repeat4>,
+}
+
+impl CardDetect {
+    /// Create a card detect from an input pin, `active_low` if the pin reads low with a card in.
+    /// A card that is already in is reported as inserted by the first few updates.
+    pub fn new(pin: ErasedPin<Input>, active_low: bool) -> Self {
+        Self {
+            pin,
+            active_low,
+            state: debounce_4(false),
+        }
+    }
+
+    /// Read the switch, this should be called on a timer
+    pub fn update(&mut self) -> Option<CardEvent> {
+        let inserted = self.pin.is_low() == self.active_low;
+        match self.state.update(inserted)? {
+            Edge::Rising => Some(CardEvent::Inserted),
+            Edge::Falling => Some(CardEvent::Removed),
+        }
+    }
+
+    /// Whether a card is in, after debouncing
+    pub fn is_inserted(&self) -> bool {
+        self.state.is_high()
+    }
+
+    /// Give back the pin
+    pub fn free(self) -> ErasedPin<Input> {
+        self.pin
+    }
+}
+
+/// An IDMA transfer in progress
+struct Transfer {
+    /// Only set for transfers started with `start_read` or `start_write`
+    buffer: Option<&'static mut [u8]>,
+    write: bool,
+    /// Data is done, waiting for the card to finish programming
+    stopping: bool,
+}
+
+impl Transfer {
+    /// Give back the buffer of a completed transfer, dropping stale cache lines after a read
+    fn finish(self) -> &'static mut [u8] {
+        let buffer = self.buffer.unwrap_or_default();
+        if !self.write && AXI_SRAM.contains(&(buffer.as_ptr() as u32)) {
+            let mut core = unsafe { cortex_m::Peripherals::steal() };
+            // SAFETY: the buffer covers whole cache lines, checked by `check_buffer`
+            unsafe { core.SCB.invalidate_dcache_by_slice(buffer) };
+        }
+        buffer
+    }
+}
+
+/// Check that the IDMA can reach a buffer of `len` bytes at `address` and that it can be
+/// invalidated without touching other data
+fn check_buffer(address: u32, len: usize) -> SdResult<()> {
+    if len == 0 || len > MAX_TRANSFER_SIZE || len & (Block::LEN - 1) != 0 {
+        return Err(SdError::InvalidBuffer);
+    }
+    let last = address
+        .checked_add(len as u32 - 1)
+        .ok_or(SdError::InvalidBuffer)?;
+    let valid = if AXI_SRAM.contains(&address) {
+        AXI_SRAM.contains(&last) && address & (CACHE_LINE - 1) == 0
+    } else {
+        SDRAM.contains(&address) && SDRAM.contains(&last) && address & 0b11 == 0
+    };
+    if !valid {
+        return Err(SdError::InvalidBuffer);
+    }
+    Ok(())
+}
+
+/// SD card as an `embedded-sdmmc` block device.
+///
+/// The card is initialised again on the first access after an error or an insert.
+///
+/// Multiple blocks can also be moved with the IDMA, either with interrupts:
+///
+/// ```rust
+/// sd.listen();
+/// sd.start_read(block, buffer).ok();
+/// // In the SDMMC1 interrupt
+/// if let Ok(buffer) = sd.poll_transfer() { ... }
+/// ```
+///
+/// or as a future, with [on_interrupt] called from the `SDMMC1` interrupt:
+///
+/// ```rust
+/// let buffer = sd.read_blocks(block, buffer).await.map_err(|(e, _)| e)?;
+/// ```
+///
+/// Remarks:
+/// - Buffers must be a multiple of 512 bytes and in AXI SRAM or SDRAM, the IDMA can't reach DTCM
+///   or D2 SRAM. AXI SRAM is cached, so buffers there must be 32 byte aligned, e.g. with
+///   `#[repr(align(32))]`. They are cleaned from the D-cache before a write and invalidated after a
+///   read.
+/// - Block device accesses fail with `InvalidConfiguration` while a transfer is in progress.
+/// - Dropping a transfer future aborts the transfer. Forgetting it with `mem::forget` leaves the
+///   buffer with the transfer, [abort](SdBlockDevice#method.abort) gives it back.
+pub struct SdBlockDevice {
+    sdmmc: RefCell<Sdmmc<SDMMC1, hal_sdmmc::SdCard>>,
+    max_frequency: Hertz,
+    frequency: Cell<Option<Hertz>>,
+    detect: Option<CardDetect>,
+    transfer: Option<Transfer>,
+    listening: bool,
+}
+
+impl SdBlockDevice {
+    /// Wrap the driver from [init]. The card is not initialised until
+    /// [init](SdBlockDevice#method.init), which uses at most `max_frequency`.
+    pub fn new(sdmmc: Sdmmc<SDMMC1, hal_sdmmc::SdCard>, max_frequency: Hertz) -> Self {
+        Self {
+            sdmmc: RefCell::new(sdmmc),
+            max_frequency,
+            frequency: Cell::new(None),
+            detect: None,
+            transfer: None,
+            listening: false,
+        }
+    }
+
+    /// Use a card detect switch, accesses fail with `NoCard` while no card is in
+    pub fn set_card_detect(&mut self, detect: CardDetect) {
+        self.detect = Some(detect);
+    }
+
+    /// Take back the card detect switch
+    pub fn take_card_detect(&mut self) -> Option<CardDetect> {
+        self.detect.take()
+    }
+
+    /// Whether a card is in, always true without a card detect switch
+    pub fn is_inserted(&self) -> bool {
+        self.detect.as_ref().is_none_or(CardDetect::is_inserted)
+    }
+
+    /// Update the card detect switch, this should be called on a timer.
+    ///
+    /// A newly inserted card is initialised right away, the result is available from
+    /// [frequency](SdBlockDevice#method.frequency).
+    pub fn poll(&mut self) -> Option<CardEvent> {
+        let event = self.detect.as_mut()?.update()?;
+        self.frequency.set(None);
+        if event == CardEvent::Inserted {
+            self.init().ok();
+        }
+        Some(event)
+    }
+
+    /// Initialise the card and return the bus frequency it runs at.
+    ///
+    /// Starting at the highest frequency not above the maximum, each is tried a few times and
+    /// checked with a read of the first block before falling back to a slower one.
+    pub fn init(&mut self) -> SdResult<Hertz> {
+        Ok(self.init_card(&mut self.sdmmc.borrow_mut())?)
+    }
+
+    /// Bus frequency of an initialised card
+    pub fn frequency(&self) -> Option<Hertz> {
+        self.frequency.get()
+    }
+
+    /// Card information, e.g. the size, or `NoCard` before init
+    pub fn card(&self) -> SdResult<hal_sdmmc::SdCard> {
+        Ok(*self.sdmmc.borrow().card()?)
+    }
+
+    /// Give back the driver, aborting a transfer in progress
+    pub fn free(mut self) -> Sdmmc<SDMMC1, hal_sdmmc::SdCard> {
+        self.abort();
+        self.sdmmc.into_inner()
+    }
+
+    /// Raise the `SDMMC1` interrupt when a transfer started with
+    /// [start_read](SdBlockDevice#method.start_read) or
+    /// [start_write](SdBlockDevice#method.start_write) needs to be polled
+    pub fn listen(&mut self) {
+        self.listening = true;
+    }
+
+    /// Stop raising the `SDMMC1` interrupt
+    pub fn unlisten(&mut self) {
+        self.listening = false;
+        self.sdmmc.get_mut().inner().maskr.reset();
+    }
+
+    /// True while a transfer is in progress or its buffer hasn't been taken back
+    pub fn is_busy(&self) -> bool {
+        self.transfer.is_some()
+    }
+
+    /// Start reading `buffer.len() / 512` blocks from `block` into `buffer`.
+    ///
+    /// The buffer is given back with the error if a transfer is already in progress, the card
+    /// isn't ready or the buffer can't be used.
+    pub fn start_read(
+        &mut self,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (SdError, &'static mut [u8])> {
+        match self.start_transfer(block, buffer.as_ptr() as u32, buffer.len(), false) {
+            Ok(()) => {
+                self.transfer.as_mut().unwrap().buffer = Some(buffer);
+                Ok(())
+            }
+            Err(e) => Err((e, buffer)),
+        }
+    }
+
+    /// Start writing `buffer` to the card from `block`, see
+    /// [start_read](SdBlockDevice#method.start_read)
+    pub fn start_write(
+        &mut self,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (SdError, &'static mut [u8])> {
+        match self.start_transfer(block, buffer.as_ptr() as u32, buffer.len(), true) {
+            Ok(()) => {
+                self.transfer.as_mut().unwrap().buffer = Some(buffer);
+                Ok(())
+            }
+            Err(e) => Err((e, buffer)),
+        }
+    }
+
+    /// Check the transfer, returns the buffer once it is done.
+    ///
+    /// After an error the buffer can be taken back with [abort](SdBlockDevice#method.abort).
+    pub fn poll_transfer(&mut self) -> nb::Result<&'static mut [u8], SdError> {
+        self.poll_inner()?;
+        let transfer = self.transfer.take().ok_or(SdError::NoTransfer)?;
+        Ok(transfer.finish())
+    }
+
+    /// Stop the transfer and give back the buffer, if any. Its contents are undefined.
+    pub fn abort(&mut self) -> Option<&'static mut [u8]> {
+        let transfer = self.transfer.take()?;
+        let regs = self.sdmmc.get_mut().inner();
+        regs.maskr.reset();
+        // CMD12 also stops the data path
+        command(regs, CMD_STOP_TRANSMISSION, 0, false).ok();
+        let mut timeout: u32 = 0xFFFF;
+        while regs.star.read().dpsmact().bit_is_set() && timeout > 0 {
+            timeout -= 1;
+        }
+        Self::end_data(regs);
+        transfer.buffer
+    }
+
+    /// Read `buffer.len() / 512` blocks from `block` into `buffer` with the IDMA, the future
+    /// completes from the `SDMMC1` interrupt, which must call [on_interrupt].
+    ///
+    /// The buffer is given back when the transfer is done or failed, like
+    /// [start_read](SdBlockDevice#method.start_read) it has to be `'static` as the IDMA keeps
+    /// writing to it if the future is forgotten.
+    pub async fn read_blocks(
+        &mut self,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<&'static mut [u8], (SdError, &'static mut [u8])> {
+        self.start_read(block, buffer)?;
+        TransferFuture(self).await
+    }
+
+    /// Write `buffer` to the card from `block` with the IDMA, see
+    /// [read_blocks](SdBlockDevice#method.read_blocks)
+    pub async fn write_blocks(
+        &mut self,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<&'static mut [u8], (SdError, &'static mut [u8])> {
+        self.start_write(block, buffer)?;
+        TransferFuture(self).await
+    }
+
+    fn start_transfer(
+        &mut self,
+        block: u32,
+        address: u32,
+        len: usize,
+        write: bool,
+    ) -> SdResult<()> {
+        if self.transfer.is_some() {
+            return Err(SdError::Busy);
+        }
+        check_buffer(address, len)?;
+        let mut sdmmc = self.sdmmc.borrow_mut();
+        self.ready(&mut sdmmc)?;
+
+        // SDSC cards are addressed in bytes, the others in blocks
+        let address_arg = if sdmmc.card()?.ocr.high_capacity() {
+            block
+        } else {
+            block * Block::LEN as u32
+        };
+
+        let regs = sdmmc.inner();
+        // Command and data state machines must be idle
+        let mut timeout: u32 = 0xFFFF;
+        while regs.star.read().dpsmact().bit_is_set() || regs.star.read().cpsmact().bit_is_set() {
+            if timeout == 0 {
+                self.frequency.set(None);
+                return Err(SdError::Card(hal_sdmmc::Error::SoftwareTimeout));
+            }
+            timeout -= 1;
+        }
+        regs.icr.write(|w| unsafe { w.bits(ICR_ALL) });
+
+        if AXI_SRAM.contains(&address) {
+            let mut core = unsafe { cortex_m::Peripherals::steal() };
+            if write {
+                // The IDMA reads the SRAM, not the cache
+                core.SCB.clean_dcache_by_address(address as usize, len);
+            } else {
+                // Dirty lines evicted during the transfer would overwrite the data
+                core.SCB
+                    .clean_invalidate_dcache_by_address(address as usize, len);
+            }
+        }
+
+        // Data timeout, in bus cycles
+        regs.dtimer
+            .write(|w| unsafe { w.datatime().bits(5_000_000) });
+        regs.dlenr
+            .write(|w| unsafe { w.datalength().bits(len as u32) });
+        regs.idmabase0r
+            .write(|w| unsafe { w.idmabase0().bits(address) });
+        regs.idmactrlr
+            .write(|w| w.idmaen().set_bit().idmabmode().clear_bit());
+        // 512 byte blocks, the command starts the data path
+        regs.dctrl
+            .write(|w| unsafe { w.dblocksize().bits(9).dtdir().bit(!write) });
+
+        let index = if write {
+            CMD_WRITE_MULTIPLE_BLOCK
+        } else {
+            CMD_READ_MULTIPLE_BLOCK
+        };
+        if let Err(e) = command(regs, index, address_arg, true) {
+            Self::end_data(regs);
+            self.frequency.set(None);
+            return Err(e.into());
+        }
+        if self.listening {
+            Self::unmask(regs, false);
+        }
+        drop(sdmmc);
+        self.transfer = Some(Transfer {
+            buffer: None,
+            write,
+            stopping: false,
+        });
+        Ok(())
+    }
+
+    fn poll_inner(&mut self) -> nb::Result<(), SdError> {
+        let transfer = self.transfer.as_mut().ok_or(SdError::NoTransfer)?;
+        let regs = self.sdmmc.get_mut().inner();
+        let status = regs.star.read();
+
+        if transfer.stopping {
+            if status.busyd0().bit_is_set() {
+                return Err(nb::Error::WouldBlock);
+            }
+            regs.icr.write(|w| w.busyd0endc().set_bit());
+            regs.maskr.reset();
+            return Ok(());
+        }
+
+        let error = if status.dcrcfail().bit() {
+            Some(hal_sdmmc::Error::DataCrcFail)
+        } else if status.rxoverr().bit() {
+            Some(hal_sdmmc::Error::RxOverFlow)
+        } else if status.txunderr().bit() {
+            Some(hal_sdmmc::Error::TxUnderFlow)
+        } else if status.dtimeout().bit() || status.idmate().bit() {
+            Some(hal_sdmmc::Error::Timeout)
+        } else {
+            None
+        };
+        if let Some(e) = error {
+            regs.maskr.reset();
+            command(regs, CMD_STOP_TRANSMISSION, 0, false).ok();
+            Self::end_data(regs);
+            // The card might be gone, initialise it again before the next access
+            self.frequency.set(None);
+            return Err(nb::Error::Other(SdError::Card(e)));
+        }
+        if status.dataend().bit_is_clear() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        Self::end_data(regs);
+        if let Err(e) = command(regs, CMD_STOP_TRANSMISSION, 0, false) {
+            regs.maskr.reset();
+            self.frequency.set(None);
+            return Err(nb::Error::Other(SdError::Card(e)));
+        }
+        // After a write the card holds D0 low until it has programmed the data
+        if transfer.write && regs.star.read().busyd0().bit_is_set() {
+            transfer.stopping = true;
+            if self.listening {
+                Self::unmask(regs, true);
+            }
+            return Err(nb::Error::WouldBlock);
+        }
+        regs.maskr.reset();
+        Ok(())
+    }
+
+    /// Turn the IDMA and data path off and clear the flags, the HAL driver works again after this
+    fn end_data(regs: &SDMMC1) {
+        regs.idmactrlr.reset();
+        regs.dctrl.reset();
+        regs.icr.write(|w| unsafe { w.bits(ICR_ALL) });
+    }
+
+    fn unmask(regs: &SDMMC1, stopping: bool) {
+        if stopping {
+            regs.maskr.write(|w| w.busyd0endie().set_bit());
+        } else {
+            regs.maskr.write(|w| {
+                w.dataendie()
+                    .set_bit()
+                    .dcrcfailie()
+                    .set_bit()
+                    .dtimeoutie()
+                    .set_bit()
+                    .rxoverrie()
+                    .set_bit()
+                    .txunderrie()
+                    .set_bit()
+            });
+        }
+    }
+
+    fn init_card(
+        &self,
+        sdmmc: &mut Sdmmc<SDMMC1, hal_sdmmc::SdCard>,
+    ) -> Result<Hertz, hal_sdmmc::Error> {
+        self.frequency.set(None);
+        if !self.is_inserted() {
+            return Err(hal_sdmmc::Error::NoCard);
+        }
+        let mut error = hal_sdmmc::Error::BadClock;
+        for &frequency in FREQUENCIES.iter().filter(|f| **f <= self.max_frequency) {
+            for _ in 0..INIT_RETRIES {
+                let mut block = [0; Block::LEN];
+                match sdmmc
+                    .init(frequency)
+                    .and_then(|_| sdmmc.read_block(0, &mut block))
+                {
+                    Ok(()) => {
+                        self.frequency.set(Some(frequency));
+                        return Ok(frequency);
+                    }
+                    // Nothing to retry without a card
+                    Err(hal_sdmmc::Error::NoCard) => return Err(hal_sdmmc::Error::NoCard),
+                    Err(e) => error = e,
+                }
+            }
+        }
+        Err(error)
+    }
+
+    /// Initialise the card if it isn't, e.g. after an error or an insert
+    fn ready(&self, sdmmc: &mut Sdmmc<SDMMC1, hal_sdmmc::SdCard>) -> Result<(), hal_sdmmc::Error> {
+        if !self.is_inserted() {
+            self.frequency.set(None);
+            return Err(hal_sdmmc::Error::NoCard);
+        }
+        if self.frequency.get().is_none() {
+            self.init_card(sdmmc)?;
+        }
+        Ok(())
+    }
+
+    /// The HAL driver can't be used while the IDMA owns the data path
+    fn check_idle(&self) -> Result<(), hal_sdmmc::Error> {
+        if self.transfer.is_some() {
+            Err(hal_sdmmc::Error::InvalidConfiguration)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Forget the frequency after a failure, the card needs to be initialised again
+    fn check<T>(&self, result: Result<T, hal_sdmmc::Error>) -> Result<T, hal_sdmmc::Error> {
+        if result.is_err() {
+            self.frequency.set(None);
+        }
+        result
+    }
+}
+
+impl BlockDevice for SdBlockDevice {
+    type Error = hal_sdmmc::Error;
+
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        let mut sdmmc = self.sdmmc.borrow_mut();
+        self.check_idle()?;
+        self.ready(&mut sdmmc)?;
+        for (address, block) in (start_block_idx.0..).zip(blocks.iter_mut()) {
+            self.check(sdmmc.read_block(address, &mut block.contents))?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        let mut sdmmc = self.sdmmc.borrow_mut();
+        self.check_idle()?;
+        self.ready(&mut sdmmc)?;
+        for (address, block) in (start_block_idx.0..).zip(blocks.iter()) {
+            self.check(sdmmc.write_block(address, &block.contents))?;
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        let mut sdmmc = self.sdmmc.borrow_mut();
+        self.check_idle()?;
+        self.ready(&mut sdmmc)?;
+        let card = self.check(sdmmc.card().copied())?;
+        Ok(BlockCount((card.size() / Block::LEN as u64) as u32))
+    }
+}
+
+/// Completes an async IDMA transfer, aborts it when dropped
+struct TransferFuture<'a>(&'a mut SdBlockDevice);
+
+impl Future for TransferFuture<'_> {
+    type Output = Result<&'static mut [u8], (SdError, &'static mut [u8])>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        interrupt::free(|cs| WAKER.borrow(cs).replace(Some(cx.waker().clone())));
+        match self.0.poll_inner() {
+            Ok(()) => {
+                let transfer = self.0.transfer.take();
+                Poll::Ready(Ok(transfer.map(Transfer::finish).unwrap_or_default()))
+            }
+            Err(nb::Error::WouldBlock) => {
+                let stopping = self.0.transfer.as_ref().is_some_and(|t| t.stopping);
+                SdBlockDevice::unmask(self.0.sdmmc.get_mut().inner(), stopping);
+                Poll::Pending
+            }
+            Err(nb::Error::Other(e)) => {
+                let buffer = self.0.abort().unwrap_or_default();
+                Poll::Ready(Err((e, buffer)))
+            }
+        }
+    }
+}
+
+impl Drop for TransferFuture<'_> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Call from the `SDMMC1` interrupt when using [read_blocks](SdBlockDevice#method.read_blocks)
+/// or [write_blocks](SdBlockDevice#method.write_blocks). Masks the interrupt until the future is
+/// polled again and wakes it.
+pub fn on_interrupt() {
+    // Safety: only the mask register is written, the future owns the rest
+    let regs = unsafe { &*SDMMC1::ptr() };
+    regs.maskr.reset();
+    if let Some(waker) = interrupt::free(|cs| WAKER.borrow(cs).take()) {
+        waker.wake();
+    }
+}
+
+/// Send a command with a short response, `transfer` starts the data path
+fn command(regs: &SDMMC1, index: u8, arg: u32, transfer: bool) -> Result<(), hal_sdmmc::Error> {
+    regs.icr.write(|w| {
+        w.ccrcfailc()
+            .set_bit()
+            .ctimeoutc()
+            .set_bit()
+            .cmdrendc()
+            .set_bit()
+            .cmdsentc()
+            .set_bit()
+    });
+    // Command state machine must be idle
+    while regs.star.read().cpsmact().bit_is_set() {}
+
+    regs.argr.write(|w| unsafe { w.cmdarg().bits(arg) });
+    regs.cmdr.write(|w| unsafe {
+        w.cmdtrans()
+            .bit(transfer)
+            .cmdstop()
+            .bit(index == CMD_STOP_TRANSMISSION)
+            .waitresp()
+            .bits(1) // short response
+            .cmdindex()
+            .bits(index)
+            .cpsmen()
+            .set_bit()
+    });
+
+    let mut timeout: u32 = 0xFFFF_FFFF;
+    let mut status;
+    while {
+        status = regs.star.read();
+        !(status.ctimeout().bit() || status.cmdrend().bit() || status.ccrcfail().bit())
+            && timeout > 0
+    } {
+        timeout -= 1;
+    }
+
+    if status.ctimeout().bit_is_set() {
+        Err(hal_sdmmc::Error::Timeout)
+    } else if timeout == 0 {
+        Err(hal_sdmmc::Error::SoftwareTimeout)
+    } else if status.ccrcfail().bit() {
+        Err(hal_sdmmc::Error::Crc)
+    } else {
+        Ok(())
+    }
+}
+
+/// FAT filesystem on an SD card
+pub struct SdCard<T: TimeSource = FixedTime> {
+    manager: VolumeManager<SdBlockDevice, T>,
+    volume: Option<Volume>,
+}
+
+impl<T: TimeSource> SdCard<T> {
+    /// Wrap the driver from [init], nothing happens on the bus until [mount](SdCard#method.mount)
+    pub fn new(sdmmc: Sdmmc<SDMMC1, hal_sdmmc::SdCard>, max_frequency: Hertz, time: T) -> Self {
+        Self {
+            manager: VolumeManager::new(SdBlockDevice::new(sdmmc, max_frequency), time),
+            volume: None,
+        }
+    }
+
+    /// Initialise the card if needed and mount the first FAT volume
+    pub fn mount(&mut self) -> SdResult<()> {
+        self.volume = None;
+        let device = self.manager.device();
+        if device.frequency().is_none() {
+            device.init()?;
+        }
+        self.volume = Some(self.manager.get_volume(VolumeIdx(0))?);
+        Ok(())
+    }
+
+    /// Update the card detect switch, this should be called on a timer.
+    ///
+    /// A newly inserted card is mounted, an error means that failed. Files that were open when
+    /// the card was removed can still be closed.
+    pub fn poll(&mut self) -> SdResult<Option<CardEvent>> {
+        match self.manager.device().poll() {
+            Some(CardEvent::Inserted) => {
+                self.mount()?;
+                Ok(Some(CardEvent::Inserted))
+            }
+            event => Ok(event),
+        }
+    }
+
+    /// Forget the mounted volume, files must be closed first
+    pub fn unmount(&mut self) {
+        self.volume = None;
+    }
+
+    /// Whether a volume is mounted and the card is in
+    pub fn is_mounted(&mut self) -> bool {
+        self.volume.is_some() && self.manager.device().is_inserted()
+    }
+
+    /// Call `f` for every entry of the directory at `path`
+    pub fn read_dir<F: FnMut(&DirEntry)>(&mut self, path: &str, f: F) -> SdResult<()> {
+        let dir = self.open_dir(path)?;
+        let volume = self.volume.as_ref().ok_or(SdError::NotMounted)?;
+        let result = self.manager.iterate_dir(volume, &dir, f);
+        self.manager.close_dir(volume, dir);
+        Ok(result?)
+    }
+
+    /// Open the file at `path`, it must be closed with [close](SdCard#method.close)
+    pub fn open(&mut self, path: &str, mode: Mode) -> SdResult<File> {
+        let (dir_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let dir = self.open_dir(dir_path)?;
+        let volume = self.volume.as_mut().ok_or(SdError::NotMounted)?;
+        let file = self.manager.open_file_in_dir(volume, &dir, name, mode);
+        self.manager.close_dir(volume, dir);
+        Ok(file?)
+    }
+
+    /// Read from the current position into `data`, returns the number of bytes read
+    pub fn read(&mut self, file: &mut File, data: &mut [u8]) -> SdResult<usize> {
+        let volume = self.volume.as_ref().ok_or(SdError::NotMounted)?;
+        Ok(self.manager.read(volume, file, data)?)
+    }
+
+    /// Write `data` at the current position, returns the number of bytes written
+    pub fn write(&mut self, file: &mut File, data: &[u8]) -> SdResult<usize> {
+        let volume = self.volume.as_mut().ok_or(SdError::NotMounted)?;
+        Ok(self.manager.write(volume, file, data)?)
+    }
+
+    /// Close a file, this also works after the card was removed
+    pub fn close(&mut self, file: File) -> SdResult<()> {
+        let volume = self.volume.as_ref().ok_or(SdError::NotMounted)?;
+        Ok(self.manager.close_file(volume, file)?)
+    }
+
+    /// Read the start of the file at `path` into `data`, returns the number of bytes read
+    pub fn read_file(&mut self, path: &str, data: &mut [u8]) -> SdResult<usize> {
+        let mut file = self.open(path, Mode::ReadOnly)?;
+        let mut len = 0;
+        let mut result = Ok(());
+        while len < data.len() && !file.eof() {
+            match self.read(&mut file, &mut data[len..]) {
+                Ok(n) => len += n,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.close(file)?;
+        result.map(|_| len)
+    }
+
+    /// Create or replace the file at `path` with `data`
+    pub fn write_file(&mut self, path: &str, data: &[u8]) -> SdResult<()> {
+        let mut file = self.open(path, Mode::ReadWriteCreateOrTruncate)?;
+        let result = self.write(&mut file, data);
+        self.close(file)?;
+        result.map(|_| ())
+    }
+
+    /// Delete the file at `path`
+    pub fn remove(&mut self, path: &str) -> SdResult<()> {
+        let (dir_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let dir = self.open_dir(dir_path)?;
+        let volume = self.volume.as_ref().ok_or(SdError::NotMounted)?;
+        let result = self.manager.delete_file_in_dir(volume, &dir, name);
+        self.manager.close_dir(volume, dir);
+        Ok(result?)
+    }
+
+    /// The block device, e.g. for the card information
+    pub fn block_device(&mut self) -> &mut SdBlockDevice {
+        self.manager.device()
+    }
+
+    /// Give back the driver and time source
+    pub fn free(self) -> (Sdmmc<SDMMC1, hal_sdmmc::SdCard>, T) {
+        let (device, time) = self.manager.free();
+        (device.free(), time)
+    }
+
+    /// Open the directory at `path` one level at a time, closing each parent behind us
+    fn open_dir(&mut self, path: &str) -> SdResult<Directory> {
+        let volume = self.volume.as_ref().ok_or(SdError::NotMounted)?;
+        let mut dir = self.manager.open_root_dir(volume)?;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let child = self.manager.open_dir(volume, &dir, name);
+            self.manager.close_dir(volume, dir);
+            dir = child?;
+        }
+        Ok(dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idma_buffers() {
+        assert!(check_buffer(0x2400_0000, 512).is_ok());
+        assert!(check_buffer(0xC000_0004, 1024).is_ok());
+        // Invalidating a misaligned AXI SRAM buffer would drop its neighbours' cache lines
+        assert!(matches!(
+            check_buffer(0x2400_0004, 512),
+            Err(SdError::InvalidBuffer)
+        ));
+        assert!(matches!(
+            check_buffer(0xC000_0002, 512),
+            Err(SdError::InvalidBuffer)
+        ));
+        assert!(matches!(
+            check_buffer(0x2400_0000, 100),
+            Err(SdError::InvalidBuffer)
+        ));
+        assert!(matches!(
+            check_buffer(0x2407_FF00, 512),
+            Err(SdError::InvalidBuffer)
+        ));
+        // DTCM
+        assert!(matches!(
+            check_buffer(0x2000_0000, 512),
+            Err(SdError::InvalidBuffer)
+        ));
+        assert!(matches!(
+            check_buffer(0xFFFF_FF00, 512),
+            Err(SdError::InvalidBuffer)
+        ));
+    }
+}
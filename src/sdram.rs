@@ -1,4 +1,6 @@
 //! Sdram
+use crate::gpio::SdramPins;
+use crate::mpu::MpuError;
 use stm32_fmc::devices::as4c16m32msa_6;
 use stm32h7xx_hal::{
     gpio::{gpiod, gpioe, gpiof, gpiog, gpioh, gpioi, Analog},
@@ -21,6 +23,22 @@ macro_rules! fmc_pins {
     };
 }
 
+/// SDRAM setup errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdramError {
+    /// The MPU region couldn't be set up
+    Mpu(MpuError),
+    /// The memory test read back something else at this address, the chip or its wiring is
+    /// faulty
+    Test(u32),
+}
+
+impl From<MpuError> for SdramError {
+    fn from(e: MpuError) -> Self {
+        SdramError::Mpu(e)
+    }
+}
+
 /// Struct that owns the sdram
 pub struct Sdram {
     inner: *mut u32,
@@ -93,6 +111,146 @@ impl Sdram {
         ii9: gpioi::PI9<Analog>,
         ii10: gpioi::PI10<Analog>,
     ) -> Self {
+        Self::try_new(
+            fmc_d,
+            fmc_p,
+            clocks,
+            delay,
+            scb,
+            mpu,
+            SdramPins {
+                pd0: dd0,
+                pd1: dd1,
+                pd8: dd8,
+                pd9: dd9,
+                pd10: dd10,
+                pd14: dd14,
+                pd15: dd15,
+                pe0: ee0,
+                pe1: ee1,
+                pe7: ee7,
+                pe8: ee8,
+                pe9: ee9,
+                pe10: ee10,
+                pe11: ee11,
+                pe12: ee12,
+                pe13: ee13,
+                pe14: ee14,
+                pe15: ee15,
+                pf0: ff0,
+                pf1: ff1,
+                pf2: ff2,
+                pf3: ff3,
+                pf4: ff4,
+                pf5: ff5,
+                pf11: ff11,
+                pf12: ff12,
+                pf13: ff13,
+                pf14: ff14,
+                pf15: ff15,
+                pg0: gg0,
+                pg1: gg1,
+                pg2: gg2,
+                pg4: gg4,
+                pg5: gg5,
+                pg8: gg8,
+                pg15: gg15,
+                ph2: hh2,
+                ph3: hh3,
+                ph5: hh5,
+                ph8: hh8,
+                ph9: hh9,
+                ph10: hh10,
+                ph11: hh11,
+                ph12: hh12,
+                ph13: hh13,
+                ph14: hh14,
+                ph15: hh15,
+                pi0: ii0,
+                pi1: ii1,
+                pi2: ii2,
+                pi3: ii3,
+                pi4: ii4,
+                pi5: ii5,
+                pi6: ii6,
+                pi7: ii7,
+                pi9: ii9,
+                pi10: ii10,
+            },
+        )
+        .expect("Failed to initialize SDRAM")
+    }
+
+    /// Initialize the sdram from the [Pins](crate::gpio::Pins) and check it with a short memory
+    /// test, which overwrites a few words
+    pub fn try_new<D: DelayUs<u8>>(
+        fmc_d: stm32::FMC,
+        fmc_p: rcc::rec::Fmc,
+        clocks: &rcc::CoreClocks,
+        delay: &mut D,
+        scb: &mut cortex_m::peripheral::SCB,
+        mpu: &mut cortex_m::peripheral::MPU,
+        pins: SdramPins,
+    ) -> Result<Self, SdramError> {
+        let SdramPins {
+            pd0: dd0,
+            pd1: dd1,
+            pd8: dd8,
+            pd9: dd9,
+            pd10: dd10,
+            pd14: dd14,
+            pd15: dd15,
+            pe0: ee0,
+            pe1: ee1,
+            pe7: ee7,
+            pe8: ee8,
+            pe9: ee9,
+            pe10: ee10,
+            pe11: ee11,
+            pe12: ee12,
+            pe13: ee13,
+            pe14: ee14,
+            pe15: ee15,
+            pf0: ff0,
+            pf1: ff1,
+            pf2: ff2,
+            pf3: ff3,
+            pf4: ff4,
+            pf5: ff5,
+            pf11: ff11,
+            pf12: ff12,
+            pf13: ff13,
+            pf14: ff14,
+            pf15: ff15,
+            pg0: gg0,
+            pg1: gg1,
+            pg2: gg2,
+            pg4: gg4,
+            pg5: gg5,
+            pg8: gg8,
+            pg15: gg15,
+            ph2: hh2,
+            ph3: hh3,
+            ph5: hh5,
+            ph8: hh8,
+            ph9: hh9,
+            ph10: hh10,
+            ph11: hh11,
+            ph12: hh12,
+            ph13: hh13,
+            ph14: hh14,
+            ph15: hh15,
+            pi0: ii0,
+            pi1: ii1,
+            pi2: ii2,
+            pi3: ii3,
+            pi4: ii4,
+            pi5: ii5,
+            pi6: ii6,
+            pi7: ii7,
+            pi9: ii9,
+            pi10: ii10,
+        } = pins;
         let sdram_pins = fmc_pins! {
             // A0-A12
             ff0, ff1, ff2, ff3,
@@ -123,8 +281,40 @@ impl Sdram {
         let ram_ptr = fmc_d
             .sdram(sdram_pins, as4c16m32msa_6::As4c16m32msa {}, fmc_p, clocks)
             .init(delay);
-        crate::mpu::sdram_init(mpu, scb, ram_ptr, Self::bytes());
-        Self { inner: ram_ptr }
+        crate::mpu::try_sdram_init(mpu, scb, ram_ptr, Self::bytes())?;
+        let sdram = Self { inner: ram_ptr };
+        sdram.test()?;
+        Ok(sdram)
+    }
+
+    /// Check the data and address lines: walk a one through the data bits, then write a
+    /// different word at every power of two offset and read them all back
+    fn test(&self) -> Result<(), SdramError> {
+        let words = Self::bytes() / 4;
+        let read = |offset: usize| unsafe { core::ptr::read_volatile(self.inner.add(offset)) };
+        let write = |offset: usize, value: u32| unsafe {
+            core::ptr::write_volatile(self.inner.add(offset), value)
+        };
+        let error = |offset: usize| SdramError::Test(self.inner as u32 + offset as u32 * 4);
+
+        for bit in 0..32 {
+            write(0, 1 << bit);
+            if read(0) != 1 << bit {
+                return Err(error(0));
+            }
+        }
+
+        let offsets =
+            || core::iter::once(0).chain((0..).map(|n| 1 << n).take_while(|&o| o < words));
+        for (i, offset) in offsets().enumerate() {
+            write(offset, !(i as u32));
+        }
+        for (i, offset) in offsets().enumerate() {
+            if read(offset) != !(i as u32) {
+                return Err(error(offset));
+            }
+        }
+        Ok(())
     }
 
     /// Get the total number of bytes that this ram has.
@@ -7,6 +7,7 @@ use log::info;
 use stm32h7xx_hal::{
     adc,
     delay::Delay,
+    gpio::{gpiob::PB11, Analog},
     prelude::*,
     rcc, stm32,
     stm32::TIM2,
@@ -15,7 +16,10 @@ use stm32h7xx_hal::{
     timer::Timer,
 };
 
-use crate::audio::Audio;
+use crate::audio::{Audio, AudioConfig, AudioError, SampleRate};
+use crate::flash::{Flash, FlashDma, FlashError};
+use crate::gpio::{AudioPins, FlashPins, SdramPins};
+use crate::sdram::SdramError;
 use crate::*;
 
 const HSE_CLOCK_MHZ: MegaHertz = MegaHertz::from_raw(16);
@@ -32,7 +36,68 @@ const PLL1_R_HZ: Hertz = Hertz::from_raw(CLOCK_RATE_HZ.raw() / 32);
 // PLL2
 const PLL2_P_HZ: Hertz = Hertz::from_raw(4_000_000);
 
-const PLL3_P_HZ: Hertz = Hertz::from_raw(AUDIO_SAMPLE_HZ.raw() * 256);
+// Highest sample rate * 256, SAI1 divides it down for the lower ones
+const PLL3_P_HZ: Hertz = Hertz::from_raw(SampleRate::Rate96K.hz().raw() * 256);
+
+// STM32H750 system memory, holds the ROM DFU bootloader vector table
+const SYSTEM_BOOTLOADER_ADDRESS: u32 = 0x1FF0_9800;
+// Backup SRAM, the Daisy bootloader reads its boot info from here after a reset
+const BACKUP_SRAM_ADDRESS: u32 = 0x3880_0000;
+// Daisy bootloader boot info status: stay in the bootloader until an update arrives
+const DAISY_BOOTLOADER_INFINITE_TIMEOUT: u32 = 0xB007_4EFA;
+// Status reads to wait for the HSE crystal, it is ready within a few ms
+const HSE_STARTUP_TIMEOUT: u32 = 1_000_000;
+
+/// Clock setup errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockError {
+    /// The HSE crystal didn't start
+    Hse,
+    /// The PLLs couldn't reach the system or audio clock
+    Frequency,
+}
+
+/// The subsystem that failed to start in [System::try_init], with the cause
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemError {
+    Clocks(ClockError),
+    Sdram(SdramError),
+    Audio(AudioError),
+    Flash(FlashError),
+}
+
+impl SystemError {
+    /// A number for each subsystem in the order they are started, 1 for the clocks to 4 for the
+    /// flash, e.g. to blink on an LED
+    pub fn code(&self) -> u8 {
+        match self {
+            SystemError::Clocks(_) => 1,
+            SystemError::Sdram(_) => 2,
+            SystemError::Audio(_) => 3,
+            SystemError::Flash(_) => 4,
+        }
+    }
+
+    /// Blink the [code](SystemError::code) on the SEED LED forever, with a pause after each
+    /// round. The LED is driven through the registers as the peripherals are already taken.
+    ///
+    /// Remarks:
+    /// - After a clock error the core still runs from the 64MHz HSI, so the blinks are slower.
+    pub fn blink_seed_led(&self) -> ! {
+        let dp = unsafe { stm32::Peripherals::steal() };
+        dp.RCC.ahb4enr.modify(|_, w| w.gpiocen().set_bit());
+        dp.GPIOC.moder.modify(|_, w| w.moder7().output());
+        loop {
+            for _ in 0..self.code() {
+                dp.GPIOC.bsrr.write(|w| w.bs7().set_bit());
+                delay_ms(200);
+                dp.GPIOC.bsrr.write(|w| w.br7().set_bit());
+                delay_ms(200);
+            }
+            delay_ms(1000);
+        }
+    }
+}
 
 pub struct System {
     pub gpio: crate::gpio::GPIO,
@@ -44,16 +109,42 @@ pub struct System {
     pub timer2: Timer<TIM2>,
     pub sdram: &'static mut [f32],
     pub flash: crate::flash::Flash,
+    /// MDMA and its clock, left free for [FlashDma::new](crate::flash::FlashDma::new)
+    pub mdma: stm32::MDMA,
+    pub mdma_rec: rcc::rec::Mdma,
+    /// Frozen clock configuration, e.g. to set up more peripherals
+    pub clocks: rcc::CoreClocks,
 }
 
 impl System {
     /// Initialize clocks
     pub fn init_clocks(pwr: stm32::PWR, rcc: stm32::RCC, syscfg: &stm32::SYSCFG) -> rcc::Ccdr {
+        Self::try_init_clocks(pwr, rcc, syscfg).expect("Failed to initialize clocks")
+    }
+
+    /// Initialize clocks, returns an error instead of hanging when the HSE crystal doesn't start
+    pub fn try_init_clocks(
+        pwr: stm32::PWR,
+        rcc: stm32::RCC,
+        syscfg: &stm32::SYSCFG,
+    ) -> Result<rcc::Ccdr, ClockError> {
         // Power
         let pwr = pwr.constrain();
         let vos = pwr.vos0(syscfg).freeze();
 
-        rcc.constrain()
+        // The HAL waits forever for the HSE, start it here so a dead crystal can be reported
+        rcc.cr.modify(|_, w| w.hseon().on());
+        let mut timeout = HSE_STARTUP_TIMEOUT;
+        while rcc.cr.read().hserdy().is_not_ready() {
+            if timeout == 0 {
+                rcc.cr.modify(|_, w| w.hseon().off());
+                return Err(ClockError::Hse);
+            }
+            timeout -= 1;
+        }
+
+        let ccdr = rcc
+            .constrain()
             .use_hse(HSE_CLOCK_MHZ.convert())
             .sys_ck(CLOCK_RATE_HZ)
             .pclk1(PCLK_HZ) // DMA clock
@@ -67,7 +158,12 @@ impl System {
             // PLL3
             .pll3_strategy(rcc::PllConfigStrategy::FractionalNotLess)
             .pll3_p_ck(PLL3_P_HZ) // used for SAI1
-            .freeze(vos, &syscfg)
+            .freeze(vos, syscfg);
+
+        if ccdr.clocks.sys_ck() != CLOCK_RATE_HZ || ccdr.clocks.pll3_p_ck().is_none() {
+            return Err(ClockError::Frequency);
+        }
+        Ok(ccdr)
     }
 
     /// Setup cache
@@ -86,10 +182,30 @@ impl System {
         dwt.enable_cycle_counter();
     }
 
-    /// Batteries included initialization
-    pub fn init(mut core: rtic::export::Peripherals, device: stm32::Peripherals) -> System {
+    /// Batteries included initialization, panics when a subsystem fails to start, see
+    /// [try_init](System::try_init)
+    pub fn init(core: rtic::export::Peripherals, device: stm32::Peripherals) -> System {
+        Self::try_init(core, device).expect("System init failed")
+    }
+
+    /// Batteries included initialization that reports which subsystem failed to start.
+    ///
+    /// ```rust
+    /// let system = match System::try_init(ctx.core, ctx.device) {
+    ///     Ok(system) => system,
+    ///     Err(e) => e.blink_seed_led(),
+    /// };
+    /// ```
+    ///
+    /// Remarks:
+    /// - The peripherals are consumed either way, reset to try again.
+    pub fn try_init(
+        mut core: rtic::export::Peripherals,
+        device: stm32::Peripherals,
+    ) -> Result<System, SystemError> {
         info!("Starting system init");
-        let mut ccdr = Self::init_clocks(device.PWR, device.RCC, &device.SYSCFG);
+        let mut ccdr = Self::try_init_clocks(device.PWR, device.RCC, &device.SYSCFG)
+            .map_err(SystemError::Clocks)?;
 
         // log_clocks(&ccdr);
         let mut delay = Delay::new(core.SYST, ccdr.clocks);
@@ -119,136 +235,80 @@ impl System {
         // timer3.listen(Event::TimeOut);
 
         info!("Setting up GPIOs...");
-        let gpioa = device.GPIOA.split(ccdr.peripheral.GPIOA);
-        let gpiob = device.GPIOB.split(ccdr.peripheral.GPIOB);
-        let gpioc = device.GPIOC.split(ccdr.peripheral.GPIOC);
-        let gpiod = device.GPIOD.split(ccdr.peripheral.GPIOD);
-        let gpioe = device.GPIOE.split(ccdr.peripheral.GPIOE);
-        let gpiof = device.GPIOF.split(ccdr.peripheral.GPIOF);
-        let gpiog = device.GPIOG.split(ccdr.peripheral.GPIOG);
-        let gpioh = device.GPIOH.split(ccdr.peripheral.GPIOH);
-        let gpioi = device.GPIOI.split(ccdr.peripheral.GPIOI);
+        let pins = crate::gpio::Pins::new(
+            device.GPIOA.split(ccdr.peripheral.GPIOA),
+            device.GPIOB.split(ccdr.peripheral.GPIOB),
+            device.GPIOC.split(ccdr.peripheral.GPIOC),
+            device.GPIOD.split(ccdr.peripheral.GPIOD),
+            device.GPIOE.split(ccdr.peripheral.GPIOE),
+            device.GPIOF.split(ccdr.peripheral.GPIOF),
+            device.GPIOG.split(ccdr.peripheral.GPIOG),
+            device.GPIOH.split(ccdr.peripheral.GPIOH),
+            device.GPIOI.split(ccdr.peripheral.GPIOI),
+        );
 
         // Configure SDRAM
         info!("Setting up SDRAM...");
-        let sdram = crate::sdram::Sdram::new(
+        let sdram = crate::sdram::Sdram::try_new(
             device.FMC,
             ccdr.peripheral.FMC,
             &ccdr.clocks,
             &mut delay,
             &mut core.SCB,
             &mut core.MPU,
-            gpiod.pd0,
-            gpiod.pd1,
-            gpiod.pd8,
-            gpiod.pd9,
-            gpiod.pd10,
-            gpiod.pd14,
-            gpiod.pd15,
-            gpioe.pe0,
-            gpioe.pe1,
-            gpioe.pe7,
-            gpioe.pe8,
-            gpioe.pe9,
-            gpioe.pe10,
-            gpioe.pe11,
-            gpioe.pe12,
-            gpioe.pe13,
-            gpioe.pe14,
-            gpioe.pe15,
-            gpiof.pf0,
-            gpiof.pf1,
-            gpiof.pf2,
-            gpiof.pf3,
-            gpiof.pf4,
-            gpiof.pf5,
-            gpiof.pf11,
-            gpiof.pf12,
-            gpiof.pf13,
-            gpiof.pf14,
-            gpiof.pf15,
-            gpiog.pg0,
-            gpiog.pg1,
-            gpiog.pg2,
-            gpiog.pg4,
-            gpiog.pg5,
-            gpiog.pg8,
-            gpiog.pg15,
-            gpioh.ph2,
-            gpioh.ph3,
-            gpioh.ph5,
-            gpioh.ph8,
-            gpioh.ph9,
-            gpioh.ph10,
-            gpioh.ph11,
-            gpioh.ph12,
-            gpioh.ph13,
-            gpioh.ph14,
-            gpioh.ph15,
-            gpioi.pi0,
-            gpioi.pi1,
-            gpioi.pi2,
-            gpioi.pi3,
-            gpioi.pi4,
-            gpioi.pi5,
-            gpioi.pi6,
-            gpioi.pi7,
-            gpioi.pi9,
-            gpioi.pi10,
+            pins.sdram,
         )
+        .map_err(SystemError::Sdram)?
         .into();
 
         info!("Setup up Audio...");
-        let audio = Audio::new(
+        let audio = Audio::try_new(
             device.DMA1,
             ccdr.peripheral.DMA1,
             device.SAI1,
             ccdr.peripheral.SAI1,
-            gpioe.pe2,
-            gpioe.pe3,
-            gpioe.pe4,
-            gpioe.pe5,
-            gpioe.pe6,
+            pins.audio,
             &ccdr.clocks,
             &mut core.MPU,
             &mut core.SCB,
-        );
+        )
+        .map_err(SystemError::Audio)?;
 
         // Setup GPIOs
         let gpio = crate::gpio::GPIO::init(
-            gpioc.pc7,
-            gpiob.pb11,
-            Some(gpiob.pb12),
-            Some(gpioc.pc11),
-            Some(gpioc.pc10),
-            Some(gpioc.pc9),
-            Some(gpioc.pc8),
-            Some(gpiod.pd2),
-            Some(gpioc.pc12),
-            Some(gpiog.pg10),
-            Some(gpiog.pg11),
-            Some(gpiob.pb4),
-            Some(gpiob.pb5),
-            Some(gpiob.pb8),
-            Some(gpiob.pb9),
-            Some(gpiob.pb6),
-            Some(gpiob.pb7),
-            Some(gpioc.pc0),
-            Some(gpioa.pa3),
-            Some(gpiob.pb1),
-            Some(gpioa.pa7),
-            Some(gpioa.pa6),
-            Some(gpioc.pc1),
-            Some(gpioc.pc4),
-            Some(gpioa.pa5),
-            Some(gpioa.pa4),
-            Some(gpioa.pa1),
-            Some(gpioa.pa0),
-            Some(gpiod.pd11),
-            Some(gpiog.pg9),
-            Some(gpioa.pa2),
-            Some(gpiob.pb14),
-            Some(gpiob.pb15),
+            pins.led,
+            pins.codec_reset,
+            Some(pins.daisy0),
+            Some(pins.daisy1),
+            Some(pins.daisy2),
+            Some(pins.daisy3),
+            Some(pins.daisy4),
+            Some(pins.daisy5),
+            Some(pins.daisy6),
+            Some(pins.daisy7),
+            Some(pins.daisy8),
+            Some(pins.daisy9),
+            Some(pins.daisy10),
+            Some(pins.daisy11),
+            Some(pins.daisy12),
+            Some(pins.daisy13),
+            Some(pins.daisy14),
+            Some(pins.daisy15),
+            Some(pins.daisy16),
+            Some(pins.daisy17),
+            Some(pins.daisy18),
+            Some(pins.daisy19),
+            Some(pins.daisy20),
+            Some(pins.daisy21),
+            Some(pins.daisy22),
+            Some(pins.daisy23),
+            Some(pins.daisy24),
+            Some(pins.daisy25),
+            Some(pins.daisy26),
+            Some(pins.daisy27),
+            Some(pins.daisy28),
+            Some(pins.daisy29),
+            Some(pins.daisy30),
         );
 
         // Setup cache
@@ -261,15 +321,16 @@ impl System {
             device.QUADSPI,
             ccdr.peripheral.QSPI,
             &ccdr.clocks,
-            gpiof.pf6,
-            gpiof.pf7,
-            gpiof.pf8,
-            gpiof.pf9,
-            gpiof.pf10,
-            gpiog.pg6,
-        );
+            pins.flash.pf6,
+            pins.flash.pf7,
+            pins.flash.pf8,
+            pins.flash.pf9,
+            pins.flash.pf10,
+            pins.flash.pg6,
+        )
+        .map_err(SystemError::Flash)?;
 
-        System {
+        Ok(System {
             gpio,
             audio,
             exti: device.EXTI,
@@ -279,7 +340,409 @@ impl System {
             timer2,
             sdram,
             flash,
+            mdma: device.MDMA,
+            mdma_rec: ccdr.peripheral.MDMA,
+            clocks: ccdr.clocks,
+        })
+    }
+
+    /// Jump to the STM32H750 ROM DFU bootloader, the same as holding BOOT while pressing RESET.
+    ///
+    /// Disables interrupts, SysTick, the caches and MPU and puts the clocks back to the reset
+    /// configuration before jumping, so this can be called at any point after `init`.
+    pub fn enter_bootloader() -> ! {
+        Self::jump(SYSTEM_BOOTLOADER_ADDRESS)
+    }
+
+    /// Disable interrupts, SysTick, the caches, MPU and clocks like `enter_bootloader` and start
+    /// the program with the vector table at `address`
+    pub(crate) fn jump(address: u32) -> ! {
+        cortex_m::interrupt::disable();
+
+        let mut core = unsafe { cortex_m::Peripherals::steal() };
+
+        // SysTick
+        core.SYST.disable_interrupt();
+        core.SYST.disable_counter();
+        unsafe {
+            core.SYST.rvr.write(0);
+            core.SYST.cvr.write(0);
+        }
+
+        // Caches and MPU
+        core.SCB.disable_icache();
+        core.SCB.disable_dcache(&mut core.CPUID);
+        crate::mpu::deinit(&mut core.MPU, &mut core.SCB);
+
+        Self::deinit_clocks();
+
+        // Clear all enabled and pending interrupts
+        unsafe {
+            for i in 0..8 {
+                core.NVIC.icer[i].write(0xFFFF_FFFF);
+                core.NVIC.icpr[i].write(0xFFFF_FFFF);
+            }
+        }
+
+        unsafe {
+            core.SCB.vtor.write(address);
+            cortex_m::interrupt::enable();
+            cortex_m::asm::bootload(address as *const u32)
+        }
+    }
+
+    /// Reset into the Daisy bootloader and wait there for a new program.
+    ///
+    /// This only has an effect when the program was loaded by the Daisy bootloader, otherwise
+    /// the board simply restarts.
+    pub fn enter_daisy_bootloader() -> ! {
+        write_backup_sram(0, DAISY_BOOTLOADER_INFINITE_TIMEOUT);
+        cortex_m::peripheral::SCB::sys_reset()
+    }
+
+    /// Switch back to the HSI and reset the peripherals started by `init`
+    fn deinit_clocks() {
+        let dp = unsafe { stm32::Peripherals::steal() };
+        let rcc = &dp.RCC;
+
+        // Stop everything that could still raise an interrupt or run a DMA transfer
+        rcc.ahb1rstr.modify(|_, w| {
+            w.dma1rst()
+                .set_bit()
+                .adc12rst()
+                .set_bit()
+                .usb2otgrst()
+                .set_bit()
+        });
+        rcc.ahb1rstr.modify(|_, w| {
+            w.dma1rst()
+                .clear_bit()
+                .adc12rst()
+                .clear_bit()
+                .usb2otgrst()
+                .clear_bit()
+        });
+        rcc.apb2rstr.modify(|_, w| w.sai1rst().set_bit());
+        rcc.apb2rstr.modify(|_, w| w.sai1rst().clear_bit());
+        rcc.apb1lrstr.modify(|_, w| w.tim2rst().set_bit());
+        rcc.apb1lrstr.modify(|_, w| w.tim2rst().clear_bit());
+
+        // Run from the HSI before turning off the PLLs and the HSE
+        rcc.cr.modify(|_, w| w.hsion().on());
+        while rcc.cr.read().hsirdy().is_not_ready() {}
+        rcc.cfgr.reset();
+        while !rcc.cfgr.read().sws().is_hsi() {}
+
+        rcc.cr.modify(|_, w| {
+            w.hseon()
+                .off()
+                .hsi48on()
+                .off()
+                .pll1on()
+                .off()
+                .pll2on()
+                .off()
+                .pll3on()
+                .off()
+        });
+        while rcc.cr.read().pll1rdy().is_ready()
+            || rcc.cr.read().pll2rdy().is_ready()
+            || rcc.cr.read().pll3rdy().is_ready()
+        {}
+
+        rcc.d1cfgr.reset();
+        rcc.d2cfgr.reset();
+        rcc.d3cfgr.reset();
+        rcc.pllckselr.reset();
+        rcc.pllcfgr.reset();
+        rcc.pll1divr.reset();
+        rcc.pll1fracr.reset();
+        rcc.pll2divr.reset();
+        rcc.pll2fracr.reset();
+        rcc.pll3divr.reset();
+        rcc.pll3fracr.reset();
+        rcc.cier.reset();
+    }
+}
+
+/// Enable access to the backup SRAM, it is write protected and unclocked out of reset
+fn enable_backup_sram() {
+    let dp = unsafe { stm32::Peripherals::steal() };
+    dp.PWR.cr1.modify(|_, w| w.dbp().set_bit());
+    dp.RCC.ahb4enr.modify(|_, w| w.bkpramen().set_bit());
+}
+
+/// Write a word of backup SRAM at `offset`, it keeps its contents across resets
+pub(crate) fn write_backup_sram(offset: u32, value: u32) {
+    enable_backup_sram();
+    let address = BACKUP_SRAM_ADDRESS + offset;
+    unsafe { core::ptr::write_volatile(address as *mut u32, value) };
+    // The D-cache is write-back, get the word out to the SRAM before a reset
+    let mut core = unsafe { cortex_m::Peripherals::steal() };
+    core.SCB
+        .clean_dcache_by_address(address as usize, core::mem::size_of::<u32>());
+    cortex_m::asm::dsb();
+}
+
+/// Read a word of backup SRAM at `offset`
+pub(crate) fn read_backup_sram(offset: u32) -> u32 {
+    enable_backup_sram();
+    unsafe { core::ptr::read_volatile((BACKUP_SRAM_ADDRESS + offset) as *const u32) }
+}
+
+/// Bring up only the subsystems that are handed to it, everything else stays with the caller.
+///
+/// Each subsystem takes its device peripheral, clock control and pins. Leaving one out skips it
+/// and keeps its peripherals, e.g. DMA1 and TIM2, free for other uses:
+///
+/// ```rust
+/// let dp = ctx.device;
+/// let ccdr = System::init_clocks(dp.PWR, dp.RCC, &dp.SYSCFG);
+/// let pins = gpio::Pins::new(dp.GPIOA.split(ccdr.peripheral.GPIOA), ...);
+/// let system = SystemBuilder::new(ctx.core, ccdr.clocks)
+///     .logging(true)
+///     .audio(dp.DMA1, ccdr.peripheral.DMA1, dp.SAI1, ccdr.peripheral.SAI1, pins.audio, pins.codec_reset)
+///     .timer(dp.TIM2, ccdr.peripheral.TIM2, 1.kHz())
+///     .build();
+/// ```
+///
+/// Remarks:
+/// - The clocks must be set up with [System::init_clocks] first, audio relies on PLL3.
+/// - Audio runs at 48kHz with blocks of [BLOCK_SIZE_MAX](crate::audio::BLOCK_SIZE_MAX) samples
+///   unless changed with [audio_config](SystemBuilder#method.audio_config).
+/// - Subsystems are started in the same order as [System::init].
+/// - The cache is enabled unless turned off with [cache](SystemBuilder#method.cache).
+pub struct SystemBuilder {
+    core: rtic::export::Peripherals,
+    clocks: rcc::CoreClocks,
+    #[allow(clippy::type_complexity)]
+    audio: Option<(
+        stm32::DMA1,
+        rcc::rec::Dma1,
+        stm32::SAI1,
+        rcc::rec::Sai1,
+        AudioPins,
+        PB11<Analog>,
+    )>,
+    audio_config: AudioConfig,
+    sdram: Option<(stm32::FMC, rcc::rec::Fmc, SdramPins)>,
+    flash: Option<(stm32::QUADSPI, rcc::rec::Qspi, FlashPins)>,
+    flash_dma: Option<(stm32::MDMA, rcc::rec::Mdma)>,
+    adc: Option<(stm32::ADC1, stm32::ADC2, rcc::rec::Adc12, Hertz)>,
+    timer: Option<(TIM2, rcc::rec::Tim2, Hertz)>,
+    cache: bool,
+    logging: bool,
+}
+
+/// The subsystems started by [SystemBuilder], `None` for the ones that were skipped
+pub struct Subsystems {
+    /// Core peripherals, given back after setup
+    pub core: rtic::export::Peripherals,
+    pub audio: Option<Audio>,
+    pub adc1: Option<adc::Adc<stm32::ADC1, adc::Disabled>>,
+    pub adc2: Option<adc::Adc<stm32::ADC2, adc::Disabled>>,
+    pub timer2: Option<Timer<TIM2>>,
+    pub sdram: Option<&'static mut [f32]>,
+    pub flash: Option<Flash>,
+    pub flash_dma: Option<FlashDma>,
+}
+
+impl SystemBuilder {
+    /// Start with every subsystem skipped except the cache
+    pub fn new(core: rtic::export::Peripherals, clocks: rcc::CoreClocks) -> Self {
+        Self {
+            core,
+            clocks,
+            audio: None,
+            audio_config: AudioConfig::default(),
+            sdram: None,
+            flash: None,
+            flash_dma: None,
+            adc: None,
+            timer: None,
+            cache: true,
+            logging: false,
+        }
+    }
+
+    /// Start the codec with SAI1 and DMA1 streams 0 and 1, `codec_reset` resets it afterwards.
+    pub fn audio(
+        mut self,
+        dma1: stm32::DMA1,
+        dma1_rec: rcc::rec::Dma1,
+        sai1: stm32::SAI1,
+        sai1_rec: rcc::rec::Sai1,
+        pins: AudioPins,
+        codec_reset: PB11<Analog>,
+    ) -> Self {
+        self.audio = Some((dma1, dma1_rec, sai1, sai1_rec, pins, codec_reset));
+        self
+    }
+
+    /// Sample rate and block size of [audio](SystemBuilder#method.audio), see
+    /// [Audio::try_new_with_config]
+    pub fn audio_config(mut self, config: AudioConfig) -> Self {
+        self.audio_config = config;
+        self
+    }
+
+    /// Set up the 64MB SDRAM
+    pub fn sdram(mut self, fmc: stm32::FMC, fmc_rec: rcc::rec::Fmc, pins: SdramPins) -> Self {
+        self.sdram = Some((fmc, fmc_rec, pins));
+        self
+    }
+
+    /// Set up the QSPI flash
+    pub fn flash(
+        mut self,
+        qspi: stm32::QUADSPI,
+        qspi_rec: rcc::rec::Qspi,
+        pins: FlashPins,
+    ) -> Self {
+        self.flash = Some((qspi, qspi_rec, pins));
+        self
+    }
+
+    /// Set up MDMA channel 0 for [FlashDma]
+    pub fn flash_dma(mut self, mdma: stm32::MDMA, mdma_rec: rcc::rec::Mdma) -> Self {
+        self.flash_dma = Some((mdma, mdma_rec));
+        self
+    }
+
+    /// Calibrate ADC1 and ADC2 with a kernel clock of `clock`, [System::init] uses 4MHz
+    pub fn adc(
+        mut self,
+        adc1: stm32::ADC1,
+        adc2: stm32::ADC2,
+        adc_rec: rcc::rec::Adc12,
+        clock: Hertz,
+    ) -> Self {
+        self.adc = Some((adc1, adc2, adc_rec, clock));
+        self
+    }
+
+    /// Run TIM2 at `rate` with its update interrupt enabled, [System::init] uses 10Hz
+    pub fn timer(mut self, tim2: TIM2, tim2_rec: rcc::rec::Tim2, rate: Hertz) -> Self {
+        self.timer = Some((tim2, tim2_rec, rate));
+        self
+    }
+
+    /// Enable the instruction and data caches, on by default
+    pub fn cache(mut self, enable: bool) -> Self {
+        self.cache = enable;
+        self
+    }
+
+    /// Call [logger::init] first, off by default
+    pub fn logging(mut self, enable: bool) -> Self {
+        self.logging = enable;
+        self
+    }
+
+    /// Start the chosen subsystems, panics when one fails to start
+    pub fn build(self) -> Subsystems {
+        self.try_build().expect("System init failed")
+    }
+
+    /// Start the chosen subsystems and report the first one that fails, see [System::try_init]
+    pub fn try_build(self) -> Result<Subsystems, SystemError> {
+        let Self {
+            mut core,
+            clocks,
+            audio,
+            audio_config,
+            sdram,
+            flash,
+            flash_dma,
+            adc,
+            timer,
+            cache,
+            logging,
+        } = self;
+
+        if logging {
+            logger::init();
+        }
+        info!("Starting system init");
+
+        let mut delay = Delay::new(core.SYST, clocks);
+        let (adc1, adc2) = match adc {
+            Some((adc1, adc2, rec, clock)) => {
+                let (adc1, adc2) = adc::adc12(adc1, adc2, clock, &mut delay, rec, &clocks);
+                (Some(adc1), Some(adc2))
+            }
+            None => (None, None),
+        };
+
+        System::init_debug(&mut core.DCB, &mut core.DWT);
+
+        let timer2 = timer.map(|(tim2, rec, rate)| {
+            let mut timer2 = tim2.timer(rate, rec, &clocks);
+            timer2.listen(Event::TimeOut);
+            timer2
+        });
+
+        let (scb, mpu) = (&mut core.SCB, &mut core.MPU);
+        let sdram = sdram
+            .map(|(fmc, rec, pins)| {
+                info!("Setting up SDRAM...");
+                crate::sdram::Sdram::try_new(fmc, rec, &clocks, &mut delay, scb, mpu, pins)
+                    .map(Into::into)
+            })
+            .transpose()
+            .map_err(SystemError::Sdram)?;
+
+        let audio = audio
+            .map(|(dma1, dma1_rec, sai1, sai1_rec, pins, codec_reset)| {
+                info!("Setup up Audio...");
+                let audio = Audio::try_new_with_config(
+                    dma1,
+                    dma1_rec,
+                    sai1,
+                    sai1_rec,
+                    pins,
+                    &clocks,
+                    mpu,
+                    scb,
+                    audio_config,
+                )?;
+                // Same as GPIO::reset_codec
+                let mut codec_reset = codec_reset.into_push_pull_output();
+                codec_reset.set_low();
+                delay_ms(5);
+                codec_reset.set_high();
+                Ok(audio)
+            })
+            .transpose()
+            .map_err(SystemError::Audio)?;
+
+        if cache {
+            System::init_cache(scb, &mut core.CPUID);
         }
+
+        let flash = flash
+            .map(|(qspi, rec, p)| {
+                Flash::new(
+                    qspi, rec, &clocks, p.pf6, p.pf7, p.pf8, p.pf9, p.pf10, p.pg6,
+                )
+            })
+            .transpose()
+            .map_err(SystemError::Flash)?;
+        let flash_dma = flash_dma.map(|(mdma, rec)| FlashDma::new(mdma, rec));
+
+        info!("System init done!");
+        core.SYST = delay.free();
+
+        Ok(Subsystems {
+            core,
+            audio,
+            adc1,
+            adc2,
+            timer2,
+            sdram,
+            flash,
+            flash_dma,
+        })
     }
 }
 